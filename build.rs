@@ -0,0 +1,10 @@
+use std::env;
+
+fn main() {
+    // Neither is otherwise known to the code being built, and `--version
+    // --verbose` reports both.
+    println!("cargo:rustc-env=FLATTEN_FILENAMES_TARGET={}",
+             env::var("TARGET").unwrap_or_default());
+    println!("cargo:rustc-env=FLATTEN_FILENAMES_PROFILE={}",
+             env::var("PROFILE").unwrap_or_default());
+}
@@ -0,0 +1,7984 @@
+//! Flatten filenames by embedding directory names in a file's name.
+
+extern crate age;
+#[cfg(unix)]
+extern crate libc;
+#[macro_use]
+extern crate serde_json;
+extern crate sha2;
+extern crate unicode_normalization;
+
+use std::borrow::Cow;
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet, VecDeque};
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;  // Need `write_fmt()` method for `writeln!()`.
+use std::path;
+use std::process;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time;
+
+use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+
+/// Order in which the directories of a tree are processed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Order {
+    /// Finish every subdirectory before renaming a directory's own
+    /// entries, so the deepest directories are handled first.
+    #[default]
+    DepthFirst,
+    /// Rename a directory's own entries before any of its subdirectories,
+    /// working through the tree one level at a time.
+    BreadthFirst,
+}
+
+/// Filesystem whose naming restrictions generated names must respect.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TargetProfile {
+    /// ISO 9660 with Joliet extensions, as mastered onto optical media.
+    Iso9660,
+    /// FAT32 and exFAT, as found on memory cards and USB sticks.
+    Fat,
+}
+
+/// Longest name Joliet allows, in UTF-16 code units.
+const JOLIET_MAX_NAME: usize = 64;
+
+/// Characters Joliet does not allow in names.
+const JOLIET_FORBIDDEN: &str = "*/:;?\\";
+
+/// Deepest directory nesting ISO 9660 allows, counting the root.
+const ISO9660_MAX_DEPTH: usize = 8;
+
+/// Longest name FAT and exFAT allow, in UTF-16 code units.
+const FAT_MAX_NAME: usize = 255;
+
+/// Characters FAT and exFAT do not allow in names.
+const FAT_FORBIDDEN: &str = "\"*/:<>?\\|";
+
+/// Device names Windows refuses as filenames on FAT, with or without an
+/// extension.
+const FAT_RESERVED: [&str; 22] = ["con", "prn", "aux", "nul",
+                                  "com1", "com2", "com3", "com4", "com5",
+                                  "com6", "com7", "com8", "com9",
+                                  "lpt1", "lpt2", "lpt3", "lpt4", "lpt5",
+                                  "lpt6", "lpt7", "lpt8", "lpt9"];
+
+impl TargetProfile {
+    /// Look up a profile by the name used on the command line.
+    pub fn from_name(name: &str) -> Option<TargetProfile> {
+        match name {
+            "iso9660" => Some(TargetProfile::Iso9660),
+            "fat" | "exfat" => Some(TargetProfile::Fat),
+            _ => None,
+        }
+    }
+
+    /// The name of the profile used on the command line.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TargetProfile::Iso9660 => "iso9660",
+            TargetProfile::Fat => "fat",
+        }
+    }
+
+    /// Make `filename` acceptable to the profile's filesystem, for a file
+    /// `depth` levels below the root.
+    ///
+    /// Returns the adjusted name along with warnings about problems that
+    /// were fixed lossily or could not be fixed at all.
+    pub fn apply(&self, filename: &str, depth: usize) -> (String, Vec<String>) {
+        let mut problems = Vec::new();
+        match *self {
+            TargetProfile::Iso9660 => {
+                // Joliet stores names as UCS-2, so anything outside the
+                // Basic Multilingual Plane cannot be represented either.
+                let replaced: String = filename.chars()
+                    .map(|c| {
+                        if c.is_control() || JOLIET_FORBIDDEN.contains(c) || c.len_utf16() > 1 {
+                            '_'
+                        } else {
+                            c
+                        }
+                    })
+                    .collect();
+                let truncated = truncate_utf16(&replaced, JOLIET_MAX_NAME);
+                if truncated != replaced {
+                    problems.push(format!("name truncated to {} characters for Joliet",
+                                          JOLIET_MAX_NAME));
+                }
+                if depth > ISO9660_MAX_DEPTH {
+                    problems.push(format!("nested {} levels deep, beyond ISO 9660's limit \
+                                           of {}", depth, ISO9660_MAX_DEPTH));
+                }
+                (truncated, problems)
+            }
+            TargetProfile::Fat => {
+                let replaced: String = filename.chars()
+                    .map(|c| if c.is_control() || FAT_FORBIDDEN.contains(c) { '_' } else { c })
+                    .collect();
+                let mut adjusted = truncate_utf16(&replaced, FAT_MAX_NAME);
+                if adjusted != replaced {
+                    problems.push(format!("name truncated to {} characters for FAT",
+                                          FAT_MAX_NAME));
+                }
+                // Windows silently drops trailing dots and spaces.
+                let trimmed_len = adjusted.trim_end_matches(['.', ' ']).len();
+                adjusted.truncate(trimmed_len);
+                if adjusted.is_empty() {
+                    problems.push(format!("{:?} has no usable characters on FAT", filename));
+                    adjusted.push('_');
+                }
+                let stem = adjusted.split('.').next().unwrap_or("").to_lowercase();
+                if FAT_RESERVED.contains(&stem.as_str()) {
+                    adjusted.insert(stem.len(), '_');
+                }
+                (adjusted, problems)
+            }
+        }
+    }
+}
+
+/// Pick the naming profile called for by a filesystem of type `kind`
+/// (as reported by `statfs`, e.g. "vfat" or "cifs").
+///
+/// Windows shares and NTFS get the FAT profile since they share its
+/// restrictions on characters and length.
+pub fn profile_for_filesystem(kind: &str) -> Option<TargetProfile> {
+    match kind {
+        "fat" | "vfat" | "msdos" | "exfat" | "ntfs" | "ntfs3" | "cifs" | "smb2" | "smbfs" => {
+            Some(TargetProfile::Fat)
+        }
+        "iso9660" | "cd9660" => Some(TargetProfile::Iso9660),
+        _ => None,
+    }
+}
+
+/// What was learned about the filesystem holding a directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilesystemInfo {
+    /// Type of the filesystem, e.g. "vfat".
+    pub kind: String,
+    /// Whether names differing only by case refer to different entries.
+    pub case_sensitive: bool,
+}
+
+/// Detect the type and case sensitivity of the filesystem holding
+/// `directory`.
+pub fn detect_filesystem(directory: &path::Path) -> Option<FilesystemInfo> {
+    filesystem_kind(directory).map(|kind| {
+        FilesystemInfo {
+            kind,
+            case_sensitive: is_case_sensitive(directory),
+        }
+    })
+}
+
+/// Look up the type of the filesystem holding `directory`.
+#[cfg(target_os = "linux")]
+fn filesystem_kind(directory: &path::Path) -> Option<String> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(directory.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let kind = match stat.f_type as u32 {
+        0x4d44 => "vfat",
+        0x2011_bab0 => "exfat",
+        0x9660 => "iso9660",
+        0x5346_544e => "ntfs",
+        0x7366_746e => "ntfs3",
+        0xff53_4d42 => "cifs",
+        0xfe53_4d42 => "smb2",
+        0xef53 => "ext4",
+        0x5846_5342 => "xfs",
+        0x9123_683e => "btrfs",
+        0x0102_1994 => "tmpfs",
+        0x6969 => "nfs",
+        magic => return Some(format!("{:#x}", magic)),
+    };
+    Some(kind.to_string())
+}
+
+/// Look up the type of the filesystem holding `directory`.
+#[cfg(target_os = "macos")]
+fn filesystem_kind(directory: &path::Path) -> Option<String> {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(directory.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let kind = unsafe { CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    Some(kind.to_string_lossy().into_owned())
+}
+
+/// Look up the type of the filesystem holding `directory`.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn filesystem_kind(_directory: &path::Path) -> Option<String> {
+    None
+}
+
+/// Tell apart the mount holding `directory` by its device and whether it
+/// is mounted read-only, which also sets read-only bind mounts apart.
+#[cfg(unix)]
+fn mount_of(directory: &path::Path) -> Option<(u64, bool)> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let device = fs::metadata(directory).ok()?.dev();
+    let c_path = CString::new(directory.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some((device, stat.f_flag & libc::ST_RDONLY != 0))
+}
+
+/// Tell apart the mount holding `directory`, which isn't possible here.
+#[cfg(not(unix))]
+fn mount_of(_directory: &path::Path) -> Option<(u64, bool)> {
+    None
+}
+
+/// Inode flags making an entry immutable or append-only (`chattr +i` or
+/// `+a`), either of which keeps it from being renamed.
+#[cfg(target_os = "linux")]
+const IMMUTABLE_FLAGS: u32 = 0x10 | 0x20;
+
+/// File flags making an entry immutable or append-only (`chflags uchg`,
+/// `schg`, `uappnd`, or `sappnd`), any of which keeps it from being
+/// renamed.
+#[cfg(target_os = "macos")]
+const IMMUTABLE_FLAGS: u32 = libc::UF_IMMUTABLE | libc::UF_APPEND | libc::SF_IMMUTABLE |
+                             libc::SF_APPEND;
+
+/// The flags set on the entry at `path` which keep it from being renamed
+/// (see `IMMUTABLE_FLAGS`), or 0 if there are none or they can't be read.
+pub fn immutable_flags(path: &path::Path) -> u32 {
+    file_flags(path).map(|flags| flags & IMMUTABLE_FLAGS).unwrap_or(0)
+}
+
+/// Set or clear `flags` on the entry at `path`, which takes privileges
+/// for most of `IMMUTABLE_FLAGS`.
+pub fn change_immutable_flags(path: &path::Path, flags: u32, set: bool) -> io::Result<()> {
+    let current = file_flags(path)?;
+    set_file_flags(path, if set { current | flags } else { current & !flags })
+}
+
+/// Open the entry at `path` for reading or changing its inode flags,
+/// refusing anything but files and directories.
+#[cfg(target_os = "linux")]
+fn open_for_flags(path: &path::Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let file_type = fs::symlink_metadata(path)?.file_type();
+    if !file_type.is_file() && !file_type.is_dir() {
+        return Err(io::Error::from(io::ErrorKind::Unsupported));
+    }
+    fs::OpenOptions::new().read(true)
+                          .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+                          .open(path)
+}
+
+/// Read the inode flags of the entry at `path`.
+#[cfg(target_os = "linux")]
+fn file_flags(path: &path::Path) -> io::Result<u32> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = open_for_flags(path)?;
+    // The kernel reads and writes an int, whatever the ioctl's name says.
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags as u32)
+}
+
+/// Replace the inode flags of the entry at `path` with `flags`.
+#[cfg(target_os = "linux")]
+fn set_file_flags(path: &path::Path, flags: u32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = open_for_flags(path)?;
+    let flags = flags as libc::c_int;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Read the file flags of the entry at `path`.
+#[cfg(target_os = "macos")]
+fn file_flags(path: &path::Path) -> io::Result<u32> {
+    use std::os::macos::fs::MetadataExt;
+
+    Ok(fs::symlink_metadata(path)?.st_flags())
+}
+
+/// Replace the file flags of the entry at `path` with `flags`.
+#[cfg(target_os = "macos")]
+fn set_file_flags(path: &path::Path, flags: u32) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    if unsafe { libc::lchflags(c_path.as_ptr(), flags as _) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// No entries are kept from being renamed by flags here.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+const IMMUTABLE_FLAGS: u32 = 0;
+
+/// Read the flags of the entry at `path`, which isn't possible here.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn file_flags(_path: &path::Path) -> io::Result<u32> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Change the flags of the entry at `path`, which isn't possible here.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn set_file_flags(_path: &path::Path, _flags: u32) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Switch the process for good from root to `user`, with their groups,
+/// so files are touched with their permissions rather than root's.
+#[cfg(unix)]
+pub fn run_as_user(user: &str) -> io::Result<()> {
+    use std::ffi::CString;
+
+    if !is_root() {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  "only root can run as another user"));
+    }
+    let c_user = CString::new(user)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "user name contains NUL"))?;
+    let passwd = unsafe { libc::getpwnam(c_user.as_ptr()) };
+    if passwd.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no user named {:?}", user)));
+    }
+    let (uid, gid) = unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) };
+    // Groups go first, as only root may change them.
+    if unsafe { libc::initgroups(c_user.as_ptr(), gid as _) } != 0 ||
+       unsafe { libc::setgid(gid) } != 0 ||
+       unsafe { libc::setuid(uid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(io::Error::other("root privileges could not be given up"));
+    }
+    Ok(())
+}
+
+/// Switch the process to `user`, which isn't possible here.
+#[cfg(not(unix))]
+pub fn run_as_user(_user: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "running as another user needs Unix"))
+}
+
+/// Name of the file created and removed to check that a directory can be
+/// written to.
+const WRITE_PROBE: &str = ".flatten_probe";
+
+/// Check that entries can be created in and removed from `directory`.
+fn probe_writable(directory: &path::Path) -> io::Result<()> {
+    let probe = directory.join(format!("{}.{}", WRITE_PROBE, process::id()));
+    fs::File::create(&probe)?;
+    fs::remove_file(&probe)
+}
+
+/// Find the mounts within `directory`, itself included, that can't be
+/// written to, such as read-only (bind) mounts, among the directories a
+/// run would descend into.
+///
+/// With `probe`, a file is created and removed where each mount begins to
+/// find out. Otherwise nothing is written and only mounts flagged
+/// read-only are found. Returns the directory each unwritable mount begins
+/// at along with why it can't be written to, sorted by path.
+pub fn unwritable_mounts(directory: &path::Path, options: &FlattenOptions, probe: bool)
+                         -> Vec<(path::PathBuf, io::Error)> {
+    let root = directory;
+    let mut unwritable = Vec::new();
+    let mut pending = vec![(directory.to_path_buf(), None)];
+    while let Some((directory, parent_mount)) = pending.pop() {
+        let mount = mount_of(&directory);
+        if parent_mount.is_none() || parent_mount != Some(mount) {
+            let writable = match mount {
+                _ if probe => probe_writable(&directory),
+                Some((_, true)) => Err(io::Error::from(io::ErrorKind::ReadOnlyFilesystem)),
+                _ => Ok(()),
+            };
+            if let Err(e) = writable {
+                unwritable.push((directory, e));
+                continue;
+            }
+        }
+        let read_dir = match directory.read_dir() {
+            Ok(read_dir) => read_dir,
+            Err(_) => continue,
+        };
+        for entry in read_dir.filter_map(Result::ok) {
+            let entry_path = entry.path();
+            if should_traverse(&entry) && !options.artifacts.contains(&entry_path) &&
+               !is_unsettled(&entry_path, options) && !is_too_deep(root, &entry_path, options) {
+                pending.push((entry_path, Some(mount)));
+            }
+        }
+    }
+    unwritable.sort_by(|a, b| a.0.cmp(&b.0));
+    unwritable
+}
+
+/// Check if the filesystem holding `directory` treats names differing
+/// only by case as different entries.
+///
+/// This looks for `directory` (or one of its entries) under a name with
+/// its case swapped, so nothing has to be written to find out. Without
+/// any cased name to try, the filesystem is assumed to be case sensitive.
+pub fn is_case_sensitive(directory: &path::Path) -> bool {
+    let swap_case = |name: &str| -> String {
+        name.chars()
+            .flat_map(|c| {
+                if c.is_lowercase() {
+                    c.to_uppercase().collect::<Vec<_>>()
+                } else {
+                    c.to_lowercase().collect::<Vec<_>>()
+                }
+            })
+            .collect()
+    };
+
+    let mut candidates = vec![directory.to_path_buf()];
+    if let Ok(read_dir) = directory.read_dir() {
+        candidates.extend(read_dir.filter_map(|entry| entry.ok()).map(|entry| entry.path()));
+    }
+    for candidate in candidates {
+        let name = match candidate.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+        let swapped = swap_case(name);
+        if swapped == name {
+            continue;
+        }
+        return match (fs::symlink_metadata(&candidate),
+                       fs::symlink_metadata(candidate.with_file_name(swapped))) {
+            (Ok(original), Ok(swapped)) => !is_same_file(&original, &swapped),
+            _ => true,
+        };
+    }
+    true
+}
+
+/// The bytes making up `path`, for tools that take paths as bytes.
+#[cfg(unix)]
+fn path_bytes(path: &path::Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+/// The bytes making up `path`, for tools that take paths as bytes.
+#[cfg(not(unix))]
+fn path_bytes(path: &path::Path) -> Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(text) => Cow::Borrowed(text.as_bytes()),
+        Cow::Owned(text) => Cow::Owned(text.into_bytes()),
+    }
+}
+
+/// Check if two sets of metadata belong to the same file.
+#[cfg(unix)]
+fn is_same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+/// Check if two sets of metadata belong to the same file.
+#[cfg(not(unix))]
+fn is_same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    a.len() == b.len() && a.modified().ok() == b.modified().ok()
+}
+
+/// Shorten `filename` to at most `max_units` UTF-16 code units, keeping
+/// its extension whenever that leaves room for part of the stem.
+pub fn truncate_utf16(filename: &str, max_units: usize) -> String {
+    let take = |text: &str, max_units: usize| -> String {
+        let mut units = 0;
+        text.chars()
+            .take_while(|c| {
+                units += c.len_utf16();
+                units <= max_units
+            })
+            .collect()
+    };
+
+    if filename.encode_utf16().count() <= max_units {
+        return filename.to_string();
+    }
+    match filename.rfind('.') {
+        Some(dot) if dot > 0 => {
+            let extension = &filename[dot..];
+            let extension_units = extension.encode_utf16().count();
+            if extension_units < max_units {
+                take(&filename[..dot], max_units - extension_units) + extension
+            } else {
+                take(filename, max_units)
+            }
+        }
+        _ => take(filename, max_units),
+    }
+}
+
+/// Options controlling how a directory is flattened.
+#[derive(Default)]
+pub struct FlattenOptions {
+    /// Skip directories modified more recently than this long ago.
+    pub settle: Option<time::Duration>,
+    /// Defer files which are still growing after this long, as well as
+    /// files that look like in-progress downloads.
+    pub defer_growing: Option<time::Duration>,
+    /// Executable of the naming scheme which picks new filenames.
+    pub scheme: Option<path::PathBuf>,
+    /// Order in which directories are processed.
+    pub order: Order,
+    /// Maximum number of directory handles held open at once while
+    /// walking the tree.
+    pub max_open: Option<usize>,
+    /// Filesystem whose naming restrictions new names must respect.
+    pub target_profile: Option<TargetProfile>,
+    /// Which directory starts the prefix.
+    pub anchor: Anchor,
+    /// Which entries starting with '.' are left alone as hidden.
+    pub dot_files: DotFiles,
+    /// Delete zero-byte files instead of renaming them.
+    pub delete_empty: bool,
+    /// Patterns of filenames, where `*` matches any run of characters and
+    /// `?` any one, which are deleted instead of renamed; see
+    /// `DEFAULT_JUNK`.
+    pub junk: Vec<String>,
+    /// Names starting with '.' which are renamed regardless of
+    /// `dot_files`.
+    pub dot_allowlist: Vec<String>,
+    /// What happens to numbers leading directory names in the prefix.
+    pub numbering: Numbering,
+    /// Turn Roman numerals and ordinals in directory names into plain
+    /// numbers in the prefix, like `part 2` for `Part II`.
+    pub normalize_numerals: bool,
+    /// Unicode normalization form directory names and filenames are put in.
+    pub normalization: Normalization,
+    /// What happens to directory names and filenames which aren't UTF-8.
+    pub non_utf8: NonUtf8,
+    /// Characters folded away in directory names and filenames.
+    pub folding: Folding,
+    /// What happens when an entry's new name is already taken.
+    pub on_collision: CollisionPolicy,
+    /// Decides what happens when an entry's new name is already taken
+    /// instead of `on_collision`, if given.
+    pub resolver: Option<Box<dyn ConflictResolver>>,
+    /// Carry on after a rename fails instead of stopping the run.
+    pub keep_going: bool,
+    /// Give up planning once more collisions than this are found.
+    pub max_collisions: Option<usize>,
+    /// Directories where unwritable mounts begin, which are skipped along
+    /// with everything in them; see `unwritable_mounts()`.
+    pub unwritable: Vec<path::PathBuf>,
+    /// What joins directory names and the filename; `DEFAULT_SEPARATOR`
+    /// if not given.
+    pub separator: Option<String>,
+    /// The case new names are put in.
+    pub case: Case,
+    /// Whose rules putting names in `case` follows.
+    pub case_locale: CaseLocale,
+    /// Replaces directory names in the prefix with opaque tokens, if given.
+    pub opaque: Option<Opaque>,
+    /// Print each rename as `old -> new` instead of making it.
+    pub dry_run: bool,
+    /// Print each rename as a command of this script instead of as
+    /// `old -> new`, when `dry_run`.
+    pub script: Option<Script>,
+    /// Sync each directory to disk after renaming its entries, and only
+    /// then journal the renames.
+    pub fsync: bool,
+    /// Merge sibling directories whose names only differ by case or
+    /// punctuation once renaming is done; see `merge_directories()`.
+    pub merge_directories: bool,
+    /// Rename entries tracked by git with `git mv`, so the index records
+    /// renames; see `move_entry()`.
+    pub git: bool,
+    /// How many threads read the tree and rename entries; 0 or 1 means
+    /// one thread.
+    ///
+    /// The entries of a directory are always renamed by one thread, one
+    /// at a time and in the same order as with one thread, so the new
+    /// names (the ` (2)` of `CollisionPolicy::Number` included) are the
+    /// same whatever the number of jobs. Only different directories are
+    /// renamed at the same time, and never with `dry_run` or `git`.
+    pub jobs: usize,
+    /// How many levels of the tree to flatten, if limited; directories
+    /// any deeper are left as they are, along with everything in them.
+    pub max_depth: Option<usize>,
+    /// Rename immutable and append-only entries by clearing their flags
+    /// and setting them again afterwards, instead of skipping them; see
+    /// `immutable_flags()`. Without it the flags are only looked up once a
+    /// rename is refused, so dry runs don't report such entries.
+    pub clear_immutable: bool,
+    /// Note the size of every file renamed or left alone so likely
+    /// duplicates can be reported; see `Report::find_duplicates()`.
+    pub find_duplicates: bool,
+    /// Absolute paths of files written by the run itself, which are
+    /// neither traversed nor renamed.
+    pub artifacts: Vec<path::PathBuf>,
+}
+
+/// The case new names are put in.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Case {
+    /// `01 come together.flac`
+    #[default]
+    Lower,
+    /// `01 COME TOGETHER.FLAC`
+    Upper,
+    /// `01 Come Together.flac`: words start with a capital, and everything
+    /// else, including any extension or letters after an apostrophe, is
+    /// lowercase.
+    Title,
+    /// The case of the directory names and the filename is kept.
+    Preserve,
+}
+
+impl Case {
+    /// Put `name` in this case following Unicode's default rules.
+    pub fn apply(&self, name: &str) -> String {
+        self.apply_in(name, CaseLocale::Unicode)
+    }
+
+    /// Put `name` in this case following the rules of `locale`.
+    pub fn apply_in(&self, name: &str, locale: CaseLocale) -> String {
+        match *self {
+            Case::Lower => locale.lowercase(name),
+            Case::Upper => locale.uppercase(name),
+            Case::Title => {
+                // Lowercasing everything at once gets context-dependent
+                // letters like the Greek final sigma right.
+                let lowered = locale.lowercase(name);
+                let mut titled = String::with_capacity(lowered.len());
+                let mut word_start = true;
+                for c in lowered.chars() {
+                    if word_start {
+                        titled.push_str(&locale.uppercase(c.encode_utf8(&mut [0; 4])));
+                    } else {
+                        titled.push(c);
+                    }
+                    word_start = !c.is_alphanumeric() && !"'\u{2019}.".contains(c);
+                }
+                titled
+            }
+            Case::Preserve => name.to_string(),
+        }
+    }
+}
+
+/// Whose rules changing case follows.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CaseLocale {
+    /// Unicode's default rules, which suit most languages.
+    #[default]
+    Unicode,
+    /// Turkish and Azerbaijani, where `I` and `ı` as well as `İ` and `i` are
+    /// pairs.
+    Turkic,
+}
+
+impl CaseLocale {
+    fn lowercase(&self, name: &str) -> String {
+        match *self {
+            CaseLocale::Unicode => name.to_lowercase(),
+            CaseLocale::Turkic => name.replace("I\u{307}", "i")
+                                      .replace('\u{130}', "i")
+                                      .replace('I', "\u{131}")
+                                      .to_lowercase(),
+        }
+    }
+
+    fn uppercase(&self, name: &str) -> String {
+        match *self {
+            CaseLocale::Unicode => name.to_uppercase(),
+            CaseLocale::Turkic => name.replace('i', "\u{130}").to_uppercase(),
+        }
+    }
+}
+
+/// What joins directory names and the filename by default.
+pub const DEFAULT_SEPARATOR: &str = " - ";
+
+impl FlattenOptions {
+    /// What decides what happens when an entry's new name is already
+    /// taken.
+    pub fn resolver(&self) -> &dyn ConflictResolver {
+        match self.resolver {
+            Some(ref resolver) => resolver.as_ref(),
+            None => &self.on_collision,
+        }
+    }
+
+    /// What joins directory names and the filename.
+    pub fn separator(&self) -> &str {
+        self.separator.as_deref().unwrap_or(DEFAULT_SEPARATOR)
+    }
+
+    /// Put `name` in `case`, following `case_locale`.
+    pub fn put_in_case(&self, name: &str) -> String {
+        self.case.apply_in(name, self.case_locale)
+    }
+
+    /// Check the options for combinations that would make a run fail
+    /// partway through, or leave an option doing nothing, so they can be
+    /// caught before anything is touched.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        if let Some(max_open) = self.max_open {
+            if self.jobs > max_open {
+                diagnostics.push(Diagnostic::error(
+                    format!("--jobs {} reads up to {} directories at once, more than \
+                             --max-open {} allows", self.jobs, self.jobs, max_open),
+                    format!("lower --jobs to {} or raise --max-open", max_open)));
+            }
+        }
+        if self.clear_immutable && !self.dry_run {
+            if IMMUTABLE_FLAGS == 0 {
+                diagnostics.push(Diagnostic::warning(
+                    "--clear-immutable does nothing on this platform".to_string(),
+                    "leave out --clear-immutable".to_string()));
+            } else if !is_root() {
+                diagnostics.push(Diagnostic::warning(
+                    "--clear-immutable needs root to clear the flags, so immutable files will \
+                     fail to be renamed".to_string(),
+                    "run as root, or leave out --clear-immutable to skip them".to_string()));
+            }
+        }
+        for name in self.dot_allowlist.iter().filter(|name| !name.starts_with('.')) {
+            diagnostics.push(Diagnostic::warning(
+                format!("--rename-dot-file {:?} doesn't start with '.', so it does nothing",
+                        name),
+                format!("give the name with its leading '.', like {:?}", format!(".{}", name))));
+        }
+        for pattern in self.junk.iter().filter(|pattern| pattern.contains(['/', '\\'])) {
+            let name = pattern.rsplit(['/', '\\']).next().unwrap_or_default();
+            diagnostics.push(Diagnostic::warning(
+                format!("--junk {:?} never matches, as only file names are matched", pattern),
+                format!("give just the file name, like {:?}", name)));
+        }
+        if self.case_locale != CaseLocale::Unicode && self.case == Case::Preserve {
+            diagnostics.push(Diagnostic::warning(
+                "--case-locale does nothing with --case preserve".to_string(),
+                "leave out --case-locale, or pick another --case".to_string()));
+        }
+        if let Numbering::Pad(width) = self.numbering {
+            if width < 2 {
+                diagnostics.push(Diagnostic::warning(
+                    format!("--pad-numbers {} pads nothing", width),
+                    "give a width of 2 or more".to_string()));
+            }
+        }
+        if self.fsync && self.dry_run {
+            diagnostics.push(Diagnostic::warning(
+                "--fsync has nothing to sync in a dry run".to_string(),
+                "leave out --fsync".to_string()));
+        }
+        diagnostics
+    }
+}
+
+/// A problem with a combination of options, found before a run starts;
+/// see `FlattenOptions::validate()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    /// Whether the run can't go ahead as asked, rather than only having an
+    /// option do nothing.
+    pub fatal: bool,
+    /// What is wrong.
+    pub problem: String,
+    /// What to do about it.
+    pub fix: String,
+}
+
+impl Diagnostic {
+    pub fn error(problem: String, fix: String) -> Diagnostic {
+        Diagnostic { fatal: true, problem, fix }
+    }
+
+    pub fn warning(problem: String, fix: String) -> Diagnostic {
+        Diagnostic { fatal: false, problem, fix }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}\n  fix: {}", if self.fatal { "error" } else { "warning" },
+               self.problem, self.fix)
+    }
+}
+
+/// Check if the process runs as root.
+#[cfg(unix)]
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Check if the process runs as root, which is never the case here.
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+/// How entries whose names start with '.' are treated.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DotFiles {
+    /// All of them are hidden and left alone.
+    #[default]
+    Hidden,
+    /// Only those without a further '.', like `.config`, are hidden; ones
+    /// like `.env.production` are data files and are renamed.
+    HiddenUnlessExtension,
+}
+
+/// Check if the entry at `path` is hidden according to
+/// `options.dot_files` and `options.dot_allowlist`, and so left alone.
+pub fn is_hidden(path: &path::Path, options: &FlattenOptions) -> bool {
+    if leading_char(path) != '.' {
+        return false;
+    }
+    let name = file_name_lossy(path);
+    if options.dot_allowlist.contains(&name) {
+        return false;
+    }
+    match options.dot_files {
+        DotFiles::Hidden => true,
+        DotFiles::HiddenUnlessExtension => !name[1..].contains('.'),
+    }
+}
+
+/// Names of files operating systems litter directories with, which
+/// `--delete-junk` deletes.
+pub const DEFAULT_JUNK: &[&str] = &["Thumbs.db", ".DS_Store"];
+
+/// Check if the entry at `path` is a file to delete instead of rename
+/// according to `options.delete_empty` and `options.junk`.
+pub fn is_junk(path: &path::Path, options: &FlattenOptions) -> bool {
+    if !options.delete_empty && options.junk.is_empty() {
+        return false;
+    }
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return false,
+    };
+    let name = file_name_lossy(path);
+    (options.delete_empty && metadata.len() == 0) ||
+    options.junk.iter().any(|pattern| glob_matches(pattern, &name))
+}
+
+/// Check if `name` matches `pattern`, where `*` matches any run of
+/// characters and `?` any one character.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) = (pattern.chars().collect(),
+                                                   name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was in `pattern` and the position in `name` it
+    // is currently matching up to, to backtrack to.
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            star = Some((star_p, star_n + 1));
+            p = star_p + 1;
+            n = star_n + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// What happens to a number leading a directory's name, like the `01` of
+/// `01 Intro`, when it becomes part of the prefix.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Numbering {
+    /// The number is kept as it is.
+    #[default]
+    Keep,
+    /// The number and the separators following it are dropped.
+    Strip,
+    /// The number is padded with zeros to the given number of digits.
+    Pad(usize),
+}
+
+/// What happens to directory names and filenames which aren't UTF-8, as
+/// Unix allows.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NonUtf8 {
+    /// Files are skipped with a warning, as are directories along with
+    /// everything in them.
+    #[default]
+    Skip,
+    /// What isn't UTF-8 is replaced by `\u{fffd}`, the replacement
+    /// character, in new names.
+    Lossy,
+}
+
+/// Unicode normalization form directory names and filenames are put in
+/// before becoming part of a new name, so that names from systems which
+/// prefer different forms (like macOS and Linux) don't end up mixed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Normalization {
+    /// Names are left in whatever form they are in.
+    #[default]
+    None,
+    /// Composed, as most Linux and Windows tools expect.
+    Nfc,
+    /// Decomposed, as macOS's HFS+ stores names.
+    Nfd,
+}
+
+impl Normalization {
+    /// Put `name` in this form.
+    pub fn apply(&self, name: &str) -> String {
+        match *self {
+            Normalization::None => name.to_string(),
+            Normalization::Nfc => name.nfc().collect(),
+            Normalization::Nfd => name.nfd().collect(),
+        }
+    }
+}
+
+/// Characters folded into simpler ones, or dropped, in directory names and
+/// filenames, for devices that cannot cope with them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Folding {
+    /// Strip accents and other combining marks, so `é` becomes `e`.
+    pub diacritics: bool,
+    /// Turn curly quotes into straight ones.
+    pub quotes: bool,
+    /// Drop apostrophes, straight or curly.
+    pub apostrophes: bool,
+}
+
+impl Folding {
+    /// Fold `name` as requested.
+    pub fn apply(&self, name: &str) -> String {
+        let mut folded: String = if self.diacritics {
+            name.nfd().filter(|&c| !is_combining_mark(c)).nfc().collect()
+        } else {
+            name.to_string()
+        };
+        if self.quotes {
+            folded = folded.chars()
+                           .map(|c| match c {
+                               '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}' => '\'',
+                               '\u{201c}' | '\u{201d}' | '\u{201e}' | '\u{201f}' => '"',
+                               c => c,
+                           })
+                           .collect();
+        }
+        if self.apostrophes {
+            folded.retain(|c| c != '\'' && c != '\u{2019}' && c != '\u{02bc}');
+        }
+        folded
+    }
+}
+
+/// Number of hex digits in an opaque token.
+const OPAQUE_TOKEN_LEN: usize = 12;
+
+/// Stand-ins for directory names which don't give away the names but are
+/// the same for the same name and seed, so files from one directory still
+/// sort together.
+#[derive(Debug, Default)]
+pub struct Opaque {
+    seed: String,
+    /// Every token handed out, mapped to the name it stands for.
+    tokens: Mutex<BTreeMap<String, String>>,
+}
+
+impl Opaque {
+    pub fn new(seed: &str) -> Opaque {
+        Opaque { seed: seed.to_string(), tokens: Mutex::default() }
+    }
+
+    /// The token standing for the directory name `name`, which is
+    /// remembered for `mapping()`.
+    ///
+    /// Names differing only by case get the same token whatever `Case` the
+    /// run uses, so a directory keeps its token when it is renamed to
+    /// another case or copied to a case-insensitive filesystem. The token
+    /// is lowercase hex, which `Case` can only change the case of, so it
+    /// still matches its `mapping()` key ignoring case.
+    pub fn token(&self, name: &str) -> String {
+        let name = name.to_lowercase();
+        let mut hasher = Sha256::new();
+        hasher.update(self.seed.as_bytes());
+        hasher.update([0]);
+        hasher.update(name.as_bytes());
+        let digest = hasher.finalize();
+        let mut token: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+        token.truncate(OPAQUE_TOKEN_LEN);
+        if let Ok(mut tokens) = self.tokens.lock() {
+            tokens.insert(token.clone(), name);
+        }
+        token
+    }
+
+    /// Every token handed out so far, mapped to the name it stands for.
+    pub fn mapping(&self) -> BTreeMap<String, String> {
+        self.tokens.lock().map(|tokens| tokens.clone()).unwrap_or_default()
+    }
+
+    /// Render `mapping()` as a JSON object.
+    pub fn render_json(&self) -> String {
+        serde_json::to_string_pretty(&self.mapping()).unwrap_or_default()
+    }
+
+    /// Render `mapping()` as a JSON object encrypted with `passphrase` in
+    /// the age format, so `age --decrypt` can read it back.
+    pub fn render_encrypted(&self, passphrase: &str) -> io::Result<Vec<u8>> {
+        let recipient = age::scrypt::Recipient::new(passphrase.to_string().into());
+        age::encrypt(&recipient, (self.render_json() + "\n").as_bytes())
+            .map_err(|e| io::Error::other(e.to_string()))
+    }
+}
+
+/// Kind of script renames are written as for running later, instead of
+/// being made.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Script {
+    /// A POSIX shell script of `mv` and `rm` commands.
+    Sh,
+    /// A PowerShell script of `Rename-Item`, `Move-Item`, and
+    /// `Remove-Item` commands.
+    PowerShell,
+}
+
+impl Script {
+    /// Lines the script starts with.
+    pub fn header(&self) -> &'static str {
+        match *self {
+            Script::Sh => "#!/bin/sh\nset -e\n",
+            // Windows PowerShell only reads scripts as UTF-8 with a BOM.
+            Script::PowerShell => "\u{feff}$ErrorActionPreference = 'Stop'\n",
+        }
+    }
+
+    /// Command renaming the entry at `from` to `to`, or deleting it if
+    /// there is no `to`, replacing whatever goes by `to` only if
+    /// `overwrite`.
+    pub fn command(&self, from: &path::Path, to: Option<&path::Path>, overwrite: bool)
+                   -> Vec<u8> {
+        let mut command = Vec::new();
+        match *self {
+            Script::Sh => {
+                match to {
+                    Some(to) => {
+                        let mv: &[u8] = if overwrite { b"mv -f -- " } else { b"mv -n -- " };
+                        command.extend_from_slice(mv);
+                        command.extend(sh_quote(from));
+                        command.push(b' ');
+                        command.extend(sh_quote(to));
+                    }
+                    None => {
+                        command.extend_from_slice(b"rm -f -- ");
+                        command.extend(sh_quote(from));
+                    }
+                }
+                command.push(b'\n');
+            }
+            Script::PowerShell => {
+                let text = match to {
+                    Some(to) => {
+                        let mut text = String::new();
+                        if overwrite {
+                            text.push_str(&format!("if (Test-Path -LiteralPath {0}) {{ \
+                                                    Remove-Item -LiteralPath {0} -Force }}\n",
+                                                   ps_quote(&to.to_string_lossy())));
+                        }
+                        let name = to.file_name().unwrap_or_default().to_string_lossy();
+                        if from.parent() == to.parent() {
+                            text.push_str(&format!("Rename-Item -LiteralPath {} -NewName {}\n",
+                                                   ps_quote(&from.to_string_lossy()),
+                                                   ps_quote(&name)));
+                        } else {
+                            // Unlike -LiteralPath, -Destination takes wildcards.
+                            let destination = ps_escape_wildcards(&to.to_string_lossy());
+                            text.push_str(&format!("Move-Item -LiteralPath {} -Destination {}\n",
+                                                   ps_quote(&from.to_string_lossy()),
+                                                   ps_quote(&destination)));
+                        }
+                        text
+                    }
+                    None => format!("Remove-Item -LiteralPath {} -Force\n",
+                                    ps_quote(&from.to_string_lossy())),
+                };
+                command.extend_from_slice(text.as_bytes());
+            }
+        }
+        command
+    }
+}
+
+/// Quote `text` as a PowerShell string taken literally.
+pub fn ps_quote(text: &str) -> String {
+    let mut quoted = String::from("'");
+    for c in text.chars() {
+        // PowerShell also takes curly quotes for straight ones.
+        if "'\u{2018}\u{2019}\u{201a}\u{201b}".contains(c) {
+            quoted.push(c);
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// Escape the wildcard characters in `text` for PowerShell parameters
+/// which take wildcards.
+fn ps_escape_wildcards(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "`[]*?".contains(c) {
+            escaped.push('`');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Quote `path` as a single word for a POSIX shell.
+pub fn sh_quote(path: &path::Path) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &byte in path_bytes(path).iter() {
+        if byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// What happens when an entry's new name is already taken.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CollisionPolicy {
+    /// Refuse to start a run when `preflight()` finds collisions, and skip
+    /// any found while renaming.
+    #[default]
+    Fail,
+    /// Skip the entry.
+    Skip,
+    /// Rename the entry anyway, replacing whatever had the name.
+    Overwrite,
+    /// Add ` (2)`, ` (3)`, ... to the new name, before any extension,
+    /// until it is free.
+    Number,
+}
+
+/// Decides what happens when an entry's new name is already taken, for
+/// resolving collisions in ways `CollisionPolicy` doesn't cover.
+///
+/// Resolvers are shared by the threads renaming directories side by side
+/// when there are `FlattenOptions::jobs`.
+pub trait ConflictResolver: Sync {
+    /// Decide what to do about renaming the entry at `entry` to `filename`
+    /// when `taken` already goes by that name. `taken` is in the same
+    /// directory as `entry`, except when merging directories.
+    ///
+    /// Returns the name to give the entry in `taken`'s directory, which is
+    /// `filename` itself to replace `taken`, or `None` to leave the entry
+    /// alone.
+    fn resolve(&self, entry: &path::Path, filename: &str, taken: &path::Path)
+               -> Option<String>;
+}
+
+impl ConflictResolver for CollisionPolicy {
+    fn resolve(&self, _entry: &path::Path, filename: &str, taken: &path::Path)
+               -> Option<String> {
+        match *self {
+            CollisionPolicy::Fail | CollisionPolicy::Skip => None,
+            CollisionPolicy::Overwrite => Some(filename.to_string()),
+            CollisionPolicy::Number => {
+                let (stem, extension) = match filename.rfind('.') {
+                    Some(dot) if dot > 0 => filename.split_at(dot),
+                    _ => (filename, ""),
+                };
+                (2..).map(|number| format!("{} ({}){}", stem, number, extension))
+                     .find(|candidate| fs::symlink_metadata(taken.with_file_name(candidate))
+                                           .is_err())
+            }
+        }
+    }
+}
+
+/// Which directory's name becomes the first segment of the prefix.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Anchor {
+    /// The directory being flattened.
+    #[default]
+    RootIncluded,
+    /// The directories below the one being flattened.
+    RootExcluded,
+    /// The given ancestor of the directory being flattened, with every
+    /// directory in between also becoming part of the prefix.
+    From(path::PathBuf),
+}
+
+/// Work out the prefix of `root`, the directory being flattened,
+/// according to `anchor`.
+pub fn anchored_prefix(root: &path::Path, options: &FlattenOptions) -> Result<String, String> {
+    match options.anchor {
+        Anchor::RootIncluded => directory_prefix(root, "", options),
+        Anchor::RootExcluded => Ok(String::new()),
+        Anchor::From(ref ancestor) => {
+            let relative = match root.strip_prefix(ancestor) {
+                Ok(relative) => relative,
+                Err(_) => return Err(format!("{:?} is not inside {:?}", root, ancestor)),
+            };
+            let mut prefix = match ancestor.file_name() {
+                Some(_) => directory_prefix(ancestor, "", options)?,
+                None => String::new(),
+            };
+            for component in relative.iter() {
+                match component.to_str() {
+                    Some(name) => prefix = extend_prefix(&prefix, name, options),
+                    None if options.non_utf8 == NonUtf8::Lossy => {
+                        prefix = extend_prefix(&prefix, &component.to_string_lossy(), options)
+                    }
+                    None => return Err(format!("{:?} is not valid UTF-8", component)),
+                }
+            }
+            Ok(prefix)
+        }
+    }
+}
+
+/// Entries of a directory being walked.
+type DirEntries = Box<dyn Iterator<Item = io::Result<fs::DirEntry>>>;
+
+/// Extensions used by browsers and download managers for files which
+/// are still being written.
+const PARTIAL_EXTENSIONS: [&str; 4] = ["part", "partial", "crdownload", "download"];
+
+/// Leading part of the name of executables which provide naming schemes.
+pub const SCHEME_PREFIX: &str = "flatten-scheme-";
+
+/// Version of the JSON protocol spoken with naming schemes.
+const SCHEME_PROTOCOL: u64 = 1;
+
+/// `schema_version` of journals, and of plans, which are written the same
+/// way; see `journal_entry()`.
+pub const JOURNAL_SCHEMA: u64 = 1;
+
+/// `schema_version` of `--manifest` files, given in a column of every row.
+pub const MANIFEST_SCHEMA: u64 = 1;
+
+/// `schema_version` of JSON reports; see `Report::render_json()`.
+pub const REPORT_SCHEMA: u64 = 1;
+
+/// `schema_version` of every event streamed to `Report::events`.
+pub const EVENTS_SCHEMA: u64 = 1;
+
+/// `schema_version` of JSON listings; see `VirtualListing::render_json()`.
+pub const LISTING_SCHEMA: u64 = 1;
+
+/// Version of each format runs read or write, bumped whenever one changes
+/// in a way older readers can't follow.
+pub const FORMAT_VERSIONS: [(&str, u64); 7] = [
+    ("journal", JOURNAL_SCHEMA),
+    ("plan", JOURNAL_SCHEMA),
+    ("manifest", MANIFEST_SCHEMA),
+    ("report", REPORT_SCHEMA),
+    ("events", EVENTS_SCHEMA),
+    ("listing", LISTING_SCHEMA),
+    ("scheme protocol", SCHEME_PROTOCOL),
+];
+
+/// Optional capabilities compiled into this build, which depend on the
+/// platform it was built for.
+pub fn compiled_features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(unix) {
+        features.extend(["as-user", "control-socket", "pause-signals"]);
+    }
+    if cfg!(any(target_os = "linux", target_os = "macos")) {
+        features.push("immutable-flags");
+    }
+    features
+}
+
+/// Describe this build in enough detail to reproduce a problem with it:
+/// its version, target, profile, features, and format versions.
+pub fn build_details() -> String {
+    let features = compiled_features();
+    let formats: Vec<String> = FORMAT_VERSIONS.iter()
+                                              .map(|(name, version)| {
+                                                  format!("{} {}", name, version)
+                                              })
+                                              .collect();
+    format!("flatten-filenames {}\n\
+             target: {}\n\
+             profile: {}\n\
+             features: {}\n\
+             formats: {}",
+            env!("CARGO_PKG_VERSION"), env!("FLATTEN_FILENAMES_TARGET"),
+            env!("FLATTEN_FILENAMES_PROFILE"),
+            if features.is_empty() { "(none)".to_string() } else { features.join(", ") },
+            formats.join(", "))
+}
+
+/// What happened to a single entry during a run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Renamed,
+    Skipped(SkipReason),
+    Deferred,
+}
+
+/// Why an entry was left alone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SkipReason {
+    /// The name starts with '.'.
+    DotFile,
+    /// A directory that has not settled according to `FlattenOptions::settle`.
+    Unsettled,
+    /// The name is not valid UTF-8.
+    NonUtf8,
+    /// The naming scheme asked for the entry to be left alone.
+    SchemeDeclined,
+    /// The naming scheme failed or gave an invalid answer.
+    SchemeFailed,
+    /// Renaming was not permitted.
+    PermissionDenied,
+    /// Renaming failed for any other reason.
+    RenameFailed,
+    /// A file written by the run itself.
+    Artifact,
+    /// The new name was already taken, per `FlattenOptions::on_collision`.
+    Collision,
+    /// A directory on a mount that can't be written to, per
+    /// `FlattenOptions::unwritable`.
+    ReadOnly,
+    /// Renaming failed as a disk quota was exceeded.
+    QuotaExceeded,
+    /// The entry is immutable or append-only, per `immutable_flags()`.
+    Immutable,
+}
+
+impl SkipReason {
+    /// Name used for the reason in JSON output.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SkipReason::DotFile => "dot_file",
+            SkipReason::Unsettled => "unsettled",
+            SkipReason::NonUtf8 => "non_utf8",
+            SkipReason::SchemeDeclined => "scheme_declined",
+            SkipReason::SchemeFailed => "scheme_failed",
+            SkipReason::PermissionDenied => "permission_denied",
+            SkipReason::RenameFailed => "rename_failed",
+            SkipReason::Artifact => "artifact",
+            SkipReason::Collision => "collision",
+            SkipReason::ReadOnly => "read_only",
+            SkipReason::QuotaExceeded => "quota_exceeded",
+            SkipReason::Immutable => "immutable",
+        }
+    }
+
+    /// Check if the entry was skipped because renaming it failed.
+    pub fn is_failure(&self) -> bool {
+        *self == SkipReason::PermissionDenied || *self == SkipReason::RenameFailed ||
+        *self == SkipReason::QuotaExceeded
+    }
+}
+
+/// Tally of outcomes for (part of) a run.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counts {
+    pub renamed: usize,
+    pub skipped: usize,
+    /// Entries among `skipped` whose rename failed.
+    pub failed: usize,
+    pub deferred: usize,
+}
+
+impl Counts {
+    fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Renamed => self.renamed += 1,
+            Outcome::Skipped(reason) => {
+                self.skipped += 1;
+                if reason.is_failure() {
+                    self.failed += 1;
+                }
+            }
+            Outcome::Deferred => self.deferred += 1,
+        }
+    }
+}
+
+/// How often live progress snapshots are written.
+const PROGRESS_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// Live progress of a run, written as periodic JSON lines snapshots.
+pub struct ProgressStream {
+    output: Box<dyn Write + Send>,
+    started: time::Instant,
+    last: Option<time::Instant>,
+}
+
+impl ProgressStream {
+    pub fn new(output: Box<dyn Write + Send>) -> ProgressStream {
+        ProgressStream {
+            output,
+            started: time::Instant::now(),
+            last: None,
+        }
+    }
+
+    /// Write a snapshot unless one was written within `PROGRESS_INTERVAL`.
+    fn update(&mut self, totals: &Counts, warnings: usize) -> io::Result<()> {
+        let now = time::Instant::now();
+        match self.last {
+            Some(last) if now.duration_since(last) < PROGRESS_INTERVAL => Ok(()),
+            _ => {
+                self.last = Some(now);
+                self.write(totals, warnings, false)
+            }
+        }
+    }
+
+    fn write(&mut self, totals: &Counts, warnings: usize, done: bool) -> io::Result<()> {
+        let snapshot = progress_snapshot(totals, warnings, self.started.elapsed(), done);
+        writeln!(self.output, "{}", snapshot)?;
+        self.output.flush()
+    }
+}
+
+/// Create a progress snapshot of a run that has been going for `elapsed`.
+pub fn progress_snapshot(totals: &Counts, warnings: usize, elapsed: time::Duration,
+                         done: bool) -> serde_json::Value {
+    let scanned = totals.renamed + totals.skipped + totals.deferred;
+    let seconds = elapsed.as_secs_f64();
+    let rate = if seconds > 0.0 { scanned as f64 / seconds } else { 0.0 };
+    json!({
+        "elapsed": seconds,
+        "scanned": scanned,
+        "renamed": totals.renamed,
+        "skipped": totals.skipped,
+        "deferred": totals.deferred,
+        "warnings": warnings,
+        "rate": rate,
+        "done": done,
+    })
+}
+
+/// How often the progress bar is redrawn.
+const PROGRESS_BAR_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// Frames of the spinner leading the progress bar.
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+/// A progress bar redrawn in place on stderr, for when it is a terminal.
+pub struct ProgressBar {
+    total: Option<usize>,
+    started: time::Instant,
+    last: Option<time::Instant>,
+    frame: usize,
+}
+
+impl ProgressBar {
+    /// Create a progress bar for a run expected to look at `total`
+    /// entries, if that is known; see `count_entries()`.
+    pub fn new(total: Option<usize>) -> ProgressBar {
+        ProgressBar {
+            total,
+            started: time::Instant::now(),
+            last: None,
+            frame: 0,
+        }
+    }
+
+    /// Redraw the bar unless it was drawn within `PROGRESS_BAR_INTERVAL`.
+    fn update(&mut self, processed: usize, totals: &Counts) {
+        let now = time::Instant::now();
+        if let Some(last) = self.last {
+            if now.duration_since(last) < PROGRESS_BAR_INTERVAL {
+                return;
+            }
+        }
+        self.last = Some(now);
+        self.frame = (self.frame + 1) % SPINNER.len();
+        let line = progress_line(processed, self.total, totals, self.started.elapsed());
+        let mut stderr = io::stderr();
+        let _ = write!(stderr, "\r\x1b[2K{} {}", SPINNER[self.frame], line);
+        let _ = stderr.flush();
+    }
+
+    /// Erase the bar so something else can be printed in its place; it is
+    /// drawn again on the next update.
+    fn clear(&self) {
+        let _ = write!(io::stderr(), "\r\x1b[2K");
+    }
+}
+
+/// Describe the progress of a run that has been going for `elapsed` and
+/// has looked at `processed` entries out of `total`, if known, in which
+/// case how much longer the run will take is estimated too.
+pub fn progress_line(processed: usize, total: Option<usize>, totals: &Counts,
+                     elapsed: time::Duration) -> String {
+    let processed_text = match total {
+        Some(total) => format!("{} / {}", group_digits(processed), group_digits(total)),
+        None => group_digits(processed),
+    };
+    let mut line = format!("{} processed, {} renamed, {} skipped [{}]",
+                           processed_text, group_digits(totals.renamed),
+                           group_digits(totals.skipped), clock_time(elapsed));
+    match total {
+        Some(total) if processed > 0 && processed <= total => {
+            let remaining = elapsed.mul_f64((total - processed) as f64 / processed as f64);
+            line.push_str(&format!(" ETA {}", clock_time(remaining)));
+        }
+        _ => (),
+    }
+    line
+}
+
+/// Format `duration` as hours, minutes, and seconds, e.g. "1:02:03".
+fn clock_time(duration: time::Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// Format `number` with its digits grouped in threes, e.g. "12,345".
+pub fn group_digits(number: usize) -> String {
+    let digits = number.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+/// Add the Prometheus gauge `flatten_filenames_last_run_<name>` to
+/// `text`, with a sample per reason label; an empty reason means no label.
+fn push_gauge(text: &mut String, name: &str, help: &str, samples: &[(&str, f64)]) {
+    let name = format!("flatten_filenames_last_run_{}", name);
+    text.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n", name, help, name));
+    for (reason, value) in samples {
+        if reason.is_empty() {
+            text.push_str(&format!("{} {}\n", name, value));
+        } else {
+            text.push_str(&format!("{}{{reason=\"{}\"}} {}\n", name, reason, value));
+        }
+    }
+}
+
+/// Lets a run be paused, resumed, cancelled, and asked for its status
+/// from another thread.
+pub struct Control {
+    state: Mutex<ControlState>,
+    changed: Condvar,
+    started: time::Instant,
+}
+
+/// State shared through a `Control`.
+#[derive(Default)]
+struct ControlState {
+    paused: bool,
+    cancelled: bool,
+    totals: Counts,
+    warnings: usize,
+}
+
+impl Default for Control {
+    fn default() -> Control {
+        Control {
+            state: Mutex::new(ControlState::default()),
+            changed: Condvar::new(),
+            started: time::Instant::now(),
+        }
+    }
+}
+
+impl Control {
+    /// Publish the run's status and wait for as long as it is paused.
+    ///
+    /// Returns `false` once the run has been cancelled.
+    pub fn checkpoint(&self, totals: &Counts, warnings: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.totals = *totals;
+        state.warnings = warnings;
+        while state.paused && !state.cancelled {
+            state = self.changed.wait(state).unwrap();
+        }
+        !state.cancelled
+    }
+
+    /// Check if the run has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.lock().unwrap().cancelled
+    }
+
+    /// Carry out one of the `status`, `pause`, `resume`, or `cancel`
+    /// commands, returning the response to send back.
+    pub fn command(&self, command: &str) -> serde_json::Value {
+        let mut state = self.state.lock().unwrap();
+        match command.trim() {
+            "status" => (),
+            "pause" => state.paused = true,
+            "resume" => state.paused = false,
+            "cancel" => state.cancelled = true,
+            command => return json!({"error": format!("unknown command: {:?}", command)}),
+        }
+        self.changed.notify_all();
+
+        let name = if state.cancelled {
+            "cancelled"
+        } else if state.paused {
+            "paused"
+        } else {
+            "running"
+        };
+        let mut response = progress_snapshot(&state.totals, state.warnings,
+                                             self.started.elapsed(), false);
+        response["state"] = json!(name);
+        response
+    }
+}
+
+/// Serve commands for `control` on a Unix socket at `path` from a
+/// background thread.
+///
+/// Each connection sends a single command line and receives a single
+/// JSON line in response.
+#[cfg(unix)]
+pub fn serve_control(path: &path::Path, control: Arc<Control>) -> io::Result<()> {
+    use std::io::BufRead;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    let listener = UnixListener::bind(path)?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let mut command = String::new();
+            if io::BufReader::new(&stream).read_line(&mut command).is_err() {
+                continue;
+            }
+            let response = control.command(&command);
+            // The client going away early is its own problem.
+            let _ = writeln!(&stream, "{}", response);
+        }
+    });
+    Ok(())
+}
+
+/// Serve commands for `control` on a Unix socket at `path`.
+#[cfg(not(unix))]
+pub fn serve_control(_path: &path::Path, _control: Arc<Control>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "control sockets require Unix"))
+}
+
+/// Whether the run was paused by SIGUSR1 (and not yet resumed by SIGUSR2).
+static SIGNAL_PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// How often a run paused by a signal checks whether it was resumed.
+const SIGNAL_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+#[cfg(unix)]
+extern "C" fn handle_pause_signal(signal: libc::c_int) {
+    SIGNAL_PAUSED.store(signal == libc::SIGUSR1, Ordering::SeqCst);
+}
+
+/// Pause runs after their current operation on SIGUSR1 and resume them
+/// on SIGUSR2.
+#[cfg(unix)]
+pub fn install_pause_signals() {
+    let handler = handle_pause_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGUSR1, handler);
+        libc::signal(libc::SIGUSR2, handler);
+    }
+}
+
+/// Pause runs after their current operation on SIGUSR1 and resume them
+/// on SIGUSR2.
+#[cfg(not(unix))]
+pub fn install_pause_signals() {}
+
+/// The last operation on the filesystem a run attempted, for incident
+/// reports.
+static LAST_OPERATION: Mutex<String> = Mutex::new(String::new());
+
+/// Note `operation` as the last one attempted.
+fn note_operation(operation: String) {
+    *LAST_OPERATION.lock().unwrap_or_else(|e| e.into_inner()) = operation;
+}
+
+/// The last operation on the filesystem a run attempted, if any.
+pub fn last_operation() -> String {
+    LAST_OPERATION.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Create an identifier for this run which is unlikely to be shared by
+/// any other.
+pub fn new_run_id() -> String {
+    let since = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap_or_default();
+    format!("{}-{}", since.as_secs(), process::id())
+}
+
+/// Write up a crash of run `run_id`, started with `arguments`, which
+/// panicked with `message`, for attaching to a bug report.
+pub fn incident_report(run_id: &str, arguments: &[String], message: &str,
+                       backtrace: &str) -> String {
+    let last_operation = last_operation();
+    format!("flatten-filenames crashed\n\
+             run: {}\n\
+             version: {}\n\
+             platform: {} {}\n\
+             arguments: {}\n\
+             last operation: {}\n\
+             panic: {}\n\
+             backtrace:\n{}\n",
+            run_id, env!("CARGO_PKG_VERSION"), env::consts::OS,
+            env::consts::ARCH, arguments.join(" "),
+            if last_operation.is_empty() { "(none)" } else { &last_operation },
+            message, backtrace)
+}
+
+/// On a panic, also write an incident report (see `incident_report()`)
+/// for run `run_id` to the temporary directory and say where it is.
+pub fn install_panic_hook(run_id: String, arguments: Vec<String>) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let backtrace = std::backtrace::Backtrace::force_capture();
+        let message = match info.location() {
+            Some(location) => format!("{} at {}", info.payload_as_str().unwrap_or("(unknown)"),
+                                      location),
+            None => info.payload_as_str().unwrap_or("(unknown)").to_string(),
+        };
+        let report = incident_report(&run_id, &arguments, &message, &backtrace.to_string());
+        let incident = env::temp_dir().join(format!("flatten-filenames-incident-{}.txt",
+                                                    run_id));
+        match fs::write(&incident, report) {
+            Ok(()) => println_stderr(format!("an incident report was written to {}; please \
+                                              attach it to a bug report",
+                                             incident.display())),
+            Err(e) => println_stderr(format!("{}: {}", incident.display(), e)),
+        }
+    }));
+}
+
+/// Why a run, or applying or undoing renames, could not go ahead.
+///
+/// Problems with single entries don't stop a run; they are reported as
+/// warnings and skips instead.
+#[derive(Debug)]
+pub enum FlattenError {
+    /// Reading or renaming `path` failed.
+    Io(path::PathBuf, io::Error),
+    /// The name of `path` isn't UTF-8, so no new name can be made from it.
+    NonUtf8(path::PathBuf),
+    /// No prefix can be made for the directory being flattened.
+    Prefix(String),
+    /// A line of the journal or plan at `path` isn't an entry.
+    InvalidJournal(path::PathBuf, String),
+    /// The journal, plan, or manifest at `path` has a `schema_version`
+    /// other than the one given, which this version can't read.
+    UnsupportedSchema(path::PathBuf, String, u64),
+    /// Writing the journal failed.
+    Journal(io::Error),
+    /// Planning found more collisions than `FlattenOptions::max_collisions`
+    /// and was given up.
+    TooManyCollisions(usize),
+}
+
+impl fmt::Display for FlattenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FlattenError::Io(ref path, ref e) => write!(f, "{}: {}", path.display(), e),
+            FlattenError::NonUtf8(ref path) => {
+                write!(f, "{}: filename is not UTF-8", path.display())
+            }
+            FlattenError::Prefix(ref message) => write!(f, "{}", message),
+            FlattenError::InvalidJournal(ref path, ref line) => {
+                write!(f, "{}: not a journal entry: {}", path.display(), line)
+            }
+            FlattenError::UnsupportedSchema(ref path, ref version, supported) => {
+                write!(f, "{}: schema_version {} is not supported (this version of \
+                           flatten-filenames reads {})", path.display(), version, supported)
+            }
+            FlattenError::Journal(ref e) => write!(f, "writing the journal: {}", e),
+            FlattenError::TooManyCollisions(max) => {
+                write!(f, "more than {} collisions found, so planning was given up; a different \
+                           separator or anchor may avoid them", max)
+            }
+        }
+    }
+}
+
+impl error::Error for FlattenError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            FlattenError::Io(_, ref e) | FlattenError::Journal(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Maximum number of warnings listed in a text report.
+const REPORT_WARNINGS: usize = 10;
+
+/// Summary of a run.
+pub struct Report {
+    /// Directory that was flattened.
+    pub root: path::PathBuf,
+    /// Outcomes across the whole run.
+    pub totals: Counts,
+    /// Outcomes per top-level directory of `root`; entries directly in
+    /// `root` are tallied under ".".
+    pub subtrees: BTreeMap<String, Counts>,
+    /// Problems worth a human's attention.
+    pub warnings: Vec<String>,
+    /// Original and new path of every renamed entry.
+    pub renames: Vec<(path::PathBuf, path::PathBuf)>,
+    /// Every skipped entry and why it was skipped.
+    pub skips: Vec<(path::PathBuf, SkipReason)>,
+    /// Every file deleted as junk; see `is_junk()`.
+    pub deletions: Vec<path::PathBuf>,
+    /// Where live progress is streamed to, if anywhere.
+    pub progress: Option<ProgressStream>,
+    /// The progress bar shown on stderr, if any.
+    pub progress_bar: Option<ProgressBar>,
+    /// How many entries have been looked at so far, whatever became of
+    /// them.
+    pub processed: usize,
+    /// Where every event of the run (directories scanned, renames, skips,
+    /// errors, and warnings) is streamed to as a JSON line when it
+    /// happens, if anywhere.
+    pub events: Option<Box<dyn Write + Send>>,
+    /// Where each rename is journaled as it happens so it can be undone,
+    /// if anywhere.
+    pub journal: Option<Box<dyn Write + Send>>,
+    /// Where every renamed or failed entry is recorded as a CSV row for
+    /// auditing, if anywhere; see `start_manifest()`.
+    pub manifest: Option<Box<dyn Write + Send>>,
+    /// What can pause or cancel the run, if anything.
+    pub control: Option<Arc<Control>>,
+    /// Keep warnings in the report without also printing them to stderr.
+    pub quiet: bool,
+    /// How much to print to stderr as the run goes: at 1, every rename; at
+    /// 2, also every skipped entry and whether each directory is
+    /// descended into.
+    pub verbose: u8,
+    /// When the run started.
+    pub started: time::Instant,
+    /// `plan_hash()` of the renames planned, when only planning.
+    pub plan_hash: Option<String>,
+    /// Size of every non-empty file seen, by where it is now, when looking
+    /// for duplicates.
+    pub sizes: HashMap<path::PathBuf, u64>,
+    /// Groups of files with the same size and contents, as found by
+    /// `find_duplicates()`.
+    pub duplicates: Vec<Vec<path::PathBuf>>,
+    /// Every entry that failed to be renamed and why.
+    pub failures: Vec<(path::PathBuf, String)>,
+    /// Whether the run stopped after a failure.
+    pub stopped: bool,
+    /// How many renames may fail before the run stops even when keeping
+    /// going; unlimited if `None`.
+    pub max_errors: Option<usize>,
+    /// Entries left alone because the run stopped early; a directory
+    /// stands for everything in it.
+    pub unprocessed: Vec<path::PathBuf>,
+}
+
+impl Report {
+    pub fn new(root: &path::Path) -> Report {
+        Report {
+            root: root.to_path_buf(),
+            totals: Counts::default(),
+            subtrees: BTreeMap::new(),
+            warnings: Vec::new(),
+            renames: Vec::new(),
+            skips: Vec::new(),
+            deletions: Vec::new(),
+            progress: None,
+            progress_bar: None,
+            processed: 0,
+            events: None,
+            journal: None,
+            manifest: None,
+            control: None,
+            quiet: false,
+            verbose: 0,
+            started: time::Instant::now(),
+            plan_hash: None,
+            sizes: HashMap::new(),
+            duplicates: Vec::new(),
+            failures: Vec::new(),
+            stopped: false,
+            max_errors: None,
+            unprocessed: Vec::new(),
+        }
+    }
+
+    /// Summarize the run so far in a single line.
+    pub fn status_line(&self) -> String {
+        format!("{} renamed, {} skipped, {} deferred, {} warnings",
+                self.totals.renamed, self.totals.skipped, self.totals.deferred,
+                self.warnings.len())
+    }
+
+    /// Give signals and `control` the chance to pause the run, returning
+    /// `false` if the run has been cancelled and should stop.
+    pub fn checkpoint(&self) -> bool {
+        if self.stopped {
+            return false;
+        }
+        if SIGNAL_PAUSED.load(Ordering::SeqCst) {
+            println_stderr(format!("paused ({})", self.status_line()));
+            while SIGNAL_PAUSED.load(Ordering::SeqCst) && !self.is_cancelled() {
+                std::thread::sleep(SIGNAL_POLL_INTERVAL);
+            }
+            println_stderr(format!("resumed ({})", self.status_line()));
+        }
+        match self.control {
+            Some(ref control) => control.checkpoint(&self.totals, self.warnings.len()),
+            None => true,
+        }
+    }
+
+    /// Check if the run was cancelled through `control`.
+    pub fn is_cancelled(&self) -> bool {
+        match self.control {
+            Some(ref control) => control.is_cancelled(),
+            None => false,
+        }
+    }
+
+    /// Count another entry as looked at, redrawing the progress bar.
+    pub fn record_processed(&mut self) {
+        self.processed += 1;
+        if let Some(ref mut progress_bar) = self.progress_bar {
+            progress_bar.update(self.processed, &self.totals);
+        }
+    }
+
+    /// Record what happened to the entry at `path`.
+    pub fn record(&mut self, path: &path::Path, outcome: Outcome) {
+        let mut components = path.strip_prefix(&self.root)
+                                 .map(|relative| relative.iter())
+                                 .unwrap_or_else(|_| path::Path::new("").iter());
+        let subtree = match (components.next(), components.next()) {
+            (Some(top), Some(_)) => top.to_string_lossy().into_owned(),
+            _ => ".".to_string(),
+        };
+        self.totals.add(outcome);
+        self.subtrees.entry(subtree).or_default().add(outcome);
+        if let Outcome::Skipped(reason) = outcome {
+            self.log(2, format!("skipped {:?} ({})", path, reason.name()));
+            self.skips.push((path.to_path_buf(), reason));
+            if !reason.is_failure() {
+                let path = portable_path(&self.root, path);
+                self.emit(json!({"event": "skip", "path": path, "reason": reason.name()}));
+            }
+        }
+
+        let written = match self.progress {
+            Some(ref mut progress) => progress.update(&self.totals, self.warnings.len()),
+            None => Ok(()),
+        };
+        if let Err(e) = written {
+            self.progress = None;
+            self.warn(format!("stopped streaming progress: {}", e));
+        }
+    }
+
+    /// Record that the entry at `from` was renamed to `to`.
+    pub fn record_rename(&mut self, from: &path::Path, to: &path::Path) {
+        self.write_journal(from, to);
+        self.record_held_rename(from, to);
+    }
+
+    /// Record that the entry at `from` was renamed to `to` without
+    /// journaling it yet; see `write_held_journal()`.
+    fn record_held_rename(&mut self, from: &path::Path, to: &path::Path) {
+        self.renames.push((from.to_path_buf(), to.to_path_buf()));
+        self.record(from, Outcome::Renamed);
+        self.write_manifest(from, Some(to), "renamed");
+        let (from, to) = (portable_path(&self.root, from), portable_path(&self.root, to));
+        self.emit(json!({"event": "rename", "from": from, "to": to}));
+    }
+
+    /// Record that the file at `path`, `size` bytes long, was deleted as
+    /// junk.
+    pub fn record_deletion(&mut self, path: &path::Path, size: u64) {
+        self.deletions.push(path.to_path_buf());
+        let written = match self.journal {
+            Some(ref mut journal) => writeln!(journal, "{}", deletion_entry(path, size)),
+            None => Ok(()),
+        };
+        if let Err(e) = written {
+            self.journal = None;
+            self.warn(format!("stopped writing the journal: {}", e));
+        }
+        self.write_manifest(path, None, "deleted");
+        let path = portable_path(&self.root, path);
+        self.emit(json!({"event": "delete", "path": path}));
+    }
+
+    /// Note the size of the file at `path`, unless it is empty, for
+    /// `find_duplicates()`.
+    pub fn record_size(&mut self, path: &path::Path) {
+        match fs::symlink_metadata(path) {
+            Ok(ref metadata) if metadata.is_file() && metadata.len() > 0 => {
+                self.sizes.insert(path.to_path_buf(), metadata.len());
+            }
+            _ => {}
+        }
+    }
+
+    /// Find the files in `sizes` which are likely duplicates of each
+    /// other: those of the same size whose contents also hash the same.
+    pub fn find_duplicates(&mut self) {
+        let mut by_size: BTreeMap<u64, Vec<path::PathBuf>> = BTreeMap::new();
+        for (path, &size) in &self.sizes {
+            by_size.entry(size).or_default().push(path.clone());
+        }
+        let mut duplicates = Vec::new();
+        for paths in by_size.values_mut().filter(|paths| paths.len() > 1) {
+            paths.sort();
+            let mut by_hash: BTreeMap<String, Vec<path::PathBuf>> = BTreeMap::new();
+            for path in paths.iter() {
+                match content_hash(path) {
+                    Ok(hash) => by_hash.entry(hash).or_default().push(path.clone()),
+                    Err(e) => self.warn(format!("{:?}: {}", path, e)),
+                }
+            }
+            duplicates.extend(by_hash.into_values().filter(|paths| paths.len() > 1));
+        }
+        duplicates.sort();
+        for paths in &duplicates {
+            let paths: Vec<String> = paths.iter()
+                                          .map(|path| portable_path(&self.root, path))
+                                          .collect();
+            self.emit(json!({"event": "duplicates", "paths": paths}));
+        }
+        self.duplicates = duplicates;
+    }
+
+    /// Record that `directory` is being scanned.
+    pub fn record_scan(&mut self, directory: &path::Path) {
+        note_operation(format!("reading {:?}", directory));
+        let path = portable_path(&self.root, directory);
+        self.emit(json!({"event": "scan", "path": path}));
+    }
+
+    /// Stream `event` to `events`, adding its `schema_version`.
+    fn emit(&mut self, mut event: serde_json::Value) {
+        event["schema_version"] = json!(EVENTS_SCHEMA);
+        let written = match self.events {
+            Some(ref mut events) => writeln!(events, "{}", event).and_then(|()| events.flush()),
+            None => Ok(()),
+        };
+        if let Err(e) = written {
+            self.events = None;
+            self.warn(format!("stopped streaming events: {}", e));
+        }
+    }
+
+    /// Journal that the entry at `from` was renamed to `to`.
+    fn write_journal(&mut self, from: &path::Path, to: &path::Path) {
+        let written = match self.journal {
+            Some(ref mut journal) => writeln!(journal, "{}", journal_entry(from, to)),
+            None => Ok(()),
+        };
+        if let Err(e) = written {
+            self.journal = None;
+            self.warn(format!("stopped writing the journal: {}", e));
+        }
+    }
+
+    /// Record renamed and failed entries in `manifest` from now on,
+    /// starting with the header row.
+    pub fn start_manifest(&mut self, mut manifest: Box<dyn Write + Send>) -> io::Result<()> {
+        writeln!(manifest, "{}", MANIFEST_HEADER)?;
+        self.manifest = Some(manifest);
+        Ok(())
+    }
+
+    /// Add a manifest row saying the entry at `from` ended up `status`,
+    /// at `to` if it was moved.
+    fn write_manifest(&mut self, from: &path::Path, to: Option<&path::Path>, status: &str) {
+        let written = match self.manifest {
+            Some(ref mut manifest) => {
+                let to = to.map(|to| to.to_string_lossy()).unwrap_or_default();
+                writeln!(manifest, "{},{},{},{},{}",
+                         csv_field(&from.to_string_lossy()), csv_field(&to),
+                         utc_timestamp(time::SystemTime::now()), status, MANIFEST_SCHEMA)
+            }
+            None => Ok(()),
+        };
+        if let Err(e) = written {
+            self.manifest = None;
+            self.warn(format!("stopped writing the manifest: {}", e));
+        }
+    }
+
+    /// Journal `renames`, which were held back until they were synced to
+    /// disk, and flush the journal.
+    fn write_held_journal(&mut self, renames: &[(path::PathBuf, path::PathBuf)]) {
+        for (from, to) in renames {
+            self.write_journal(from, to);
+        }
+        let flushed = match self.journal {
+            Some(ref mut journal) => journal.flush(),
+            None => Ok(()),
+        };
+        if let Err(e) = flushed {
+            self.journal = None;
+            self.warn(format!("stopped writing the journal: {}", e));
+        }
+    }
+
+    /// Mark the run as done, writing a final progress snapshot and
+    /// removing the progress bar.
+    pub fn finish(&mut self) {
+        self.clear_progress_bar();
+        self.progress_bar = None;
+        let written = match self.progress {
+            Some(ref mut progress) => progress.write(&self.totals, self.warnings.len(), true),
+            None => Ok(()),
+        };
+        if let Err(e) = written {
+            self.progress = None;
+            self.warn(format!("stopped streaming progress: {}", e));
+        }
+        let (totals, deleted, warnings) = (self.totals, self.deletions.len(),
+                                           self.warnings.len());
+        self.emit(json!({
+            "event": "done",
+            "renamed": totals.renamed,
+            "deleted": deleted,
+            "skipped": totals.skipped,
+            "failed": totals.failed,
+            "deferred": totals.deferred,
+            "warnings": warnings,
+        }));
+    }
+
+    /// Print `message` to stderr if `verbose` is at least `level`, unless
+    /// `quiet`.
+    pub fn log(&self, level: u8, message: String) {
+        if !self.quiet && self.verbose >= level {
+            self.clear_progress_bar();
+            println_stderr(message);
+        }
+    }
+
+    /// Record a warning, also printing it to stderr.
+    pub fn warn(&mut self, message: String) {
+        if !self.quiet {
+            self.clear_progress_bar();
+            println_stderr(message.clone());
+        }
+        self.emit(json!({"event": "warning", "message": message}));
+        self.warnings.push(message);
+    }
+
+    /// Erase the progress bar, if any, so a message can take its line.
+    fn clear_progress_bar(&self) {
+        if let Some(ref progress_bar) = self.progress_bar {
+            progress_bar.clear();
+        }
+    }
+
+    /// Record that renaming the entry at `path` failed with `e`, stopping
+    /// the run unless `keep_going` and the failure leaves room to, and
+    /// `max_errors` hasn't been exceeded.
+    pub fn record_failure(&mut self, path: &path::Path, e: &io::Error, keep_going: bool) {
+        self.warn(format!("{:?}: {}", path, e));
+        self.failures.push((path.to_path_buf(), e.to_string()));
+        self.write_manifest(path, None, "failed");
+        let relative = portable_path(&self.root, path);
+        self.emit(json!({"event": "error", "path": relative, "error": e.to_string()}));
+        let reason = if e.kind() == io::ErrorKind::QuotaExceeded {
+            SkipReason::QuotaExceeded
+        } else if e.kind() == io::ErrorKind::PermissionDenied {
+            SkipReason::PermissionDenied
+        } else {
+            SkipReason::RenameFailed
+        };
+        self.record(path, Outcome::Skipped(reason));
+        // Nothing more will fit until the quota is raised.
+        self.stopped = !keep_going || reason == SkipReason::QuotaExceeded ||
+                       self.too_many_errors();
+    }
+
+    /// Check if more renames failed than `max_errors` allows.
+    pub fn too_many_errors(&self) -> bool {
+        self.max_errors.map(|max_errors| self.failures.len() > max_errors).unwrap_or(false)
+    }
+
+    /// Check if a rename failed as a disk quota was exceeded.
+    pub fn quota_exceeded(&self) -> bool {
+        self.skips.iter().any(|&(_, reason)| reason == SkipReason::QuotaExceeded)
+    }
+
+    /// List every failed rename, for the end of a run.
+    pub fn render_failures(&self) -> String {
+        let mut text = format!("{} renames failed:\n", self.failures.len());
+        for (path, e) in &self.failures {
+            text.push_str(&format!("  {}: {}\n", path.display(), e));
+        }
+        text.push_str("by subtree:\n");
+        for (name, counts) in self.subtrees.iter().filter(|&(_, counts)| counts.failed > 0) {
+            text.push_str(&format!("  {}: {} failed, {} renamed\n",
+                                   name, counts.failed, counts.renamed));
+        }
+        if self.stopped && self.quota_exceeded() {
+            text.push_str("stopped as a disk quota was exceeded; rerun on what was left \
+                           unprocessed once it is raised\n");
+        } else if self.stopped && self.too_many_errors() {
+            text.push_str(&format!("stopped after more than {} failures; check the options \
+                                    and that the filesystem is healthy before rerunning\n",
+                                   self.max_errors.unwrap_or_default()));
+        } else if self.stopped {
+            text.push_str("stopped after the first failure; everything after it was left \
+                           alone\n");
+        }
+        if !self.unprocessed.is_empty() {
+            let mut unprocessed = self.unprocessed.clone();
+            unprocessed.sort();
+            text.push_str(&format!("{} entries left unprocessed:\n", unprocessed.len()));
+            for path in &unprocessed {
+                text.push_str(&format!("  {}\n", path.display()));
+            }
+        }
+        text
+    }
+
+    /// List the groups of likely duplicates, one per line.
+    pub fn render_duplicates(&self) -> String {
+        let mut text = format!("likely duplicates ({}):\n", self.duplicates.len());
+        for paths in &self.duplicates {
+            let paths: Vec<String> = paths.iter()
+                                          .map(|path| portable_path(&self.root, path))
+                                          .collect();
+            text.push_str(&format!("  {}\n", paths.join("  |  ")));
+        }
+        text
+    }
+
+    /// Render the report as compact, column-aligned plain text.
+    pub fn render_text(&self) -> String {
+        let mut text = format!("flatten-filenames report for {}\n\n", self.root.display());
+
+        let totals = [("renamed", self.totals.renamed),
+                      ("deleted", self.deletions.len()),
+                      ("skipped", self.totals.skipped),
+                      ("failed", self.totals.failed),
+                      ("deferred", self.totals.deferred),
+                      ("warnings", self.warnings.len())];
+        let width = totals.iter().map(|total| total.1.to_string().len()).max().unwrap_or(1);
+        for &(label, count) in &totals {
+            text.push_str(&format!("{:<10}{:>width$}\n", label, count, width = width));
+        }
+
+        if !self.subtrees.is_empty() {
+            let headers = ["subtree", "renamed", "skipped", "failed", "deferred"];
+            let name_width = self.subtrees.keys()
+                                 .map(|name| name.chars().count())
+                                 .chain(Some(headers[0].len()))
+                                 .max()
+                                 .unwrap_or(0);
+            text.push_str(&format!("\n{:<nw$}  {}  {}  {}  {}\n",
+                                   headers[0], headers[1], headers[2], headers[3], headers[4],
+                                   nw = name_width));
+            for (name, counts) in &self.subtrees {
+                text.push_str(&format!("{:<nw$}  {:>7}  {:>7}  {:>6}  {:>8}\n",
+                                       name, counts.renamed, counts.skipped, counts.failed,
+                                       counts.deferred, nw = name_width));
+            }
+        }
+
+        if !self.duplicates.is_empty() {
+            text.push('\n');
+            text.push_str(&self.render_duplicates());
+        }
+
+        if let Some(ref plan_hash) = self.plan_hash {
+            text.push_str(&format!("\nplan hash {}\n", plan_hash));
+        }
+
+        if !self.warnings.is_empty() {
+            text.push_str("\nwarnings:\n");
+            for warning in self.warnings.iter().take(REPORT_WARNINGS) {
+                text.push_str(&format!("  {}\n", warning));
+            }
+            if self.warnings.len() > REPORT_WARNINGS {
+                text.push_str(&format!("  ... and {} more\n",
+                                       self.warnings.len() - REPORT_WARNINGS));
+            }
+        }
+        text
+    }
+
+    /// Render the report as JSON for use on other machines: renamed paths
+    /// are relative to `root` and always separated by '/'.
+    pub fn render_json(&self) -> serde_json::Value {
+        let renames: Vec<serde_json::Value> = self.renames
+            .iter()
+            .map(|(from, to)| {
+                json!({
+                    "from": portable_path(&self.root, from),
+                    "to": portable_path(&self.root, to),
+                })
+            })
+            .collect();
+        let skips: Vec<serde_json::Value> = self.skips
+            .iter()
+            .map(|(path, reason)| {
+                json!({
+                    "path": portable_path(&self.root, path),
+                    "reason": reason.name(),
+                })
+            })
+            .collect();
+        let failures: Vec<serde_json::Value> = self.failures
+            .iter()
+            .map(|(path, e)| {
+                json!({
+                    "path": portable_path(&self.root, path),
+                    "error": e,
+                })
+            })
+            .collect();
+        let deleted: Vec<String> = self.deletions
+                                       .iter()
+                                       .map(|path| portable_path(&self.root, path))
+                                       .collect();
+        let mut unprocessed: Vec<String> = self.unprocessed
+                                               .iter()
+                                               .map(|path| portable_path(&self.root, path))
+                                               .collect();
+        unprocessed.sort();
+        let duplicates: Vec<Vec<String>> = self.duplicates
+            .iter()
+            .map(|paths| paths.iter().map(|path| portable_path(&self.root, path)).collect())
+            .collect();
+        let mut json = json!({
+            "schema_version": REPORT_SCHEMA,
+            "root": self.root.to_string_lossy(),
+            "renamed": self.totals.renamed,
+            "deleted": deleted,
+            "skipped": self.totals.skipped,
+            "failed": self.totals.failed,
+            "deferred": self.totals.deferred,
+            "warnings": self.warnings,
+            "renames": renames,
+            "skips": skips,
+            "failures": failures,
+            "stopped": self.stopped,
+            "unprocessed": unprocessed,
+            "duplicates": duplicates,
+        });
+        if let Some(ref plan_hash) = self.plan_hash {
+            json["plan_hash"] = json!(plan_hash);
+        }
+        json
+    }
+
+    /// Render the report as Prometheus metrics in the text exposition
+    /// format, for node_exporter's textfile collector.
+    pub fn render_prometheus(&self) -> String {
+        let mut skipped: BTreeMap<&str, usize> = BTreeMap::new();
+        for (_, reason) in &self.skips {
+            *skipped.entry(reason.name()).or_default() += 1;
+        }
+        let timestamp = time::SystemTime::now().duration_since(time::UNIX_EPOCH)
+                                               .map(|since| since.as_secs())
+                                               .unwrap_or_default();
+        let reasons: Vec<(&str, f64)> = skipped.into_iter()
+                                               .map(|(reason, count)| (reason, count as f64))
+                                               .collect();
+        let mut text = String::new();
+        push_gauge(&mut text, "renamed", "Entries renamed by the last run.",
+                   &[("", self.totals.renamed as f64)]);
+        push_gauge(&mut text, "skipped", "Entries the last run left alone, by reason.", &reasons);
+        push_gauge(&mut text, "deferred", "Entries the last run deferred.",
+                   &[("", self.totals.deferred as f64)]);
+        push_gauge(&mut text, "warnings", "Warnings during the last run.",
+                   &[("", self.warnings.len() as f64)]);
+        push_gauge(&mut text, "duration_seconds", "How long the last run took.",
+                   &[("", self.started.elapsed().as_secs_f64())]);
+        push_gauge(&mut text, "timestamp_seconds",
+                   "When the last run finished, in seconds since the epoch.",
+                   &[("", timestamp as f64)]);
+        text
+    }
+
+    /// Render the report as a standalone HTML page with a collapsible,
+    /// sortable table of entries per directory and a filter box.
+    pub fn render_html(&self) -> String {
+        let mut directories: BTreeMap<String, Vec<(&str, String, String)>> = BTreeMap::new();
+        for (from, to) in &self.renames {
+            let new_name = to.file_name().map(|name| name.to_string_lossy().into_owned());
+            directories.entry(parent_portable_path(&self.root, from))
+                       .or_default()
+                       .push(("renamed", file_name_lossy(from), new_name.unwrap_or_default()));
+        }
+        for (path, reason) in &self.skips {
+            directories.entry(parent_portable_path(&self.root, path))
+                       .or_default()
+                       .push(("skipped", file_name_lossy(path), reason.name().to_string()));
+        }
+
+        let title = format!("flatten-filenames report for {}", self.root.display());
+        let mut html = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                                <title>{}</title>\n<style>{}</style>\n</head>\n<body>\n\
+                                <h1>{}</h1>\n<p>{} renamed, {} skipped, {} deferred, \
+                                {} warnings</p>\n\
+                                <input id=\"filter\" placeholder=\"Filter\">\n",
+                               escape_html(&title), HTML_STYLE, escape_html(&title),
+                               self.totals.renamed, self.totals.skipped, self.totals.deferred,
+                               self.warnings.len());
+        for (directory, entries) in &directories {
+            let name = if directory.is_empty() { "." } else { directory };
+            html.push_str(&format!("<details open>\n<summary>{} ({})</summary>\n<table>\n\
+                                    <tr><th>entry</th><th>outcome</th>\
+                                    <th>new name or reason</th></tr>\n",
+                                   escape_html(name), entries.len()));
+            for &(outcome, ref entry, ref detail) in entries {
+                html.push_str(&format!("<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                                       escape_html(entry), outcome, escape_html(detail)));
+            }
+            html.push_str("</table>\n</details>\n");
+        }
+        if !self.warnings.is_empty() {
+            html.push_str("<h2>warnings</h2>\n<ul>\n");
+            for warning in &self.warnings {
+                html.push_str(&format!("<li>{}</li>\n", escape_html(warning)));
+            }
+            html.push_str("</ul>\n");
+        }
+        html.push_str(&format!("<script>{}</script>\n</body>\n</html>\n", HTML_SCRIPT));
+        html
+    }
+}
+
+/// Styling for HTML reports.
+const HTML_STYLE: &str = "body{font-family:sans-serif}table{border-collapse:collapse}\
+                          th,td{border:1px solid #ccc;padding:2px 6px;text-align:left}\
+                          th{cursor:pointer;background:#eee}";
+
+/// Sorting by clicking a column header and filtering rows by the text of
+/// the filter box, for HTML reports.
+const HTML_SCRIPT: &str = "\
+document.querySelectorAll('th').forEach(function (th) {
+  th.addEventListener('click', function () {
+    var table = th.closest('table');
+    var column = Array.prototype.indexOf.call(th.parentNode.children, th);
+    var rows = Array.prototype.slice.call(table.rows, 1);
+    var ascending = table.dataset.sorted !== String(column);
+    rows.sort(function (a, b) {
+      var order = a.cells[column].textContent.localeCompare(b.cells[column].textContent);
+      return ascending ? order : -order;
+    });
+    table.dataset.sorted = ascending ? String(column) : '';
+    rows.forEach(function (row) { table.appendChild(row); });
+  });
+});
+document.getElementById('filter').addEventListener('input', function (event) {
+  var text = event.target.value.toLowerCase();
+  document.querySelectorAll('tr').forEach(function (row) {
+    if (row.cells[0].tagName === 'TD') {
+      row.hidden = row.textContent.toLowerCase().indexOf(text) === -1;
+    }
+  });
+});
+";
+
+/// Escape `text` for inclusion in HTML.
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Header row of the CSV manifest.
+pub const MANIFEST_HEADER: &str = "original_path,new_path,timestamp,status,schema_version";
+
+/// Header row of manifests written before `schema_version` was recorded,
+/// which are read as the first version.
+const UNVERSIONED_MANIFEST_HEADER: &str = "original_path,new_path,timestamp,status";
+
+/// Quote `text` for use as a CSV field if it needs it.
+pub fn csv_field<'a>(text: &'a str) -> Cow<'a, str> {
+    if text.contains(&[',', '"', '\n', '\r'][..]) {
+        Cow::Owned(format!("\"{}\"", text.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// `when` in UTC as an RFC 3339 timestamp, to the second.
+pub fn utc_timestamp(when: time::SystemTime) -> String {
+    let seconds = when.duration_since(time::UNIX_EPOCH).map(|since| since.as_secs())
+                                                      .unwrap_or_default();
+    let (days, seconds) = (seconds / 86_400, seconds % 86_400);
+    // Civil date from days since the epoch, counting years from March so
+    // leap days fall at the end.
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 -
+                       day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day, seconds / 3_600, seconds / 60 % 60, seconds % 60)
+}
+
+/// The filename of `path`, with anything not UTF-8 replaced.
+fn file_name_lossy(path: &path::Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// `portable_path()` of the directory holding `path`.
+fn parent_portable_path(root: &path::Path, path: &path::Path) -> String {
+    portable_path(root, path.parent().unwrap_or(root))
+}
+
+/// Name of the journal written into the directory being flattened when
+/// no other path is given.
+pub const DEFAULT_JOURNAL: &str = ".flatten_undo.json";
+
+/// Journal line recording that `from` was renamed to `to`.
+pub fn journal_entry(from: &path::Path, to: &path::Path) -> serde_json::Value {
+    json!({
+        "schema_version": JOURNAL_SCHEMA,
+        "from": from.to_string_lossy(),
+        "to": to.to_string_lossy(),
+    })
+}
+
+/// Journal line recording that the file at `path`, `size` bytes long,
+/// was deleted as junk.
+pub fn deletion_entry(path: &path::Path, size: u64) -> serde_json::Value {
+    json!({"schema_version": JOURNAL_SCHEMA, "deleted": path.to_string_lossy(), "size": size})
+}
+
+/// Something a run did, as recorded in its journal.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JournalEntry {
+    /// The entry at the first path was renamed to the second.
+    Rename(path::PathBuf, path::PathBuf),
+    /// The file at the path, this many bytes long, was deleted as junk.
+    Deletion(path::PathBuf, u64),
+}
+
+impl JournalEntry {
+    /// The paths the entry is about: the old and new path of a rename, or
+    /// the path of a deleted file.
+    pub fn paths(&self) -> Vec<&path::Path> {
+        match *self {
+            JournalEntry::Rename(ref from, ref to) => vec![from, to],
+            JournalEntry::Deletion(ref deleted, _) => vec![deleted],
+        }
+    }
+}
+
+/// Which of the entries in a journal `undo()` reverses, and how.
+#[derive(Default)]
+pub struct UndoOptions {
+    /// Only undo entries with a path matching one of these patterns, per
+    /// `glob_matches()`, if any are given. `*` also matches '/', so
+    /// `*/photos/*` matches everything under any `photos` directory.
+    pub include: Vec<String>,
+    /// Leave alone entries with a path matching one of these patterns.
+    pub exclude: Vec<String>,
+    /// Only undo entries with a path that is one of these or inside one of
+    /// them, if any are given.
+    pub paths: Vec<path::PathBuf>,
+    /// Print each rename as `new -> old` instead of making it.
+    pub dry_run: bool,
+    /// What happens when an old name has since been taken.
+    pub on_collision: CollisionPolicy,
+    /// Decides what happens when an old name has since been taken instead
+    /// of `on_collision`, if given.
+    pub resolver: Option<Box<dyn ConflictResolver>>,
+}
+
+impl UndoOptions {
+    /// Whatever decides what happens when an old name has since been
+    /// taken: `resolver` if given, otherwise `on_collision`.
+    pub fn resolver(&self) -> &dyn ConflictResolver {
+        match self.resolver {
+            Some(ref resolver) => resolver.as_ref(),
+            None => &self.on_collision,
+        }
+    }
+
+    /// Check if `entry` is one `undo()` should reverse, going by any of its
+    /// `JournalEntry::paths()`.
+    pub fn selects(&self, entry: &JournalEntry) -> bool {
+        let paths = entry.paths();
+        let matches = |patterns: &[String]| {
+            patterns.iter().any(|pattern| {
+                paths.iter().any(|path| glob_matches(pattern, &path.to_string_lossy()))
+            })
+        };
+        (self.include.is_empty() || matches(&self.include)) && !matches(&self.exclude) &&
+        (self.paths.is_empty() ||
+         self.paths.iter().any(|under| paths.iter().any(|path| path.starts_with(under))))
+    }
+}
+
+/// Read everything recorded in the journal, plan, or `--manifest` at
+/// `path`, oldest first.
+///
+/// Entries without a `schema_version` were written before versions were
+/// recorded, and are read as the first version.
+pub fn read_journal_entries(path: &path::Path) -> Result<Vec<JournalEntry>, FlattenError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| FlattenError::Io(path.to_path_buf(), e))?;
+    let header = contents.lines().next().unwrap_or_default();
+    if header == MANIFEST_HEADER || header == UNVERSIONED_MANIFEST_HEADER {
+        return read_manifest(path, &contents);
+    }
+    let mut entries = Vec::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let entry: serde_json::Value = serde_json::from_str(line).unwrap_or_default();
+        match entry.get("schema_version") {
+            Some(version) if version.as_u64() != Some(JOURNAL_SCHEMA) => {
+                return Err(FlattenError::UnsupportedSchema(path.to_path_buf(),
+                                                           version.to_string(), JOURNAL_SCHEMA));
+            }
+            _ => {}
+        }
+        match (entry["from"].as_str(), entry["to"].as_str(), entry["deleted"].as_str()) {
+            (Some(from), Some(to), _) => {
+                entries.push(JournalEntry::Rename(path::PathBuf::from(from),
+                                                  path::PathBuf::from(to)))
+            }
+            (_, _, Some(deleted)) => {
+                let size = entry["size"].as_u64().unwrap_or_default();
+                entries.push(JournalEntry::Deletion(path::PathBuf::from(deleted), size))
+            }
+            _ => return Err(FlattenError::InvalidJournal(path.to_path_buf(), line.to_string())),
+        }
+    }
+    Ok(entries)
+}
+
+/// Read the renames recorded in the CSV manifest at `path`, whose
+/// `contents` are already read; deleted and failed files are left out as
+/// there is nothing to replay for them.
+fn read_manifest(path: &path::Path, contents: &str) -> Result<Vec<JournalEntry>, FlattenError> {
+    let versioned = contents.lines().next() == Some(MANIFEST_HEADER);
+    let mut entries = Vec::new();
+    for row in parse_csv(contents).into_iter().skip(1) {
+        let fields = match row.split_last() {
+            Some((version, fields)) if versioned && row.len() == 5 => {
+                if version.parse() != Ok(MANIFEST_SCHEMA) {
+                    return Err(FlattenError::UnsupportedSchema(path.to_path_buf(),
+                                                               version.clone(),
+                                                               MANIFEST_SCHEMA));
+                }
+                fields
+            }
+            _ if versioned => {
+                return Err(FlattenError::InvalidJournal(path.to_path_buf(), row.join(",")));
+            }
+            _ => row.as_slice(),
+        };
+        match fields {
+            [from, to, _, status] if status == "renamed" && !to.is_empty() => {
+                entries.push(JournalEntry::Rename(path::PathBuf::from(from),
+                                                  path::PathBuf::from(to)))
+            }
+            [_, _, _, status] if status == "deleted" || status == "failed" => {}
+            _ => return Err(FlattenError::InvalidJournal(path.to_path_buf(), row.join(","))),
+        }
+    }
+    Ok(entries)
+}
+
+/// Split CSV `contents` into rows of fields, undoing `csv_field()`'s
+/// quoting; quoted fields may span lines.
+pub fn parse_csv(contents: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = contents.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => row.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
+}
+
+/// Read the renames recorded in the journal, plan, or `--manifest` at
+/// `path`, oldest first, leaving out any deletions.
+pub fn read_journal(path: &path::Path)
+                    -> Result<Vec<(path::PathBuf, path::PathBuf)>, FlattenError> {
+    let mut renames = Vec::new();
+    for entry in read_journal_entries(path)? {
+        if let JournalEntry::Rename(from, to) = entry {
+            renames.push((from, to));
+        }
+    }
+    Ok(renames)
+}
+
+/// Undo the renames and deletions recorded in the journal at `path` that
+/// `options` selects, newest first.
+///
+/// Returns how many were undone along with why any others could not be.
+/// A rename is only undone when its new name still exists and the entry
+/// wasn't modified after the journal was last written; when its old name
+/// has since been taken, `options.resolver()` decides. Of the deleted
+/// files only empty ones can be recreated. In a dry run nothing is
+/// touched, and what would be undone is printed instead.
+pub fn undo(path: &path::Path, options: &UndoOptions)
+            -> Result<(usize, Vec<String>), FlattenError> {
+    let journaled = fs::metadata(path).and_then(|metadata| metadata.modified())
+                                      .map_err(|e| FlattenError::Io(path.to_path_buf(), e))?;
+    let mut undone = 0;
+    let mut problems = Vec::new();
+    let entries = read_journal_entries(path)?;
+    for entry in entries.iter().rev().filter(|entry| options.selects(entry)) {
+        let undone_entry = match *entry {
+            JournalEntry::Rename(ref from, ref to) => {
+                undo_rename(from, to, journaled, options)
+            }
+            JournalEntry::Deletion(ref deleted, size) => {
+                if size > 0 {
+                    Err(format!("{:?} was deleted with {} bytes in it, so it can't be \
+                                 recreated", deleted, size))
+                } else if fs::symlink_metadata(deleted).is_ok() {
+                    Err(format!("{:?} already exists, so it was left alone", deleted))
+                } else if options.dry_run {
+                    println!("(deleted) -> {}", deleted.display());
+                    Ok(())
+                } else {
+                    recreate_empty(deleted).map_err(|e| format!("{:?}: {}", deleted, e))
+                }
+            }
+        };
+        match undone_entry {
+            Ok(()) => undone += 1,
+            Err(problem) => problems.push(problem),
+        }
+    }
+    Ok((undone, problems))
+}
+
+/// Rename the entry at `to` back to `from`, unless it is gone, was
+/// modified after `journaled`, or `options.resolver()` leaves it alone
+/// because `from` has since been taken.
+fn undo_rename(from: &path::Path, to: &path::Path, journaled: time::SystemTime,
+               options: &UndoOptions) -> Result<(), String> {
+    let target = match drift(to, from) {
+        Some(Drift::Gone) => {
+            return Err(format!("{:?} is gone, so {:?} was left alone", to, from));
+        }
+        _ if modified_since(to, journaled) => {
+            return Err(format!("{:?} was modified after the run, so it was left alone", to));
+        }
+        Some(Drift::Taken) => {
+            let filename = from.file_name().unwrap_or_default().to_string_lossy();
+            match options.resolver().resolve(to, &filename, from) {
+                Some(filename) => from.with_file_name(filename),
+                None => {
+                    return Err(format!("{:?} already exists, so {:?} was left alone", from,
+                                       to));
+                }
+            }
+        }
+        None => from.to_path_buf(),
+    };
+    if options.dry_run {
+        println!("{} -> {}", to.display(), target.display());
+        Ok(())
+    } else {
+        rename_back(&target, to).map_err(|e| format!("{:?}: {}", to, e))
+    }
+}
+
+/// Check if the entry at `path` was modified after `time`.
+fn modified_since(path: &path::Path, time: time::SystemTime) -> bool {
+    fs::symlink_metadata(path).and_then(|metadata| metadata.modified())
+                              .is_ok_and(|modified| modified > time)
+}
+
+/// Recreate the empty file at `path` that was deleted as junk.
+fn recreate_empty(path: &path::Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::OpenOptions::new().write(true).create_new(true).open(path).map(|_| ())
+}
+
+/// Rename `to` back to `from`, recreating the directory `from` was in if
+/// merging directories removed it.
+fn rename_back(from: &path::Path, to: &path::Path) -> io::Result<()> {
+    if let Some(parent) = from.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(to, from)
+}
+
+/// Write the renames worked out by `plan()` to `out` in the journal's
+/// format, one JSON line per rename, for `apply()` to make later.
+pub fn write_plan(renames: &[(path::PathBuf, String)], out: &mut dyn Write)
+                  -> io::Result<()> {
+    for (from, name) in renames {
+        writeln!(out, "{}", journal_entry(from, &from.with_file_name(name)))?;
+    }
+    Ok(())
+}
+
+/// Hash the contents of the file at `path`.
+fn content_hash(path: &path::Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Hash the planned `renames` (old and new paths) together with the state
+/// of the filesystem they depend on: the size and modification time of
+/// each old path and whether each new path is taken.
+///
+/// The hash stays the same for as long as neither the plan nor those parts
+/// of the tree change, so a reviewed plan can be checked before it is
+/// applied.
+pub fn plan_hash(renames: &[(path::PathBuf, path::PathBuf)]) -> String {
+    let mut hasher = Sha256::new();
+    for (from, to) in renames {
+        let state = match fs::symlink_metadata(from) {
+            Ok(metadata) => {
+                let modified = metadata.modified()
+                                       .ok()
+                                       .and_then(|modified| {
+                                           modified.duration_since(time::UNIX_EPOCH).ok()
+                                       })
+                                       .map(|since| since.as_nanos())
+                                       .unwrap_or_default();
+                format!("{} {}", metadata.len(), modified)
+            }
+            Err(_) => "missing".to_string(),
+        };
+        let taken = fs::symlink_metadata(to).is_ok();
+        hasher.update(format!("{}\0{}\0{}\0{}\n", from.to_string_lossy(), to.to_string_lossy(),
+                              state, taken));
+    }
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// What `apply()` did with a plan.
+#[derive(Debug, Default, PartialEq)]
+pub struct Applied {
+    /// Number of renames made.
+    pub renamed: usize,
+    /// Renames left alone as the tree no longer matches the plan: the old
+    /// name is gone or the new one is taken.
+    pub drifted: Vec<String>,
+    /// Renames that failed.
+    pub failed: Vec<String>,
+    /// Whether the rest of the plan was abandoned after too much drift.
+    pub stopped: bool,
+}
+
+/// How the tree no longer matches a rename recorded in a plan or journal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Drift {
+    /// Nothing goes by the old name any more.
+    Gone,
+    /// Something already goes by the new name.
+    Taken,
+}
+
+/// Check if renaming `from` to `to` still matches the tree: `from` must
+/// exist and `to` must be free.
+fn drift(from: &path::Path, to: &path::Path) -> Option<Drift> {
+    if fs::symlink_metadata(from).is_err() {
+        Some(Drift::Gone)
+    } else if fs::symlink_metadata(to).is_ok() {
+        Some(Drift::Taken)
+    } else {
+        None
+    }
+}
+
+/// Move `path` from under the directory `old` to under `new`, as for
+/// applying a plan to a copy of the tree it was made for; paths outside
+/// `old` are left as they are.
+pub fn rebase_path(path: &path::Path, old: &path::Path, new: &path::Path) -> path::PathBuf {
+    match path.strip_prefix(old) {
+        Ok(relative) => new.join(relative),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// Make the renames listed in the plan or `--manifest` at `path`, oldest
+/// first, recording each one made in `journal` if given. With `rebase`,
+/// the paths in the plan are moved from under its first directory to
+/// under its second one with `rebase_path()` first.
+///
+/// Each rename is checked just before it is made, and only made when its
+/// old name still exists and its new name is free. Once more than
+/// `max_drift` renames have been left alone for not matching the tree, the
+/// rest of the plan is abandoned.
+pub fn apply(path: &path::Path, mut journal: Option<&mut dyn Write>, max_drift: Option<usize>,
+             rebase: Option<(&path::Path, &path::Path)>)
+             -> Result<Applied, FlattenError> {
+    let mut applied = Applied::default();
+    for (from, to) in read_journal(path)? {
+        let (from, to) = match rebase {
+            Some((old, new)) => (rebase_path(&from, old, new), rebase_path(&to, old, new)),
+            None => (from, to),
+        };
+        let drifted = drift(&from, &to);
+        if drifted == Some(Drift::Gone) {
+            applied.drifted.push(format!("{:?} is gone", from));
+        } else if drifted == Some(Drift::Taken) {
+            applied.drifted.push(format!("{:?} already exists, so {:?} was left alone", to, from));
+        } else if let Err(e) = fs::rename(&from, &to) {
+            applied.failed.push(format!("{:?}: {}", from, e));
+        } else {
+            applied.renamed += 1;
+            if let Some(ref mut journal) = journal {
+                writeln!(journal, "{}", journal_entry(&from, &to))
+                    .map_err(FlattenError::Journal)?;
+            }
+        }
+        if max_drift.is_some_and(|max_drift| applied.drifted.len() > max_drift) {
+            applied.stopped = true;
+            break;
+        }
+    }
+    Ok(applied)
+}
+
+/// Express `path` relative to `root` with '/' separators regardless of
+/// platform, so it means the same thing on Windows and Unix.
+pub fn portable_path(root: &path::Path, path: &path::Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let components: Vec<String> = relative.components()
+                                          .map(|component| {
+                                              component.as_os_str().to_string_lossy().into_owned()
+                                          })
+                                          .collect();
+    components.join("/")
+}
+
+/// Rough figures for a run, gathered without computing target names.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Estimate {
+    /// Directories that would be traversed, including the root.
+    pub directories: usize,
+    /// Entries that would be considered for renaming.
+    pub files: usize,
+    /// Entries that would be renamed.
+    pub renames: usize,
+    /// Total number of characters the renames would add to names.
+    pub prefix_growth: usize,
+}
+
+impl Estimate {
+    /// Render the estimate as plain text.
+    pub fn render_text(&self) -> String {
+        let average = if self.renames == 0 {
+            0.0
+        } else {
+            self.prefix_growth as f64 / self.renames as f64
+        };
+        format!("directories    {}\nfiles          {}\nrenames        {}\n\
+                 prefix growth  {} characters ({:.1} per rename)\n",
+                self.directories, self.files, self.renames, self.prefix_growth, average)
+    }
+}
+
+/// Prints a message to `std::io::stderr`.
+///
+/// Failing to write is ignored as there is nowhere left to report it.
+fn println_stderr(message: String) {
+    let _ = writeln!(&mut std::io::stderr(), "{}", message);
+}
+
+/// Extract the leading character of a path, or '\0' if it has no
+/// filename.
+pub fn leading_char(path: &path::Path) -> char {
+    path.file_name()
+        .and_then(|filename| filename.to_string_lossy().chars().next())
+        .unwrap_or('\0')
+}
+
+/// Get the filename of `path` as a `str`.
+pub fn filename_str(path: &path::Path) -> Result<&str, String> {
+    match path.file_name() {
+        Some(filename) => filename.to_str()
+                                  .ok_or_else(|| format!("{:?}: filename is not UTF-8", path)),
+        None => Err(format!("{:?}: path lacks a filename", path)),
+    }
+}
+
+/// Get the filename of `path` as text, going by `options.non_utf8` if it
+/// isn't UTF-8.
+fn filename_text<'a>(path: &'a path::Path, options: &FlattenOptions)
+                     -> Result<Cow<'a, str>, String> {
+    match (filename_str(path), path.file_name()) {
+        (Ok(filename), _) => Ok(Cow::Borrowed(filename)),
+        (Err(_), Some(filename)) if options.non_utf8 == NonUtf8::Lossy => {
+            Ok(filename.to_string_lossy())
+        }
+        (Err(message), _) => Err(message),
+    }
+}
+
+/// Check if a `entry` is a directory that doesn't have any special
+/// leading characters.
+///
+/// The characters that signal not to traverse into a directory are
+/// '.' and '_'.
+pub fn should_traverse(entry: &fs::DirEntry) -> bool {
+    // Usually known from reading the directory, sparing a `stat()` per entry.
+    let file_type = entry.file_type();
+    if file_type.is_err() {
+        println_stderr(format!("path missing metadata: {:?}", entry.path()));
+        return false;
+    }
+
+    if file_type.map(|file_type| file_type.is_dir()).unwrap_or(false) {
+        let path = entry.path();
+        let leading_char = leading_char(&path);
+        leading_char != '.' && leading_char != '_'
+    } else {
+        false
+    }
+}
+
+/// Check if `path` was last modified at least `settle` ago.
+///
+/// A directory still being filled keeps having its modification time
+/// bumped, so anything newer than `settle` is left for a later run.
+pub fn is_settled(path: &path::Path, settle: time::Duration) -> bool {
+    let modified = fs::metadata(path).and_then(|metadata| metadata.modified());
+    match modified {
+        Ok(modified) => match modified.elapsed() {
+            Ok(age) => age >= settle,
+            // Modified "in the future", so certainly not settled.
+            Err(_) => false,
+        },
+        Err(_) => {
+            println_stderr(format!("path missing modification time: {:?}", path));
+            false
+        }
+    }
+}
+
+/// Parse a duration such as "90s", "15m", "2h", or "1d".
+///
+/// A bare number is taken as seconds.
+pub fn parse_duration(duration: &str) -> Result<time::Duration, String> {
+    let (number, multiplier) = match duration.chars().last() {
+        Some('s') => (&duration[..duration.len() - 1], 1),
+        Some('m') => (&duration[..duration.len() - 1], 60),
+        Some('h') => (&duration[..duration.len() - 1], 60 * 60),
+        Some('d') => (&duration[..duration.len() - 1], 24 * 60 * 60),
+        _ => (duration, 1),
+    };
+    match number.parse::<u64>().ok().and_then(|number| number.checked_mul(multiplier)) {
+        Some(seconds) => Ok(time::Duration::from_secs(seconds)),
+        None => Err(format!("invalid duration: {:?}", duration)),
+    }
+}
+
+/// Check if `path` looks like an in-progress download based on its
+/// extension.
+pub fn is_partial_download(path: &path::Path) -> bool {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => {
+            let extension = extension.to_lowercase();
+            PARTIAL_EXTENSIONS.iter().any(|partial| *partial == extension)
+        }
+        None => false,
+    }
+}
+
+/// Find the files directly in `directory` whose size changes over `delay`.
+///
+/// The whole directory is stat'ed before and after a single wait so that
+/// the cost of the delay is paid once per directory instead of per file.
+pub fn growing_files(directory: &path::Path, delay: time::Duration)
+                     -> HashSet<path::PathBuf> {
+    let sizes = |directory: &path::Path| -> Vec<(path::PathBuf, u64)> {
+        let read_dir = match directory.read_dir() {
+            Ok(read_dir) => read_dir,
+            Err(_) => return Vec::new(),
+        };
+        read_dir.filter_map(|entry| entry.ok())
+                .filter_map(|entry| match entry.metadata() {
+                    Ok(ref metadata) if metadata.is_file() => {
+                        Some((entry.path(), metadata.len()))
+                    }
+                    _ => None,
+                })
+                .collect()
+    };
+
+    let before = sizes(directory);
+    if before.is_empty() {
+        return HashSet::new();
+    }
+    std::thread::sleep(delay);
+    let after: HashSet<_> = sizes(directory).into_iter().collect();
+    before.into_iter()
+          .filter(|file| !after.contains(file))
+          .map(|(path, _)| path)
+          .collect()
+}
+
+/// Check if the file at `path` is still growing according to
+/// `options.defer_growing`.
+fn is_growing(path: &path::Path, options: &FlattenOptions) -> bool {
+    match (options.defer_growing, path.parent()) {
+        (Some(delay), Some(parent)) => growing_files(parent, delay).contains(path),
+        _ => false,
+    }
+}
+
+/// Rename a file with a prefix.
+///
+/// If the file starts with '.' then skip the renaming.
+pub fn rename(path: &path::Path, prefix: &str) -> Result<(), FlattenError> {
+    if leading_char(path) == '.' {
+        return Ok(());
+    }
+
+    let filename = filename_str(path).map_err(|_| FlattenError::NonUtf8(path.to_path_buf()))?;
+    rename_to(path, &new_filename(prefix, filename, &FlattenOptions::default()))
+        .map_err(|e| FlattenError::Io(path.to_path_buf(), e))
+}
+
+/// Rename a file to `filename` within the same directory.
+pub fn rename_to(path: &path::Path, filename: &str) -> io::Result<()> {
+    fs::rename(path, path.with_file_name(filename))
+}
+
+/// Move the entry at `from` to `to`, through `git mv` when `options.git`
+/// and git tracks it.
+pub fn move_entry(from: &path::Path, to: &path::Path, options: &FlattenOptions)
+                  -> io::Result<()> {
+    note_operation(format!("renaming {:?} to {:?}", from, to));
+    if options.git && is_tracked(from)? {
+        let output = git_command(from).arg("mv").arg("--").arg(from).arg(to).output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!("git mv: {}",
+                                                String::from_utf8_lossy(&output.stderr).trim())));
+        }
+        Ok(())
+    } else {
+        fs::rename(from, to)
+    }
+}
+
+/// Check if `directory` is inside a git work tree.
+pub fn in_git_work_tree(directory: &path::Path) -> bool {
+    process::Command::new("git")
+        .arg("-C")
+        .arg(directory)
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .output()
+        .is_ok_and(|output| output.status.success() && output.stdout.starts_with(b"true"))
+}
+
+/// Check if git tracks the entry at `path`, or anything in it.
+fn is_tracked(path: &path::Path) -> io::Result<bool> {
+    let status = git_command(path).args(["ls-files", "--error-unmatch", "--"])
+                                  .arg(path)
+                                  .stdout(process::Stdio::null())
+                                  .stderr(process::Stdio::null())
+                                  .status()?;
+    Ok(status.success())
+}
+
+/// A `git` command run from the directory holding `path`.
+fn git_command(path: &path::Path) -> process::Command {
+    let mut command = process::Command::new("git");
+    if let Some(directory) = path.parent() {
+        command.current_dir(directory);
+    }
+    command
+}
+
+/// Create the new name for a file named `filename` that is given `prefix`,
+/// joined by `options.separator()` and put in `options.case`.
+pub fn new_filename(prefix: &str, filename: &str, options: &FlattenOptions) -> String {
+    if prefix.is_empty() {
+        options.put_in_case(filename)
+    } else {
+        options.put_in_case(&(prefix.to_string() + options.separator() + filename))
+    }
+}
+
+/// `new_filename()` with `filename` normalized according to
+/// `options.normalization` and folded according to `options.folding`.
+fn folded_filename(prefix: &str, filename: &str, options: &FlattenOptions) -> String {
+    let filename = options.folding.apply(&options.normalization.apply(filename));
+    new_filename(prefix, &filename, options)
+}
+
+/// A journal file whose `flush()` also syncs what was written to it to
+/// disk.
+pub struct SyncedFile(pub fs::File);
+
+impl Write for SyncedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.sync_data()
+    }
+}
+
+/// Sync the entries of `directory` to disk, so renames in it survive a
+/// power cut.
+#[cfg(unix)]
+fn sync_directory(directory: &path::Path) -> io::Result<()> {
+    fs::File::open(directory)?.sync_all()
+}
+
+/// Sync the entries of `directory` to disk, which isn't possible here.
+#[cfg(not(unix))]
+fn sync_directory(_directory: &path::Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Check if `metadata` belongs to something that can be executed.
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+}
+
+/// Check if `metadata` belongs to something that can be executed.
+#[cfg(not(unix))]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    metadata.is_file()
+}
+
+/// Find the naming schemes provided by `flatten-scheme-*` executables on
+/// `PATH`.
+///
+/// Returns the name of each scheme along with its executable, sorted by
+/// name. When a scheme appears more than once, the first one on `PATH`
+/// wins.
+pub fn discover_schemes() -> Vec<(String, path::PathBuf)> {
+    let mut schemes: Vec<(String, path::PathBuf)> = Vec::new();
+    let paths = match env::var_os("PATH") {
+        Some(paths) => paths,
+        None => return schemes,
+    };
+    for directory in env::split_paths(&paths) {
+        let read_dir = match directory.read_dir() {
+            Ok(read_dir) => read_dir,
+            Err(_) => continue,
+        };
+        for entry in read_dir.filter_map(|entry| entry.ok()) {
+            let filename = entry.file_name();
+            let filename = match filename.to_str() {
+                Some(filename) => filename,
+                None => continue,
+            };
+            if !filename.starts_with(SCHEME_PREFIX) {
+                continue;
+            }
+            let name = filename[SCHEME_PREFIX.len()..].trim_end_matches(".exe");
+            if name.is_empty() || schemes.iter().any(|scheme| scheme.0 == name) {
+                continue;
+            }
+            match fs::metadata(entry.path()) {
+                Ok(ref metadata) if is_executable(metadata) => {
+                    schemes.push((name.to_string(), entry.path()));
+                }
+                _ => (),
+            }
+        }
+    }
+    schemes.sort();
+    schemes
+}
+
+/// Ask the naming scheme executable `scheme` what the file at `path`
+/// should be renamed to when given `prefix`.
+///
+/// The scheme receives a JSON object on stdin and answers with a JSON
+/// object on stdout; see the README for the protocol. `Ok(None)` means the
+/// scheme asked for the file to be left alone.
+pub fn scheme_filename(scheme: &path::Path, path: &path::Path, prefix: &str,
+                       options: &FlattenOptions) -> Result<Option<String>, String> {
+    let filename = filename_text(path, options)?;
+    let filename = filename.as_ref();
+    let request = json!({
+        "protocol": SCHEME_PROTOCOL,
+        "path": path.to_string_lossy(),
+        "filename": filename,
+        "prefix": prefix,
+        "default": folded_filename(prefix, filename, options),
+    });
+
+    let child = process::Command::new(scheme)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => return Err(format!("naming scheme {:?}: {}", scheme, e)),
+    };
+    {
+        let stdin = child.stdin.as_mut().expect("child lacks stdin");
+        if let Err(e) = stdin.write_all(request.to_string().as_bytes()) {
+            // Don't leave the scheme running, or unreaped once it exits.
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("naming scheme {:?}: {}", scheme, e));
+        }
+    }
+    let output = match child.wait_with_output() {
+        Ok(output) => output,
+        Err(e) => return Err(format!("naming scheme {:?}: {}", scheme, e)),
+    };
+    if !output.status.success() {
+        return Err(format!("naming scheme {:?} failed for {:?} ({})",
+                           scheme, path, output.status));
+    }
+
+    let response: serde_json::Value = match serde_json::from_slice(&output.stdout) {
+        Ok(response) => response,
+        Err(e) => return Err(format!("naming scheme {:?} gave invalid JSON: {}", scheme, e)),
+    };
+    if response["skip"] == json!(true) {
+        return Ok(None);
+    }
+    match response["name"].as_str() {
+        Some(name) if is_valid_filename(name) => Ok(Some(name.to_string())),
+        Some(name) => Err(format!("naming scheme {:?} gave an invalid name: {:?}",
+                                  scheme, name)),
+        None => Err(format!("naming scheme {:?} gave no name for {:?}", scheme, path)),
+    }
+}
+
+/// Check if `filename` names a file in a directory rather than a path
+/// leading somewhere else.
+fn is_valid_filename(filename: &str) -> bool {
+    let mut components = path::Path::new(filename).components();
+    matches!((components.next(), components.next()),
+             (Some(path::Component::Normal(_)), None))
+}
+
+/// Create the filename prefix, joining the parts by `options.separator()`
+/// and putting them in `options.case`.
+///
+/// If a new part starts with '-' or '+' then strip it off.
+pub fn new_prefix(old_prefix: &str, tail: &str, options: &FlattenOptions) -> String {
+    let mut postfix = tail;
+    if tail.starts_with('+') || tail.starts_with('-') {
+        postfix = &tail[1..];
+    }
+    if old_prefix.is_empty() {
+        options.put_in_case(postfix)
+    } else {
+        options.put_in_case(&(old_prefix.to_string() + options.separator() + postfix))
+    }
+}
+
+/// Flattens directories according to `FlattenOptions`; the entry point
+/// for programs embedding this crate.
+pub struct Flattener {
+    options: FlattenOptions,
+}
+
+impl Flattener {
+    pub fn new(options: FlattenOptions) -> Flattener {
+        Flattener { options }
+    }
+
+    /// The options every run follows.
+    pub fn options(&self) -> &FlattenOptions {
+        &self.options
+    }
+
+    /// Flatten `directory`, returning what happened.
+    pub fn flatten(&self, directory: &path::Path) -> Result<Report, FlattenError> {
+        let mut report = Report::new(directory);
+        flatten(directory, "", &self.options, &mut report)?;
+        report.finish();
+        Ok(report)
+    }
+
+    /// Work out the renames flattening `directory` would make without
+    /// making them; see `plan()`.
+    pub fn plan(&self, directory: &path::Path)
+                -> Result<(Vec<(path::PathBuf, String)>, Report), FlattenError> {
+        let mut report = Report::new(directory);
+        let renames = plan(directory, &self.options, &mut report)?;
+        Ok((renames, report))
+    }
+
+    /// Explain how `target` would be handled when flattening `root`; see
+    /// `explain()`.
+    pub fn explain(&self, root: &path::Path, target: &path::Path)
+                   -> Result<Vec<String>, String> {
+        explain(root, target, &self.options)
+    }
+
+    /// Find the collisions flattening `directory` would cause; see
+    /// `preflight()`.
+    pub fn preflight(&self, directory: &path::Path) -> Result<Vec<Collision>, FlattenError> {
+        preflight(directory, &self.options)
+    }
+
+    /// Estimate what flattening `directory` would do; see `estimate()`.
+    pub fn estimate(&self, directory: &path::Path) -> Estimate {
+        let mut totals = Estimate::default();
+        estimate(directory, 0, &self.options, &mut totals);
+        totals
+    }
+}
+
+/// Words after which a Roman numeral is taken as a number even when it is
+/// a single letter or not in capitals, as with `Part I`.
+const NUMBERED_WORDS: [&str; 10] = ["act", "book", "chapter", "disc", "episode", "part",
+                                    "season", "series", "vol", "volume"];
+
+/// The value of `word` read as a Roman numeral, if it is one.
+///
+/// Only the usual spelling counts, so neither `IIII` nor `VX` are numerals.
+fn roman_value(word: &str) -> Option<usize> {
+    const NUMERALS: [(&str, usize); 13] = [("M", 1000), ("CM", 900), ("D", 500), ("CD", 400),
+                                           ("C", 100), ("XC", 90), ("L", 50), ("XL", 40),
+                                           ("X", 10), ("IX", 9), ("V", 5), ("IV", 4), ("I", 1)];
+    let upper = word.to_uppercase();
+    let mut rest = upper.as_str();
+    let mut value = 0;
+    for &(numeral, numeral_value) in &NUMERALS {
+        while rest.starts_with(numeral) {
+            rest = &rest[numeral.len()..];
+            value += numeral_value;
+        }
+    }
+    if !rest.is_empty() || value == 0 {
+        return None;
+    }
+    let mut spelling = String::new();
+    let mut remaining = value;
+    for &(numeral, numeral_value) in &NUMERALS {
+        while remaining >= numeral_value {
+            spelling.push_str(numeral);
+            remaining -= numeral_value;
+        }
+    }
+    if spelling == upper {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// Replace Roman numerals and ordinals in `name` by plain numbers, e.g.
+/// `Part II` becomes `Part 2` and `3rd Season` becomes `03 Season`.
+///
+/// Roman numerals are only replaced when following a word like `Part` or
+/// `Volume`, or when written in capitals with at least two of `I`, `V`, and
+/// `X`, so that words like `I`, `Mix`, or `CD` are left alone.
+pub fn normalize_numerals(name: &str) -> String {
+    let mut previous = "";
+    let mut words = Vec::new();
+    for word in name.split(' ') {
+        let digits = word.len() - word.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let suffix = word[digits..].to_lowercase();
+        let ordinal = digits > 0 && ["st", "nd", "rd", "th"].contains(&suffix.as_str());
+        let numbered = NUMBERED_WORDS.contains(&previous.to_lowercase().as_str());
+        let capitals = word.len() > 1 && word.chars().all(|c| "IVX".contains(c));
+        words.push(match roman_value(word) {
+            _ if ordinal => format!("{:0>2}", &word[..digits]),
+            Some(value) if numbered || capitals => value.to_string(),
+            _ => word.to_string(),
+        });
+        previous = word;
+    }
+    words.join(" ")
+}
+
+/// Prepare the directory name `tail` for becoming part of a prefix
+/// according to `options`.
+///
+/// A leading '+' or '-' is kept for `new_prefix()` to strip.
+pub fn prefix_segment(tail: &str, options: &FlattenOptions) -> String {
+    let folded = options.folding.apply(&options.normalization.apply(tail));
+    let normalized;
+    let tail = if options.normalize_numerals {
+        normalized = normalize_numerals(&folded);
+        normalized.as_str()
+    } else {
+        folded.as_str()
+    };
+    let segment = numbered_segment(tail, options.numbering);
+    match options.opaque {
+        Some(ref opaque) => {
+            let (marker, name) = split_marker(&segment);
+            format!("{}{}", marker, opaque.token(name))
+        }
+        None => segment,
+    }
+}
+
+/// Split a leading '+' or '-' off of the directory name `tail`.
+fn split_marker(tail: &str) -> (&str, &str) {
+    if tail.starts_with('+') || tail.starts_with('-') {
+        tail.split_at(1)
+    } else {
+        ("", tail)
+    }
+}
+
+/// Apply `numbering` to the directory name `tail`.
+fn numbered_segment(tail: &str, numbering: Numbering) -> String {
+    let (marker, name) = split_marker(tail);
+    let digits = name.len() - name.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if digits == 0 {
+        return tail.to_string();
+    }
+    let (number, rest) = name.split_at(digits);
+    match numbering {
+        Numbering::Keep => tail.to_string(),
+        Numbering::Strip => {
+            let title = rest.trim_start_matches([' ', '.', '-', '_']);
+            if title.is_empty() || title.len() == rest.len() {
+                tail.to_string()
+            } else {
+                format!("{}{}", marker, title)
+            }
+        }
+        Numbering::Pad(width) => format!("{}{:0>width$}{}", marker, number, rest, width = width),
+    }
+}
+
+/// `new_prefix()` for the directory name `tail` prepared by
+/// `prefix_segment()`, joined by `options.separator()`.
+fn extend_prefix(old_prefix: &str, tail: &str, options: &FlattenOptions) -> String {
+    new_prefix(old_prefix, &prefix_segment(tail, options), options)
+}
+
+/// "Flattens" `directory by prepending `prefix` plus the directories
+/// name.
+///
+/// Certain considerations are taken into account based on the leading
+/// character of the directory's name.
+///
+/// Subdirectories that have not settled according to `options.settle`
+/// are skipped entirely. Directories are processed in `options.order`.
+/// What happens to each entry is recorded in `report`.
+///
+/// An empty `prev_prefix` makes `directory` the root of the run, with its
+/// prefix decided by `options.anchor`.
+pub fn flatten(directory: &path::Path, prev_prefix: &str, options: &FlattenOptions,
+               report: &mut Report) -> Result<(), FlattenError> {
+    let prefix = if prev_prefix.is_empty() {
+        anchored_prefix(directory, options)
+    } else {
+        directory_prefix(directory, prev_prefix, options)
+    };
+    let prefix = prefix.map_err(FlattenError::Prefix)?;
+    check_readable(directory)?;
+    let mut listings = if options.jobs > 1 {
+        read_ahead(directory, options)
+    } else {
+        Listings::new()
+    };
+    let mut queue = Vec::new();
+    match options.order {
+        Order::DepthFirst => flatten_depth_first(directory, &prefix, options, &mut listings,
+                                                 &mut queue, report),
+        Order::BreadthFirst => flatten_breadth_first(directory, prefix, options, &mut listings,
+                                                     &mut queue, report),
+    }
+    rename_directories(&queue, options, report);
+    // Before merging moves files out from under the sizes noted.
+    if options.find_duplicates && report.checkpoint() {
+        report.find_duplicates();
+    }
+    if options.merge_directories && report.checkpoint() {
+        merge_directories(directory, options, report);
+    }
+    Ok(())
+}
+
+/// Check that `directory` can be read, so a run doesn't start on what it
+/// can't finish.
+fn check_readable(directory: &path::Path) -> Result<(), FlattenError> {
+    directory.read_dir()
+             .map(|_| ())
+             .map_err(|e| FlattenError::Io(directory.to_path_buf(), e))
+}
+
+/// Create the prefix for the contents of `directory`.
+fn directory_prefix(directory: &path::Path, prev_prefix: &str, options: &FlattenOptions)
+                    -> Result<String, String> {
+    filename_text(directory, options).map(|path_tail| {
+        extend_prefix(prev_prefix, &path_tail, options)
+    })
+}
+
+/// Check if `directory` is to be skipped because it has not settled
+/// according to `options.settle`.
+fn is_unsettled(directory: &path::Path, options: &FlattenOptions) -> bool {
+    match options.settle {
+        Some(settle) => !is_settled(directory, settle),
+        None => false,
+    }
+}
+
+/// Check if the subdirectory `directory` of `root` is too deep to be
+/// descended into according to `options.max_depth`.
+fn is_too_deep(root: &path::Path, directory: &path::Path, options: &FlattenOptions) -> bool {
+    options.max_depth.is_some_and(|max_depth| depth_within(root, directory) >= max_depth)
+}
+
+/// Read `directory` while `open_handles` directory handles are already
+/// open, keeping to the budget of `options.max_open`.
+///
+/// Returns the entries along with the number of handles that stay open
+/// while they are walked. Once the budget is used up, the directory is
+/// read in full up front so its handle is closed before descending.
+fn read_dir_budgeted(directory: &path::Path, open_handles: usize, options: &FlattenOptions)
+                     -> io::Result<(DirEntries, usize)> {
+    let read_dir = directory.read_dir()?;
+    Ok(match options.max_open {
+        Some(max_open) if open_handles + 1 >= max_open => {
+            let entries: Vec<_> = read_dir.collect();
+            (Box::new(entries.into_iter()), open_handles)
+        }
+        _ => (Box::new(read_dir), open_handles + 1),
+    })
+}
+
+/// Note that `entry` isn't descended into when it is a directory, which
+/// `should_traverse()` decided.
+fn log_not_traversed(entry: &fs::DirEntry, report: &Report) {
+    if entry.file_type().map(|file_type| file_type.is_dir()).unwrap_or(false) {
+        report.log(2, format!("not descending into {:?}: its name starts with '.' or '_'",
+                              entry.path()));
+    }
+}
+
+/// Note that the directory `entry_path` isn't descended into because of
+/// `options.max_depth`.
+fn log_too_deep(entry_path: &path::Path, report: &Report) {
+    report.log(2, format!("not descending into {:?}: it is deeper than --max-depth",
+                          entry_path));
+}
+
+/// A directory being flattened depth first.
+struct DepthFirstFrame {
+    directory: path::PathBuf,
+    prefix: String,
+    /// What is left to read of the directory.
+    read_dir: DirEntries,
+    /// Directory handles open while `read_dir` is walked.
+    open_handles: usize,
+    /// Entries to rename once every subdirectory has been flattened.
+    entries: Vec<path::PathBuf>,
+    /// Whether the run stopped before all of the directory was read.
+    stopped: bool,
+}
+
+/// Start flattening `directory` depth first while `open_handles`
+/// directory handles are already open, or `None` if it can't be read.
+fn open_depth_first(directory: path::PathBuf, prefix: String, open_handles: usize,
+                    options: &FlattenOptions, listings: &mut Listings, report: &mut Report)
+                    -> Option<DepthFirstFrame> {
+    let listed = match listings.remove(&directory) {
+        Some(listing) => listing.map(|listing| {
+            (Box::new(listing.into_iter()) as DirEntries, open_handles)
+        }),
+        None => read_dir_budgeted(&directory, open_handles, options),
+    };
+    let (read_dir, open_handles) = match listed {
+        Ok(read_dir) => read_dir,
+        Err(e) => {
+            report.warn(format!("{:?}: {}", directory, e));
+            return None;
+        }
+    };
+    report.record_scan(&directory);
+    Some(DepthFirstFrame {
+        directory,
+        prefix,
+        read_dir,
+        open_handles,
+        entries: Vec::new(),
+        stopped: false,
+    })
+}
+
+/// Flatten every subdirectory of `directory` before renaming its own
+/// entries.
+///
+/// Directories still being walked are kept on a stack rather than the
+/// call stack, so how deep a tree can be is only bounded by memory.
+fn flatten_depth_first(directory: &path::Path, prefix: &str, options: &FlattenOptions,
+                       listings: &mut Listings, queue: &mut Vec<Batch>, report: &mut Report) {
+    let mut stack: Vec<DepthFirstFrame> = Vec::new();
+    stack.extend(open_depth_first(directory.to_path_buf(), prefix.to_string(), 0, options,
+                                  listings, report));
+    while let Some(frame) = stack.last_mut() {
+        let entry = if frame.stopped { None } else { frame.read_dir.next() };
+        let entry = match entry {
+            Some(Ok(entry)) => entry,
+            Some(Err(e)) => {
+                report.warn(format!("{:?}: {}", frame.directory, e));
+                continue;
+            }
+            None => {
+                let frame = stack.pop().unwrap();
+                if frame.stopped {
+                    report.unprocessed.extend(frame.read_dir
+                                                   .filter_map(Result::ok)
+                                                   .map(|entry| entry.path()));
+                }
+                let batch = Batch { directory: frame.directory, entries: frame.entries,
+                                    prefix: frame.prefix };
+                rename_or_queue(batch, options, queue, report);
+                continue;
+            }
+        };
+        let entry_path = entry.path();
+        if options.artifacts.contains(&entry_path) {
+            report.record(&entry_path, Outcome::Skipped(SkipReason::Artifact));
+        } else if !should_traverse(&entry) {
+            log_not_traversed(&entry, report);
+            frame.entries.push(entry_path);
+        } else if is_too_deep(directory, &entry_path, options) {
+            log_too_deep(&entry_path, report);
+        } else if options.unwritable.contains(&entry_path) {
+            report.record(&entry_path, Outcome::Skipped(SkipReason::ReadOnly));
+        } else if is_unsettled(&entry_path, options) {
+            report.record(&entry_path, Outcome::Skipped(SkipReason::Unsettled));
+        } else {
+            if !report.checkpoint() {
+                report.unprocessed.push(entry_path);
+                frame.stopped = true;
+                continue;
+            }
+            report.log(2, format!("descending into {:?}", entry_path));
+            match directory_prefix(&entry_path, &frame.prefix, options) {
+                Ok(entry_prefix) => {
+                    let open_handles = frame.open_handles;
+                    stack.extend(open_depth_first(entry_path, entry_prefix, open_handles,
+                                                  options, listings, report));
+                }
+                Err(message) => {
+                    report.warn(message);
+                    report.record(&entry_path, Outcome::Skipped(SkipReason::NonUtf8));
+                }
+            }
+        }
+    }
+}
+
+/// Listings of directories read ahead of a run, by directory; see
+/// `read_ahead()`.
+type Listings = HashMap<path::PathBuf, io::Result<Vec<io::Result<fs::DirEntry>>>>;
+
+/// Read `directory` and every directory under it a run would descend into
+/// across `options.jobs` threads, so the run can walk the tree without
+/// waiting on the filesystem at every step.
+///
+/// A directory a run ends up descending into that wasn't read ahead (say,
+/// as it only just settled) is read when it gets there.
+fn read_ahead(directory: &path::Path, options: &FlattenOptions) -> Listings {
+    let (artifacts, unwritable, settle) = (&options.artifacts, &options.unwritable,
+                                           options.settle);
+    let (root, max_depth) = (directory, options.max_depth);
+    // Directories left to read, and how many are being read.
+    let queue = Mutex::new((vec![directory.to_path_buf()], 0));
+    let changed = Condvar::new();
+    let listings = Mutex::new(Listings::new());
+    std::thread::scope(|scope| {
+        for _ in 0..options.jobs {
+            scope.spawn(|| loop {
+                let directory = {
+                    let mut queue = queue.lock().unwrap();
+                    loop {
+                        if let Some(directory) = queue.0.pop() {
+                            queue.1 += 1;
+                            break directory;
+                        }
+                        if queue.1 == 0 {
+                            return;
+                        }
+                        queue = changed.wait(queue).unwrap();
+                    }
+                };
+                let listing = directory.read_dir().map(|read_dir| read_dir.collect::<Vec<_>>());
+                let mut subdirectories = Vec::new();
+                for entry in listing.iter().flatten().filter_map(|entry| entry.as_ref().ok()) {
+                    let entry_path = entry.path();
+                    if !artifacts.contains(&entry_path) && should_traverse(entry) &&
+                       !unwritable.contains(&entry_path) &&
+                       settle.map(|settle| is_settled(&entry_path, settle)).unwrap_or(true) &&
+                       max_depth.map(|max| depth_within(root, &entry_path) < max)
+                                .unwrap_or(true) {
+                        subdirectories.push(entry_path);
+                    }
+                }
+                listings.lock().unwrap().insert(directory, listing);
+                let mut queue = queue.lock().unwrap();
+                queue.0.extend(subdirectories);
+                queue.1 -= 1;
+                changed.notify_all();
+            });
+        }
+    });
+    listings.into_inner().unwrap()
+}
+
+/// Count the entries a run on `directory` would look at, descending into
+/// the same directories it would, so progress can be shown against a
+/// total.
+pub fn count_entries(directory: &path::Path, options: &FlattenOptions) -> usize {
+    let root = directory;
+    let mut count = 0;
+    let mut directories = vec![directory.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        let read_dir = match directory.read_dir() {
+            Ok(read_dir) => read_dir,
+            Err(_) => continue,
+        };
+        for entry in read_dir.filter_map(Result::ok) {
+            let entry_path = entry.path();
+            if options.artifacts.contains(&entry_path) {
+                continue;
+            } else if !should_traverse(&entry) {
+                if !is_junk(&entry_path, options) {
+                    count += 1;
+                }
+            } else if !options.unwritable.contains(&entry_path) &&
+                      !is_unsettled(&entry_path, options) &&
+                      !is_too_deep(root, &entry_path, options) {
+                directories.push(entry_path);
+            }
+        }
+    }
+    count
+}
+
+/// Rename the entries of each directory before moving on to the next
+/// level of subdirectories.
+fn flatten_breadth_first(directory: &path::Path, prefix: String, options: &FlattenOptions,
+                         listings: &mut Listings, batches: &mut Vec<Batch>,
+                         report: &mut Report) {
+    let root = directory;
+    let mut queue = VecDeque::new();
+    queue.push_back((directory.to_path_buf(), prefix));
+    while let Some((directory, prefix)) = queue.pop_front() {
+        if !report.checkpoint() {
+            report.unprocessed.push(directory);
+            report.unprocessed.extend(queue.drain(..).map(|(directory, _)| directory));
+            return;
+        }
+        let mut entries = Vec::new();
+        let listed = match listings.remove(&directory) {
+            Some(listing) => listing.map(|listing| Box::new(listing.into_iter()) as DirEntries),
+            None => directory.read_dir().map(|read_dir| Box::new(read_dir) as DirEntries),
+        };
+        let read_dir = match listed {
+            Ok(read_dir) => read_dir,
+            Err(e) => {
+                report.warn(format!("{:?}: {}", directory, e));
+                continue;
+            }
+        };
+        report.record_scan(&directory);
+        for entry in read_dir {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    report.warn(format!("{:?}: {}", directory, e));
+                    continue;
+                }
+            };
+            let entry_path = entry.path();
+            if options.artifacts.contains(&entry_path) {
+                report.record(&entry_path, Outcome::Skipped(SkipReason::Artifact));
+            } else if !should_traverse(&entry) {
+                log_not_traversed(&entry, report);
+                entries.push(entry_path);
+            } else if is_too_deep(root, &entry_path, options) {
+                log_too_deep(&entry_path, report);
+            } else if options.unwritable.contains(&entry_path) {
+                report.record(&entry_path, Outcome::Skipped(SkipReason::ReadOnly));
+            } else if is_unsettled(&entry_path, options) {
+                report.record(&entry_path, Outcome::Skipped(SkipReason::Unsettled));
+            } else {
+                report.log(2, format!("descending into {:?}", entry_path));
+                match directory_prefix(&entry_path, &prefix, options) {
+                    Ok(entry_prefix) => queue.push_back((entry_path, entry_prefix)),
+                    Err(message) => {
+                        report.warn(message);
+                        report.record(&entry_path, Outcome::Skipped(SkipReason::NonUtf8));
+                    }
+                }
+            }
+        }
+        rename_or_queue(Batch { directory, entries, prefix }, options, batches, report);
+    }
+}
+
+/// The entries of a directory a run renames, along with their prefix.
+struct Batch {
+    directory: path::PathBuf,
+    entries: Vec<path::PathBuf>,
+    prefix: String,
+}
+
+/// Rename the entries of `batch` right away, or add it to `queue` for
+/// `rename_directories()` when directories are renamed side by side.
+fn rename_or_queue(batch: Batch, options: &FlattenOptions, queue: &mut Vec<Batch>,
+                   report: &mut Report) {
+    // Each `git mv` takes the index lock, so they can't run side by side.
+    if options.jobs > 1 && !options.dry_run && !options.git {
+        queue.push(batch);
+    } else {
+        rename_entries(&batch.directory, &batch.entries, &batch.prefix, options,
+                       &Mutex::new(report));
+    }
+}
+
+/// Rename the entries of every directory in `queue` across `options.jobs`
+/// threads.
+///
+/// This is what upholds the guarantee documented on
+/// `FlattenOptions::jobs`: each batch is taken by a single thread, and
+/// different directories can't affect each other since entries are only
+/// renamed within their own directory and the directories a run descends
+/// into keep their names.
+fn rename_directories(queue: &[Batch], options: &FlattenOptions, report: &mut Report) {
+    let next = AtomicUsize::new(0);
+    let report = Mutex::new(report);
+    std::thread::scope(|scope| {
+        for _ in 0..options.jobs.min(queue.len()) {
+            scope.spawn(|| {
+                while let Some(batch) = queue.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    rename_entries(&batch.directory, &batch.entries, &batch.prefix, options,
+                                   &report);
+                }
+            });
+        }
+    });
+}
+
+/// Rename `entries`, which are directly in `directory`, using `prefix`.
+///
+/// `report` is only locked to consult it or add to it; new names are
+/// worked out, and entries deleted or moved, without holding it, so that
+/// other directories can be renamed meanwhile (see
+/// `rename_directories()`).
+fn rename_entries(directory: &path::Path, entries: &[path::PathBuf], prefix: &str,
+                  options: &FlattenOptions, report: &Mutex<&mut Report>) {
+    let root = {
+        let mut locked = report.lock().unwrap();
+        if !locked.checkpoint() {
+            locked.unprocessed.extend(entries.iter().cloned());
+            return;
+        }
+        locked.root.clone()
+    };
+    let growing = match options.defer_growing {
+        Some(delay) => growing_files(directory, delay),
+        None => HashSet::new(),
+    };
+    let entries: Vec<&path::PathBuf> = entries.iter()
+                                              .filter(|entry| {
+                                                  !delete_junk(entry, options, report)
+                                              })
+                                              .collect();
+    let synced = options.fsync && !options.dry_run;
+    // Renames only journaled once they are synced to disk.
+    let mut held = Vec::new();
+    for (index, &entry_path) in entries.iter().enumerate() {
+        let mut locked = report.lock().unwrap();
+        if !locked.checkpoint() {
+            locked.unprocessed.extend(entries[index..].iter().map(|&entry| entry.clone()));
+            break;
+        }
+        locked.record_processed();
+        if is_hidden(entry_path, options) {
+            locked.record(entry_path, Outcome::Skipped(SkipReason::DotFile));
+            continue;
+        }
+        if options.find_duplicates {
+            locked.record_size(entry_path);
+        }
+        if options.defer_growing.is_some() &&
+           (growing.contains(entry_path) || is_partial_download(entry_path)) {
+            locked.warn(format!("deferred (still being written): {:?}", entry_path));
+            locked.record(entry_path, Outcome::Deferred);
+            continue;
+        }
+        drop(locked);
+
+        let mut warnings = Vec::new();
+        let target = work_out_filename(entry_path, prefix, options, &root, &mut warnings);
+        let filename = match record_target(entry_path, target, warnings,
+                                           &mut report.lock().unwrap()) {
+            Some(filename) => filename,
+            None => continue,
+        };
+        let filename = match free_filename(entry_path, &filename, options.resolver()) {
+            Some(filename) => filename,
+            None => {
+                let mut locked = report.lock().unwrap();
+                locked.warn(format!("{:?}: {:?} is already taken", entry_path, filename));
+                locked.record(entry_path, Outcome::Skipped(SkipReason::Collision));
+                continue;
+            }
+        };
+        // The flags are only worth an open and an ioctl per entry when they
+        // are to be cleared; otherwise a refused rename is checked below.
+        let immutable = if options.clear_immutable { immutable_flags(entry_path) } else { 0 };
+        let new_path = entry_path.with_file_name(&filename);
+        if options.dry_run {
+            print_dry_run(entry_path, Some(&new_path), options);
+            report.lock().unwrap().record_rename(entry_path, &new_path);
+            continue;
+        }
+        let moved = if immutable != 0 {
+            move_immutable(entry_path, &new_path, immutable, options, report)
+        } else {
+            move_entry(entry_path, &new_path, options)
+        };
+        let mut locked = report.lock().unwrap();
+        match moved {
+            Ok(()) => {
+                record_moved(entry_path, &new_path, !synced, &mut locked);
+                if synced {
+                    held.push((entry_path.clone(), new_path));
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied &&
+                          !options.clear_immutable && immutable_flags(entry_path) != 0 => {
+                locked.record(entry_path, Outcome::Skipped(SkipReason::Immutable))
+            }
+            Err(e) => locked.record_failure(entry_path, &e, options.keep_going),
+        }
+    }
+    if !held.is_empty() {
+        if let Err(e) = sync_directory(directory) {
+            report.lock().unwrap().warn(format!("{:?}: {}", directory, e));
+        }
+        report.lock().unwrap().write_held_journal(&held);
+    }
+}
+
+/// Record that the entry at `from` was renamed to `to`, journaling it
+/// unless `journal` is false.
+fn record_moved(from: &path::Path, to: &path::Path, journal: bool, report: &mut Report) {
+    if let Some(size) = report.sizes.remove(from) {
+        report.sizes.insert(to.to_path_buf(), size);
+    }
+    report.log(1, format!("{} -> {}", from.display(), to.display()));
+    if journal {
+        report.record_rename(from, to);
+    } else {
+        report.record_held_rename(from, to);
+    }
+}
+
+/// Print what a dry run would do to the entry at `from`: rename it to `to`,
+/// or delete it if there is no `to`.
+fn print_dry_run(from: &path::Path, to: Option<&path::Path>, options: &FlattenOptions) {
+    match (options.script, to) {
+        (Some(script), _) => {
+            let overwrite = options.on_collision == CollisionPolicy::Overwrite;
+            let _ = io::stdout().write_all(&script.command(from, to, overwrite));
+        }
+        (None, Some(to)) => println!("{} -> {}", from.display(), to.display()),
+        (None, None) => println!("{} -> (deleted)", from.display()),
+    }
+}
+
+/// Move the entry at `from`, which has the immutable `flags`, to `to` by
+/// clearing the flags and setting them again wherever it ends up.
+fn move_immutable(from: &path::Path, to: &path::Path, flags: u32, options: &FlattenOptions,
+                  report: &Mutex<&mut Report>) -> io::Result<()> {
+    change_immutable_flags(from, flags, false)?;
+    let moved = move_entry(from, to, options);
+    let entry = if moved.is_ok() { to } else { from };
+    if let Err(e) = change_immutable_flags(entry, flags, true) {
+        report.lock().unwrap().warn(format!("{:?}: flags not restored: {}", entry, e));
+    }
+    moved
+}
+
+/// Delete the entry at `entry` if it is junk according to `is_junk()`.
+///
+/// Returns whether it was junk, even if deleting it failed.
+fn delete_junk(entry: &path::Path, options: &FlattenOptions, report: &Mutex<&mut Report>)
+               -> bool {
+    if !is_junk(entry, options) {
+        return false;
+    }
+    let size = fs::symlink_metadata(entry).map(|metadata| metadata.len()).unwrap_or_default();
+    if options.dry_run {
+        print_dry_run(entry, None, options);
+        report.lock().unwrap().record_deletion(entry, size);
+        return true;
+    }
+    note_operation(format!("deleting {:?}", entry));
+    let removed = fs::remove_file(entry);
+    let mut report = report.lock().unwrap();
+    match removed {
+        Ok(()) => {
+            report.log(1, format!("deleted {}", entry.display()));
+            report.record_deletion(entry, size);
+        }
+        Err(e) => report.record_failure(entry, &e, options.keep_going),
+    }
+    true
+}
+
+/// Merge sibling directories within `directory`, and within every
+/// directory below it, whose names only differ by case or punctuation
+/// (like `AC-DC` and `ACDC`): everything in them is moved into the first
+/// of them by name, and the emptied directories are removed.
+///
+/// Moved entries whose names are already taken go by
+/// `options.resolver()`, except that directories of the same name are
+/// merged in turn; entries left alone keep their directory.
+pub fn merge_directories(directory: &path::Path, options: &FlattenOptions,
+                         report: &mut Report) {
+    // The groups of similar siblings left to merge in each directory
+    // being walked.
+    let mut stack = Vec::new();
+    stack.extend(similar_siblings(directory, directory, options, report));
+    'groups: while let Some(groups) = stack.last_mut() {
+        let mut similar = match groups.next() {
+            Some((_, similar)) => similar,
+            None => {
+                stack.pop();
+                continue;
+            }
+        };
+        similar.sort();
+        let into = similar.remove(0);
+        for from in similar {
+            if !report.checkpoint() {
+                stack.pop();
+                continue 'groups;
+            }
+            if options.dry_run {
+                println!("{} => {}", from.display(), into.display());
+                continue;
+            }
+            merge_into(&from, &into, options, report);
+        }
+        stack.extend(similar_siblings(directory, &into, options, report));
+    }
+}
+
+/// Group the subdirectories of `directory`, somewhere under `root`, that
+/// a run would descend into by `similarity_key()`, or `None` if it can't
+/// be read.
+fn similar_siblings(root: &path::Path, directory: &path::Path, options: &FlattenOptions,
+                    report: &mut Report)
+                    -> Option<btree_map::IntoIter<String, Vec<path::PathBuf>>> {
+    let read_dir = match directory.read_dir() {
+        Ok(read_dir) => read_dir,
+        Err(e) => {
+            report.warn(format!("{:?}: {}", directory, e));
+            return None;
+        }
+    };
+    let mut siblings: BTreeMap<String, Vec<path::PathBuf>> = BTreeMap::new();
+    for entry in read_dir.filter_map(Result::ok) {
+        let entry_path = entry.path();
+        if should_traverse(&entry) && !options.artifacts.contains(&entry_path) &&
+           !options.unwritable.contains(&entry_path) && !is_too_deep(root, &entry_path, options) {
+            let key = similarity_key(&entry.file_name().to_string_lossy());
+            siblings.entry(key).or_default().push(entry_path);
+        }
+    }
+    Some(siblings.into_iter())
+}
+
+/// Move everything in the directory `from` into the directory `into`,
+/// removing `from` once it is empty.
+///
+/// If the run is stopped, whatever is left to move is recorded as
+/// unprocessed and the directories being emptied are kept.
+fn merge_into(from: &path::Path, into: &path::Path, options: &FlattenOptions,
+              report: &mut Report) {
+    // Each directory being emptied, with the one it is merged into and
+    // what is left to move.
+    let mut stack = Vec::new();
+    stack.extend(open_merge(from.to_path_buf(), into.to_path_buf(), report));
+    while let Some((_, into, entries)) = stack.last_mut() {
+        let entry_path = match entries.next() {
+            Some(entry_path) => entry_path,
+            None => {
+                let (from, _, _) = stack.pop().unwrap();
+                if let Err(e) = fs::remove_dir(&from) {
+                    report.warn(format!("{:?} was not removed: {}", from, e));
+                }
+                continue;
+            }
+        };
+        if !report.checkpoint() {
+            report.unprocessed.push(entry_path);
+            for (_, _, entries) in stack {
+                report.unprocessed.extend(entries);
+            }
+            return;
+        }
+        let name = match entry_path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        let taken = into.join(&name);
+        let is_dir = |path: &path::Path| fs::symlink_metadata(path).is_ok_and(|m| m.is_dir());
+        if is_dir(&entry_path) && is_dir(&taken) {
+            stack.extend(open_merge(entry_path, taken, report));
+            continue;
+        }
+        let name = if fs::symlink_metadata(&taken).is_err() {
+            Some(name)
+        } else {
+            options.resolver().resolve(&entry_path, &name, &taken)
+        };
+        let target = match name {
+            Some(name) => into.join(name),
+            None => {
+                report.warn(format!("{:?}: {:?} is already taken", entry_path, taken));
+                report.record(&entry_path, Outcome::Skipped(SkipReason::Collision));
+                continue;
+            }
+        };
+        match move_entry(&entry_path, &target, options) {
+            Ok(()) => {
+                report.log(1, format!("{} -> {}", entry_path.display(), target.display()));
+                report.record_rename(&entry_path, &target);
+            }
+            Err(e) => report.record_failure(&entry_path, &e, options.keep_going),
+        }
+    }
+}
+
+/// Start merging the directory `from` into `into`, or `None` if `from`
+/// can't be read.
+fn open_merge(from: path::PathBuf, into: path::PathBuf, report: &mut Report)
+              -> Option<(path::PathBuf, path::PathBuf, std::vec::IntoIter<path::PathBuf>)> {
+    match from.read_dir() {
+        Ok(read_dir) => {
+            let entries: Vec<path::PathBuf> = read_dir.filter_map(Result::ok)
+                                                      .map(|entry| entry.path())
+                                                      .collect();
+            Some((from, into, entries.into_iter()))
+        }
+        Err(e) => {
+            report.warn(format!("{:?}: {}", from, e));
+            None
+        }
+    }
+}
+
+/// Check if `a` and `b` only differ by case, and so are the same entry on
+/// case-insensitive filesystems.
+fn same_but_case(a: &path::Path, b: &path::Path) -> bool {
+    a.to_string_lossy().to_lowercase() == b.to_string_lossy().to_lowercase()
+}
+
+/// Make sure the entry at `entry_path` can be renamed to `filename` without
+/// clobbering anything else, going by `resolver`.
+///
+/// `None` means the entry is to be left alone.
+fn free_filename(entry_path: &path::Path, filename: &str, resolver: &dyn ConflictResolver)
+                 -> Option<String> {
+    let target = entry_path.with_file_name(filename);
+    if same_but_case(entry_path, &target) || fs::symlink_metadata(&target).is_err() {
+        return Some(filename.to_string());
+    }
+    resolver.resolve(entry_path, filename, &target)
+}
+
+/// Work out what the entry at `entry_path` is to be renamed to using
+/// `prefix`, going by `options.scheme` and `options.target_profile`.
+///
+/// `None` means the entry is to be left alone, which has already been
+/// recorded in `report`.
+fn target_filename(entry_path: &path::Path, prefix: &str, options: &FlattenOptions,
+                   report: &mut Report) -> Option<String> {
+    let mut warnings = Vec::new();
+    let target = work_out_filename(entry_path, prefix, options, &report.root, &mut warnings);
+    record_target(entry_path, target, warnings, report)
+}
+
+/// Do the work of `target_filename()` for the entry at `entry_path`, which
+/// is under `root`, without touching the report, so that `options.scheme`
+/// can run without holding it.
+///
+/// Warnings are added to `warnings`, and an entry to be left alone gives
+/// the reason why.
+fn work_out_filename(entry_path: &path::Path, prefix: &str, options: &FlattenOptions,
+                     root: &path::Path, warnings: &mut Vec<String>)
+                     -> Result<String, SkipReason> {
+    let filename = match options.scheme {
+        Some(ref scheme) => match scheme_filename(scheme, entry_path, prefix, options) {
+            Ok(Some(filename)) => filename,
+            Ok(None) => return Err(SkipReason::SchemeDeclined),
+            Err(message) => {
+                warnings.push(message);
+                return Err(SkipReason::SchemeFailed);
+            }
+        },
+        None => match filename_text(entry_path, options) {
+            Ok(filename) => folded_filename(prefix, &filename, options),
+            Err(message) => {
+                warnings.push(message);
+                return Err(SkipReason::NonUtf8);
+            }
+        },
+    };
+    match options.target_profile {
+        Some(profile) => {
+            let (filename, problems) = profile.apply(&filename, depth_within(root, entry_path));
+            for problem in problems {
+                warnings.push(format!("{:?}: {}", entry_path, problem));
+            }
+            Ok(filename)
+        }
+        None => Ok(filename),
+    }
+}
+
+/// Add what `work_out_filename()` found for the entry at `entry_path` to
+/// `report`, giving the new filename if there is one.
+fn record_target(entry_path: &path::Path, target: Result<String, SkipReason>,
+                 warnings: Vec<String>, report: &mut Report) -> Option<String> {
+    for warning in warnings {
+        report.warn(warning);
+    }
+    match target {
+        Ok(filename) => Some(filename),
+        Err(reason) => {
+            report.record(entry_path, Outcome::Skipped(reason));
+            None
+        }
+    }
+}
+
+/// Count how many levels below `root` the entry at `path` sits, with
+/// entries directly in `root` being one level down.
+fn depth_within(root: &path::Path, path: &path::Path) -> usize {
+    match path.strip_prefix(root) {
+        Ok(relative) => relative.components().count(),
+        Err(_) => 0,
+    }
+}
+
+/// Graph languages the before/after structure can be rendered in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GraphFormat {
+    /// Graphviz's DOT language.
+    Dot,
+    /// Mermaid flowcharts.
+    Mermaid,
+}
+
+impl GraphFormat {
+    /// Look up a format by its command-line name.
+    pub fn from_name(name: &str) -> Option<GraphFormat> {
+        match name {
+            "dot" => Some(GraphFormat::Dot),
+            "mermaid" => Some(GraphFormat::Mermaid),
+            _ => None,
+        }
+    }
+}
+
+/// Directories and files before flattening, each file linked to the name
+/// it would end up with.
+#[derive(Debug, Default)]
+pub struct Graph {
+    /// Label of each node and whether it is a directory.
+    pub nodes: Vec<(String, bool)>,
+    /// Node indexes linked together, and whether the link is a rename
+    /// rather than containment.
+    pub edges: Vec<(usize, usize, bool)>,
+}
+
+impl Graph {
+    fn add_node(&mut self, label: String, is_dir: bool) -> usize {
+        self.nodes.push((label, is_dir));
+        self.nodes.len() - 1
+    }
+
+    /// Render the graph in `format`.
+    pub fn render(&self, format: GraphFormat) -> String {
+        match format {
+            GraphFormat::Dot => {
+                let mut text = "digraph flatten {\n  rankdir=LR;\n".to_string();
+                for (index, &(ref label, is_dir)) in self.nodes.iter().enumerate() {
+                    let shape = if is_dir { "folder" } else { "note" };
+                    text.push_str(&format!("  n{} [label={:?}, shape={}];\n",
+                                           index, label, shape));
+                }
+                for &(from, to, is_rename) in &self.edges {
+                    let style = if is_rename { " [style=dashed]" } else { "" };
+                    text.push_str(&format!("  n{} -> n{}{};\n", from, to, style));
+                }
+                text.push_str("}\n");
+                text
+            }
+            GraphFormat::Mermaid => {
+                let mut text = "graph LR\n".to_string();
+                for (index, &(ref label, is_dir)) in self.nodes.iter().enumerate() {
+                    let label = label.replace('"', "#quot;");
+                    if is_dir {
+                        text.push_str(&format!("  n{}[\"{}\"]\n", index, label));
+                    } else {
+                        text.push_str(&format!("  n{}(\"{}\")\n", index, label));
+                    }
+                }
+                for &(from, to, is_rename) in &self.edges {
+                    let arrow = if is_rename { "-.->" } else { "-->" };
+                    text.push_str(&format!("  n{} {} n{}\n", from, arrow, to));
+                }
+                text
+            }
+        }
+    }
+}
+
+/// Build a graph of what flattening `directory` would do without
+/// renaming anything.
+///
+/// `options.defer_growing` is ignored like it is for `estimate()`.
+pub fn visualize(directory: &path::Path, options: &FlattenOptions) -> Graph {
+    let mut graph = Graph::default();
+    let mut report = Report::new(directory);
+    let prefix = match anchored_prefix(directory, options) {
+        Ok(prefix) => prefix,
+        Err(message) => {
+            println_stderr(message);
+            return graph;
+        }
+    };
+    let node = graph.add_node(file_name_lossy(directory), true);
+    visualize_directory(directory, &prefix, node, options, &mut graph, &mut report);
+    graph
+}
+
+/// Read the entries of `directory` sorted by name, or `None` if it can't
+/// be read.
+fn sorted_entries(directory: &path::Path) -> Option<std::vec::IntoIter<fs::DirEntry>> {
+    match directory.read_dir() {
+        Ok(read_dir) => {
+            let mut entries: Vec<fs::DirEntry> = read_dir.filter_map(Result::ok).collect();
+            entries.sort_by_key(|entry| entry.file_name());
+            Some(entries.into_iter())
+        }
+        Err(e) => {
+            println_stderr(format!("{:?}: {}", directory, e));
+            None
+        }
+    }
+}
+
+/// Add the contents of `directory`, whose node is `node`, to `graph`.
+fn visualize_directory(directory: &path::Path, prefix: &str, node: usize,
+                       options: &FlattenOptions, graph: &mut Graph, report: &mut Report) {
+    // Each directory being walked, with its prefix and node.
+    let mut stack = Vec::new();
+    if let Some(entries) = sorted_entries(directory) {
+        stack.push((entries, prefix.to_string(), node));
+    }
+    while let Some((entries, prefix, node)) = stack.last_mut() {
+        let entry = match entries.next() {
+            Some(entry) => entry,
+            None => {
+                stack.pop();
+                continue;
+            }
+        };
+        let node = *node;
+        let entry_path = entry.path();
+        if options.artifacts.contains(&entry_path) {
+            continue;
+        }
+        if should_traverse(&entry) {
+            if is_unsettled(&entry_path, options) {
+                continue;
+            }
+            if let Ok(entry_prefix) = directory_prefix(&entry_path, prefix, options) {
+                let entry_node = graph.add_node(file_name_lossy(&entry_path), true);
+                graph.edges.push((node, entry_node, false));
+                if is_too_deep(directory, &entry_path, options) ||
+                   options.unwritable.contains(&entry_path) {
+                    continue;
+                }
+                if let Some(entries) = sorted_entries(&entry_path) {
+                    stack.push((entries, entry_prefix, entry_node));
+                }
+            }
+        } else {
+            let entry_node = graph.add_node(file_name_lossy(&entry_path), false);
+            graph.edges.push((node, entry_node, false));
+            if is_hidden(&entry_path, options) {
+                continue;
+            }
+            if is_junk(&entry_path, options) {
+                let deleted_node = graph.add_node("(deleted)".to_string(), false);
+                graph.edges.push((entry_node, deleted_node, true));
+            } else if let Some(filename) = target_filename(&entry_path, prefix, options, report) {
+                let renamed_node = graph.add_node(filename, false);
+                graph.edges.push((entry_node, renamed_node, true));
+            }
+        }
+    }
+}
+
+/// Work out every rename flattening `directory` would make, without
+/// renaming anything.
+///
+/// Returns the path of each entry to be renamed along with its new
+/// filename, in a stable order; entries left alone are recorded in
+/// `report`. `options.defer_growing` is ignored like it is for
+/// `estimate()`.
+pub fn plan(directory: &path::Path, options: &FlattenOptions, report: &mut Report)
+            -> Result<Vec<(path::PathBuf, String)>, FlattenError> {
+    let prefix = anchored_prefix(directory, options).map_err(FlattenError::Prefix)?;
+    check_readable(directory)?;
+    let mut renames = Vec::new();
+    let mut collisions = 0;
+    plan_directory(directory, &prefix, options, report, &mut renames, &mut collisions);
+    match options.max_collisions {
+        Some(max) if collisions > max => Err(FlattenError::TooManyCollisions(max)),
+        _ => Ok(renames),
+    }
+}
+
+/// A directory being planned, with its entries sorted by name.
+struct PlanFrame {
+    prefix: String,
+    /// Entries yet to be planned.
+    entries: std::vec::IntoIter<fs::DirEntry>,
+    /// Renames planned for entries directly in the directory, when
+    /// counting collisions.
+    own: Vec<(path::PathBuf, String)>,
+}
+
+/// Start planning `directory`, or `None` if it can't be read.
+fn open_plan(directory: &path::Path, prefix: String, report: &mut Report) -> Option<PlanFrame> {
+    let mut entries: Vec<fs::DirEntry> = match directory.read_dir() {
+        Ok(read_dir) => read_dir.filter_map(Result::ok).collect(),
+        Err(e) => {
+            report.warn(format!("{:?}: {}", directory, e));
+            return None;
+        }
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+    Some(PlanFrame { prefix, entries: entries.into_iter(), own: Vec::new() })
+}
+
+/// Add the renames planned for `directory` to `renames`, and the number of
+/// collisions among them to `collisions`.
+///
+/// Stops early once there are more collisions than
+/// `options.max_collisions`.
+fn plan_directory(directory: &path::Path, prefix: &str, options: &FlattenOptions,
+                  report: &mut Report, renames: &mut Vec<(path::PathBuf, String)>,
+                  collisions: &mut usize) {
+    let mut stack: Vec<PlanFrame> = Vec::new();
+    stack.extend(open_plan(directory, prefix.to_string(), report));
+    while let Some(frame) = stack.last_mut() {
+        if options.max_collisions.is_some_and(|max| *collisions > max) {
+            return;
+        }
+        let entry = match frame.entries.next() {
+            Some(entry) => entry,
+            None => {
+                // Entries are only ever renamed within their directory, so
+                // collisions can be counted one directory at a time.
+                let frame = stack.pop().unwrap();
+                *collisions += find_collisions(&frame.own).len();
+                continue;
+            }
+        };
+        let entry_path = entry.path();
+        if options.artifacts.contains(&entry_path) {
+            report.record(&entry_path, Outcome::Skipped(SkipReason::Artifact));
+        } else if should_traverse(&entry) {
+            if options.unwritable.contains(&entry_path) {
+                report.record(&entry_path, Outcome::Skipped(SkipReason::ReadOnly));
+                continue;
+            }
+            if is_unsettled(&entry_path, options) {
+                report.record(&entry_path, Outcome::Skipped(SkipReason::Unsettled));
+                continue;
+            }
+            if is_too_deep(directory, &entry_path, options) {
+                continue;
+            }
+            match directory_prefix(&entry_path, &frame.prefix, options) {
+                Ok(entry_prefix) => stack.extend(open_plan(&entry_path, entry_prefix, report)),
+                Err(message) => {
+                    report.warn(message);
+                    report.record(&entry_path, Outcome::Skipped(SkipReason::NonUtf8));
+                }
+            }
+        } else if is_junk(&entry_path, options) {
+            continue;
+        } else if is_hidden(&entry_path, options) {
+            report.record(&entry_path, Outcome::Skipped(SkipReason::DotFile));
+        } else if let Some(filename) = target_filename(&entry_path, &frame.prefix, options,
+                                                       report) {
+            if options.max_collisions.is_some() {
+                frame.own.push((entry_path.clone(), filename.clone()));
+            }
+            renames.push((entry_path, filename));
+        }
+    }
+}
+
+/// A new name that cannot be given without clobbering something.
+#[derive(Debug, PartialEq)]
+pub struct Collision {
+    /// Path the new name would take.
+    pub target: path::PathBuf,
+    /// Entries that would be given the new name.
+    pub sources: Vec<path::PathBuf>,
+    /// Whether something already goes by the new name.
+    pub exists: bool,
+}
+
+impl Collision {
+    /// Explain the collision in a single line.
+    pub fn describe(&self) -> String {
+        let sources: Vec<String> = self.sources
+                                       .iter()
+                                       .map(|source| format!("{:?}", source))
+                                       .collect();
+        if self.exists {
+            format!("{:?} already exists, so renaming {} would clobber it",
+                    self.target, sources.join(" and "))
+        } else {
+            format!("{} would each be renamed to {:?}", sources.join(" and "), self.target)
+        }
+    }
+}
+
+/// Find the renames among those `plan()` came up with that would clobber
+/// an existing entry or each other, sorted by target.
+///
+/// An entry whose new name only differs from its old one by case does not
+/// collide with itself, even on case-insensitive filesystems.
+pub fn find_collisions(renames: &[(path::PathBuf, String)]) -> Vec<Collision> {
+    let mut by_target: BTreeMap<path::PathBuf, Vec<path::PathBuf>> = BTreeMap::new();
+    for (source, filename) in renames {
+        let target = source.with_file_name(filename);
+        if &target != source {
+            by_target.entry(target).or_default().push(source.clone());
+        }
+    }
+    by_target.into_iter()
+             .filter_map(|(target, sources)| {
+                 let renamed_in_place = sources.len() == 1 && same_but_case(&sources[0], &target);
+                 let exists = !renamed_in_place && fs::symlink_metadata(&target).is_ok();
+                 if exists || sources.len() > 1 {
+                     Some(Collision { target, sources, exists })
+                 } else {
+                     None
+                 }
+             })
+             .collect()
+}
+
+/// Plan flattening `directory` and find the collisions the renames would
+/// cause; see `find_collisions()`.
+///
+/// Warnings and errors from planning are left for the real run to report,
+/// except for finding more collisions than `options.max_collisions`.
+pub fn preflight(directory: &path::Path, options: &FlattenOptions)
+                 -> Result<Vec<Collision>, FlattenError> {
+    let mut report = Report::new(directory);
+    report.quiet = true;
+    match plan(directory, options, &mut report) {
+        Ok(renames) => Ok(find_collisions(&renames)),
+        Err(FlattenError::TooManyCollisions(max)) => Err(FlattenError::TooManyCollisions(max)),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+/// An entry of a tree as it would be once a run is done.
+#[derive(Debug, PartialEq)]
+pub struct ListedEntry {
+    /// Where the entry would end up.
+    pub path: path::PathBuf,
+    /// Where the entry is now.
+    pub from: path::PathBuf,
+    /// Whether the entry is a directory.
+    pub is_dir: bool,
+}
+
+/// Every entry of a tree as it would be once the renames `plan()` came
+/// up with were made, without making them.
+#[derive(Debug, PartialEq)]
+pub struct VirtualListing {
+    pub root: path::PathBuf,
+    /// Sorted by where they would end up; renames that collide leave the
+    /// same path listed more than once.
+    pub entries: Vec<ListedEntry>,
+}
+
+impl VirtualListing {
+    /// List everything under `root` as it would be after `renames`, leaving
+    /// out junk a run would delete; `options.merge_directories` is not
+    /// taken into account.
+    pub fn new(root: &path::Path, options: &FlattenOptions, renames: &[(path::PathBuf, String)])
+               -> VirtualListing {
+        let renamed: HashMap<&path::Path, &str> = renames.iter()
+                                                         .map(|(from, name)| {
+                                                             (from.as_path(), name.as_str())
+                                                         })
+                                                         .collect();
+        let mut entries = Vec::new();
+        // Each directory left to list, where it would end up, and whether a
+        // run descends into it.
+        let mut directories = vec![(root.to_path_buf(), root.to_path_buf(), true)];
+        while let Some((directory, moved_to, walked)) = directories.pop() {
+            let read_dir = match directory.read_dir() {
+                Ok(read_dir) => read_dir,
+                Err(e) => {
+                    println_stderr(format!("{:?}: {}", directory, e));
+                    continue;
+                }
+            };
+            for entry in read_dir.filter_map(Result::ok) {
+                let entry_path = entry.path();
+                if walked && is_junk(&entry_path, options) {
+                    continue;
+                }
+                let path = match renamed.get(entry_path.as_path()) {
+                    Some(name) => moved_to.join(name),
+                    None => moved_to.join(entry.file_name()),
+                };
+                let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+                if is_dir {
+                    let walks = walked && should_traverse(&entry) &&
+                                !options.artifacts.contains(&entry_path) &&
+                                !options.unwritable.contains(&entry_path) &&
+                                !is_unsettled(&entry_path, options) &&
+                                !is_too_deep(root, &entry_path, options);
+                    directories.push((entry_path.clone(), path.clone(), walks));
+                }
+                entries.push(ListedEntry { path, from: entry_path, is_dir });
+            }
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        VirtualListing { root: root.to_path_buf(), entries }
+    }
+
+    /// Render the listing as one path relative to the root per line, with
+    /// directories ending in `/`.
+    pub fn render_text(&self) -> String {
+        self.entries
+            .iter()
+            .map(|entry| {
+                let slash = if entry.is_dir { "/" } else { "" };
+                format!("{}{}\n", portable_path(&self.root, &entry.path), slash)
+            })
+            .collect()
+    }
+
+    /// Render the listing as a JSON object with its `schema_version` and
+    /// an array of objects giving each entry's path, where it is now, and
+    /// its type.
+    pub fn render_json(&self) -> serde_json::Value {
+        let entries: Vec<serde_json::Value> = self.entries
+            .iter()
+            .map(|entry| {
+                json!({
+                    "path": portable_path(&self.root, &entry.path),
+                    "from": portable_path(&self.root, &entry.from),
+                    "type": if entry.is_dir { "directory" } else { "file" },
+                })
+            })
+            .collect();
+        json!({"schema_version": LISTING_SCHEMA, "entries": entries})
+    }
+}
+
+/// Number of entries listed in each part of an analysis.
+const ANALYSIS_TOP: usize = 5;
+
+/// Read-only findings about the names a run would produce.
+#[derive(Debug, Default, PartialEq)]
+pub struct Analysis {
+    /// Number of entries that would be renamed.
+    pub renames: usize,
+    /// New names given to more than one entry anywhere in the tree, with
+    /// the entries given them.
+    pub collisions: BTreeMap<String, Vec<path::PathBuf>>,
+    /// The most deeply nested entries and their depth below the root.
+    pub deepest: Vec<(usize, path::PathBuf)>,
+    /// The longest new names and their length in characters.
+    pub longest: Vec<(usize, String)>,
+    /// How many entries sit at each depth below the root.
+    pub depths: BTreeMap<usize, usize>,
+    /// Mean length in characters of the new names.
+    pub mean_length: f64,
+    /// Groups of distinct new names that differ only by case, punctuation
+    /// or spacing, or by a single letter or digit beyond that.
+    pub similar: Vec<Vec<String>>,
+}
+
+impl Analysis {
+    /// Analyze the renames `plan()` came up with for `root`.
+    pub fn new(root: &path::Path, renames: &[(path::PathBuf, String)]) -> Analysis {
+        let mut analysis = Analysis { renames: renames.len(), ..Analysis::default() };
+        let mut by_name: BTreeMap<&str, Vec<path::PathBuf>> = BTreeMap::new();
+        let mut total_length = 0;
+        for (path, filename) in renames {
+            let depth = depth_within(root, path);
+            let length = filename.chars().count();
+            total_length += length;
+            by_name.entry(filename).or_default().push(path.clone());
+            *analysis.depths.entry(depth).or_default() += 1;
+            analysis.deepest.push((depth, path.clone()));
+            analysis.longest.push((length, filename.clone()));
+        }
+        analysis.collisions = by_name.into_iter()
+                                     .filter(|(_, paths)| paths.len() > 1)
+                                     .map(|(filename, paths)| (filename.to_string(), paths))
+                                     .collect();
+        analysis.deepest.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        analysis.deepest.truncate(ANALYSIS_TOP);
+        analysis.longest.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        analysis.longest.truncate(ANALYSIS_TOP);
+        if !renames.is_empty() {
+            analysis.mean_length = total_length as f64 / renames.len() as f64;
+        }
+        let names: Vec<&str> = renames.iter().map(|rename| rename.1.as_str()).collect();
+        analysis.similar = similar_names(&names);
+        analysis
+    }
+
+    /// Render the analysis as plain text, with paths relative to `root`.
+    pub fn render_text(&self, root: &path::Path) -> String {
+        let mut text = format!("renames      {}\nmean length  {:.1} characters\n",
+                               self.renames, self.mean_length);
+
+        text.push_str(&format!("\ncollisions ({}):\n", self.collisions.len()));
+        for (filename, paths) in &self.collisions {
+            text.push_str(&format!("  {}\n", filename));
+            for path in paths {
+                text.push_str(&format!("    {}\n", portable_path(root, path)));
+            }
+        }
+
+        text.push_str(&format!("\nsimilar names ({}):\n", self.similar.len()));
+        for cluster in &self.similar {
+            text.push_str(&format!("  {}\n", cluster.join("  |  ")));
+        }
+
+        text.push_str("\ndeepest:\n");
+        for (depth, path) in &self.deepest {
+            text.push_str(&format!("  {:>3}  {}\n", depth, portable_path(root, path)));
+        }
+
+        text.push_str("\nlongest names:\n");
+        for (length, filename) in &self.longest {
+            text.push_str(&format!("  {:>3}  {}\n", length, filename));
+        }
+
+        text.push_str("\nentries by depth:\n");
+        for (depth, count) in &self.depths {
+            text.push_str(&format!("  {:>3}  {}\n", depth, count));
+        }
+        text
+    }
+}
+
+/// Reduce `name` to what matters when comparing names for similarity:
+/// its letters and digits, lowercased.
+fn similarity_key(name: &str) -> String {
+    name.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+/// Group the distinct `names` whose similarity keys are equal or one edit
+/// apart, leaving out names without any similar name.
+///
+/// Keys one edit apart share a variant with a single character deleted,
+/// which avoids comparing every pair of names.
+pub fn similar_names(names: &[&str]) -> Vec<Vec<String>> {
+    let mut distinct: Vec<&str> = names.to_vec();
+    distinct.sort();
+    distinct.dedup();
+
+    let mut parents: Vec<usize> = (0..distinct.len()).collect();
+    fn find(parents: &mut [usize], mut index: usize) -> usize {
+        while parents[index] != index {
+            parents[index] = parents[parents[index]];
+            index = parents[index];
+        }
+        index
+    }
+
+    let mut variants: BTreeMap<String, usize> = BTreeMap::new();
+    for (index, name) in distinct.iter().enumerate() {
+        let key: Vec<char> = similarity_key(name).chars().collect();
+        let mut keys = vec![key.iter().collect::<String>()];
+        if key.len() > 1 {
+            for skip in 0..key.len() {
+                keys.push(key.iter()
+                             .enumerate()
+                             .filter(|&(position, _)| position != skip)
+                             .map(|(_, c)| c)
+                             .collect());
+            }
+        }
+        for variant in keys {
+            match variants.get(&variant) {
+                Some(&other) => {
+                    let (a, b) = (find(&mut parents, index), find(&mut parents, other));
+                    parents[a] = b;
+                }
+                None => {
+                    variants.insert(variant, index);
+                }
+            }
+        }
+    }
+
+    let mut clusters: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    for (index, name) in distinct.iter().enumerate() {
+        let root = find(&mut parents, index);
+        clusters.entry(root).or_default().push(name.to_string());
+    }
+    clusters.into_values().filter(|cluster| cluster.len() > 1).collect()
+}
+
+/// Estimate what flattening `directory` would do, adding to `totals`.
+///
+/// Only entries are counted and prefix lengths summed, so this is much
+/// faster than a real run; `prev_prefix_len` is the length of the prefix
+/// handed down from the parent directory. `options.defer_growing` and
+/// `options.scheme` are ignored since honouring them means waiting on
+/// or running something for every file.
+pub fn estimate(directory: &path::Path, prev_prefix_len: usize, options: &FlattenOptions,
+                totals: &mut Estimate) {
+    let prefix_len = if prev_prefix_len == 0 {
+        anchored_prefix(directory, options).map(|prefix| prefix.chars().count())
+    } else {
+        extended_prefix_len(directory, prev_prefix_len, options)
+    };
+    match prefix_len {
+        Ok(prefix_len) => estimate_directory(directory, prefix_len, 0, options, totals),
+        Err(message) => println_stderr(message),
+    }
+}
+
+/// Length of the prefix of `directory` when its parent's is `prev_prefix_len`
+/// characters long.
+fn extended_prefix_len(directory: &path::Path, prev_prefix_len: usize, options: &FlattenOptions)
+                       -> Result<usize, String> {
+    let segment = prefix_segment(&filename_text(directory, options)?, options);
+    let segment_len = new_prefix("", &segment, options).chars().count();
+    Ok(if prev_prefix_len == 0 {
+        segment_len
+    } else {
+        prev_prefix_len + options.separator().chars().count() + segment_len
+    })
+}
+
+/// Estimate `directory`, whose prefix is `prefix_len` characters long,
+/// while `open_handles` directory handles are open.
+fn estimate_directory(directory: &path::Path, prefix_len: usize, open_handles: usize,
+                      options: &FlattenOptions, totals: &mut Estimate) {
+    // Each directory being walked, with its prefix length and the handles
+    // open while it is.
+    let mut stack: Vec<(DirEntries, usize, usize)> = Vec::new();
+    stack.extend(open_estimate(directory, prefix_len, open_handles, options, totals));
+    while let Some((read_dir, prefix_len, open_handles)) = stack.last_mut() {
+        let entry = match read_dir.next() {
+            Some(Ok(entry)) => entry,
+            Some(Err(_)) => continue,
+            None => {
+                stack.pop();
+                continue;
+            }
+        };
+        let (prefix_len, open_handles) = (*prefix_len, *open_handles);
+        let entry_path = entry.path();
+        if options.artifacts.contains(&entry_path) {
+            continue;
+        }
+        if should_traverse(&entry) {
+            if is_unsettled(&entry_path, options) ||
+               is_too_deep(directory, &entry_path, options) ||
+               options.unwritable.contains(&entry_path) {
+                continue;
+            }
+            match extended_prefix_len(&entry_path, prefix_len, options) {
+                Ok(entry_prefix_len) => stack.extend(open_estimate(&entry_path, entry_prefix_len,
+                                                                   open_handles, options,
+                                                                   totals)),
+                Err(message) => println_stderr(message),
+            }
+        } else {
+            totals.files += 1;
+            if !is_hidden(&entry_path, options) && !is_junk(&entry_path, options) {
+                totals.renames += 1;
+                if prefix_len > 0 {
+                    totals.prefix_growth += prefix_len + options.separator().chars().count();
+                }
+            }
+        }
+    }
+}
+
+/// Start estimating `directory`, or `None` if it can't be read.
+fn open_estimate(directory: &path::Path, prefix_len: usize, open_handles: usize,
+                 options: &FlattenOptions, totals: &mut Estimate)
+                 -> Option<(DirEntries, usize, usize)> {
+    totals.directories += 1;
+    match read_dir_budgeted(directory, open_handles, options) {
+        Ok((read_dir, open_handles)) => Some((read_dir, prefix_len, open_handles)),
+        Err(e) => {
+            println_stderr(format!("{:?}: {}", directory, e));
+            None
+        }
+    }
+}
+
+/// Explain how `target` would be handled when flattening `root`.
+///
+/// Every returned line describes a rule that influenced the decision,
+/// with the final line stating the outcome.
+pub fn explain(root: &path::Path, target: &path::Path, options: &FlattenOptions)
+               -> Result<Vec<String>, String> {
+    let relative = match target.strip_prefix(root) {
+        Ok(relative) => relative,
+        Err(_) => return Err(format!("{:?} is not inside {:?}", target, root)),
+    };
+    let mut lines = Vec::new();
+
+    let root_name = filename_str(root)?;
+    let mut prefix = anchored_prefix(root, options)?;
+    lines.push(format!("root directory {:?} starts the prefix as {:?}", root_name, prefix));
+
+    let mut current = root.to_path_buf();
+    let components: Vec<_> = relative.iter().collect();
+    for (index, component) in components.iter().enumerate() {
+        current.push(component);
+        let name = match component.to_str() {
+            Some(name) => name,
+            None => return Err(format!("{:?} is not valid UTF-8", component)),
+        };
+        let is_dir = match fs::symlink_metadata(&current) {
+            Ok(metadata) => metadata.is_dir(),
+            Err(e) => return Err(format!("{:?}: {}", current, e)),
+        };
+        let is_last = index + 1 == components.len();
+        let leading_char = leading_char(&current);
+
+        if is_dir && leading_char != '.' && leading_char != '_' {
+            if options.unwritable.contains(&current) {
+                lines.push(format!("directory {:?} is on a mount that can't be written to, \
+                                    so it is skipped", name));
+                lines.push("result: skipped".to_string());
+                return Ok(lines);
+            }
+            if let Some(settle) = options.settle {
+                if !is_settled(&current, settle) {
+                    lines.push(format!("directory {:?} was modified within --settle {:?}, \
+                                        so it is skipped this run", name, settle));
+                    lines.push("result: skipped".to_string());
+                    return Ok(lines);
+                }
+            }
+            if let Some(max_depth) = options.max_depth.filter(|max| index + 1 >= *max) {
+                lines.push(format!("directory {:?} is {} levels down, deeper than --max-depth {}, \
+                                    so it is left alone", name, index + 1, max_depth));
+                lines.push("result: skipped".to_string());
+                return Ok(lines);
+            }
+            if leading_char == '+' || leading_char == '-' {
+                lines.push(format!("leading '{}' is stripped from directory {:?}",
+                                   leading_char, name));
+            }
+            prefix = extend_prefix(&prefix, name, options);
+            lines.push(format!("directory {:?} extends the prefix to {:?}", name, prefix));
+            continue;
+        }
+
+        if is_dir {
+            lines.push(format!("directory {:?} starts with '{}', so it is not traversed",
+                               name, leading_char));
+        } else if !is_last {
+            lines.push(format!("{:?} is not a directory, so it is not traversed", name));
+        }
+        if !is_last {
+            lines.push("result: skipped".to_string());
+        } else if is_junk(&current, options) {
+            let empty = fs::symlink_metadata(&current).is_ok_and(|metadata| metadata.len() == 0);
+            if options.delete_empty && empty {
+                lines.push(format!("{:?} is empty, so --delete-empty deletes it", name));
+            } else {
+                lines.push(format!("{:?} matches a junk pattern, so it is deleted", name));
+            }
+            lines.push("result: deleted".to_string());
+        } else if is_hidden(&current, options) {
+            lines.push(format!("{:?} starts with '.', so it is hidden and not renamed", name));
+            lines.push("result: skipped".to_string());
+        } else if options.defer_growing.is_some() && is_partial_download(&current) {
+            lines.push(format!("{:?} looks like an in-progress download", name));
+            lines.push("result: deferred".to_string());
+        } else if is_growing(&current, options) {
+            lines.push(format!("{:?} is still growing", name));
+            lines.push("result: deferred".to_string());
+        } else {
+            let filename = match options.scheme {
+                Some(ref scheme) => match scheme_filename(scheme, &current, &prefix, options)? {
+                    Some(filename) => {
+                        lines.push(format!("naming scheme {:?} picked the name {:?}",
+                                           scheme, filename));
+                        filename
+                    }
+                    None => {
+                        lines.push(format!("naming scheme {:?} asked to leave it alone",
+                                           scheme));
+                        lines.push("result: skipped".to_string());
+                        return Ok(lines);
+                    }
+                },
+                None => folded_filename(&prefix, name, options),
+            };
+            let filename = match options.target_profile {
+                Some(profile) => {
+                    let (adjusted, problems) = profile.apply(&filename, index + 1);
+                    if adjusted != filename {
+                        lines.push(format!("target profile {} adjusted {:?}",
+                                           profile.name(), filename));
+                    }
+                    for problem in problems {
+                        lines.push(format!("warning: {}", problem));
+                    }
+                    adjusted
+                }
+                None => filename,
+            };
+            lines.push(format!("result: renamed to {:?}", filename));
+        }
+        return Ok(lines);
+    }
+
+    lines.push(format!("result: traversed, with files given the prefix {:?}", prefix));
+    Ok(lines)
+}
+
+/// Work out the name the file at the hypothetical path `sample` would be
+/// given, without touching the filesystem.
+///
+/// The first component of `sample` is taken as the directory being
+/// flattened and the last as the file; `Err` explains why the file would
+/// be left alone. `Anchor::From` is treated like `Anchor::RootIncluded`
+/// as the sample's ancestors are unknown.
+pub fn try_path(sample: &path::Path, options: &FlattenOptions) -> Result<String, String> {
+    let components: Vec<&str> = match sample.iter().map(|c| c.to_str()).collect() {
+        Some(components) => components,
+        None => return Err(format!("{:?} is not valid UTF-8", sample)),
+    };
+    let (filename, directories) = match components.split_last() {
+        Some((filename, directories)) if !directories.is_empty() => (filename, directories),
+        _ => return Err(format!("{:?} needs a directory and a filename", sample)),
+    };
+    let mut prefix = String::new();
+    for (index, directory) in directories.iter().enumerate() {
+        if index == 0 {
+            prefix = match options.anchor {
+                Anchor::RootExcluded => String::new(),
+                _ => extend_prefix("", directory, options),
+            };
+            continue;
+        }
+        if directory.starts_with('.') || directory.starts_with('_') {
+            return Err(format!("{:?} is not traversed, so its contents are left alone",
+                               directory));
+        }
+        prefix = extend_prefix(&prefix, directory, options);
+    }
+    if is_hidden(path::Path::new(filename), options) {
+        return Err(format!("{:?} starts with '.', so it is left alone", filename));
+    }
+
+    let new_name = match options.scheme {
+        Some(ref scheme) => match scheme_filename(scheme, sample, &prefix, options)? {
+            Some(new_name) => new_name,
+            None => return Err(format!("naming scheme {:?} left {:?} alone", scheme, filename)),
+        },
+        None => folded_filename(&prefix, filename, options),
+    };
+    Ok(match options.target_profile {
+        Some(profile) => {
+            let (new_name, problems) = profile.apply(&new_name, directories.len());
+            for problem in problems {
+                println_stderr(format!("warning: {}", problem));
+            }
+            new_name
+        }
+        None => new_name,
+    })
+}
+
+/// Make `path` absolute without resolving a symlink in its final component.
+pub fn absolute_path(path: &path::Path) -> std::io::Result<path::PathBuf> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(filename)) => {
+            let parent = if parent.as_os_str().is_empty() {
+                path::Path::new(".")
+            } else {
+                parent
+            };
+            Ok(parent.canonicalize()?.join(filename))
+        }
+        _ => path.canonicalize(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+    use std::path;
+
+    extern crate tempdir;
+
+    #[test]
+    fn leading_char_for_filename() {
+        let mut path = path::PathBuf::new();
+        path.push("/tmp");
+        path.push("file.txt");
+        assert_eq!(leading_char(&path), 'f');
+    }
+
+    #[test]
+    fn should_traverse_not_dir() {
+        // Create a temporary directory.
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+
+        // Create a file.
+        let tmp_dir_path = tmp_dir.path();
+        let mut path_buf = tmp_dir_path.to_path_buf();
+        path_buf.push("file.txt");
+        let f = fs::File::create(&path_buf);
+        if f.is_err() {
+            return;
+        }
+        let f = f.unwrap();
+        // Flush the file.
+        if f.sync_all().is_err() {
+            return;
+        }
+
+        // Get the temporary directory's content.
+        let read_dir = path_buf.read_dir();
+        if read_dir.is_err() {
+            return;
+        }
+        let entry_item = read_dir.unwrap().last();
+        let entry_option = entry_item.unwrap();
+        let entry = entry_option.unwrap();
+
+        assert!(!should_traverse(&entry));
+    }
+
+    #[test]
+    fn should_traverse_not_leading_dot_or_underscore() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+
+        let dir_builder = fs::DirBuilder::new();
+        let tmp_dir_path = tmp_dir.path();
+        let mut path_buf = tmp_dir_path.to_path_buf();
+        path_buf.push(".directory");
+        if dir_builder.create(path_buf.as_path()).is_err() {
+            return;
+        } else {
+            path_buf.pop();
+        }
+
+        path_buf.push("_directory");
+        if dir_builder.create(path_buf.as_path()).is_err() {
+            return;
+        } else {
+            path_buf.pop();
+        }
+
+        // Get the temporary directory's content.
+        let read_dir = path_buf.read_dir();
+        if read_dir.is_err() {
+            return;
+        }
+
+        let mut count = 0;
+        for entry in read_dir.unwrap() {
+            assert!(!should_traverse(&entry.unwrap()));
+            count += 1;
+        }
+        assert_eq!(2, count);
+    }
+
+    #[test]
+    fn should_traverse_directory() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+
+        let dir_builder = fs::DirBuilder::new();
+        let tmp_dir_path = tmp_dir.path();
+        let mut path_buf = tmp_dir_path.to_path_buf();
+        path_buf.push("directory");
+        if dir_builder.create(path_buf.as_path()).is_err() {
+            return;
+        } else {
+            path_buf.pop();
+        }
+
+        // Get the temporary directory's content.
+        let read_dir = path_buf.read_dir();
+        if read_dir.is_err() {
+            return;
+        }
+
+        let mut count = 0;
+        for entry in read_dir.unwrap() {
+            assert!(should_traverse(&entry.unwrap()));
+            count += 1;
+        }
+        assert_eq!(1, count);
+    }
+
+    #[test]
+    fn new_prefix_empty_old_prefix() {
+        assert_eq!("tail", new_prefix("", "tail", &FlattenOptions::default()));
+    }
+
+    #[test]
+    fn new_prefix_leading_dash_or_plus() {
+        assert_eq!("a - b", new_prefix("a", "-b", &FlattenOptions::default()));
+        assert_eq!("a - b", new_prefix("a", "+b", &FlattenOptions::default()));
+    }
+
+    #[test]
+    fn new_prefix_works() {
+        assert_eq!("a - b", new_prefix("a", "B", &FlattenOptions::default()));
+        assert_eq!("a - b - c", new_prefix("a - b", "C", &FlattenOptions::default()));
+    }
+
+    #[test]
+    fn rename_skips_dot_files() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+
+        // Create a file.
+        let tmp_dir_path = tmp_dir.path();
+        let mut path_buf = tmp_dir_path.to_path_buf();
+        path_buf.push(".file");
+        let f = fs::File::create(&path_buf);
+        if f.is_err() {
+            return;
+        }
+        let f = f.unwrap();
+        // Flush the file.
+        if f.sync_all().is_err() {
+            return;
+        }
+
+        rename(&path_buf, "prefix").unwrap();
+        assert!(path_buf.exists());
+    }
+
+    #[test]
+    fn rename_works() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+
+        // Create a file.
+        let tmp_dir_path = tmp_dir.path();
+        let mut path_buf = tmp_dir_path.to_path_buf();
+        path_buf.push("d");
+        let f = fs::File::create(&path_buf);
+        if f.is_err() {
+            return;
+        }
+        let f = f.unwrap();
+        // Flush the file.
+        if f.sync_all().is_err() {
+            return;
+        }
+
+        rename(&path_buf, "a - b - c").unwrap();
+        path_buf.pop();
+        path_buf.push("a - b - c - d");
+        assert!(path_buf.exists());
+    }
+
+    #[test]
+    fn flatten_works() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let tmp_dir_path = tmp_dir.path();
+        let mut path_buf = tmp_dir_path.to_path_buf();
+        let dir_builder = fs::DirBuilder::new();
+
+        path_buf.push("A");
+        if dir_builder.create(path_buf.as_path()).is_err() {
+            return;
+        }
+
+        // A/_skipped/skipped -> None
+        path_buf.push("_skipped");
+        if dir_builder.create(path_buf.as_path()).is_err() {
+            return;
+        } else {
+            path_buf.push("skipped");
+            let f = fs::File::create(&path_buf);
+            if f.is_err() {
+                return;
+            }
+            let f = f.unwrap();
+            // Flush the file.
+            if f.sync_all().is_err() {
+                return;
+            } else {
+                path_buf.pop();
+            }
+
+            path_buf.pop();
+        }
+
+        // A/-B/C -> A - B - C
+        path_buf.push("-B");
+        if dir_builder.create(path_buf.as_path()).is_err() {
+            return;
+        } else {
+            path_buf.push("C");
+            let f = fs::File::create(&path_buf);
+            if f.is_err() {
+                return;
+            }
+            let f = f.unwrap();
+            // Flush the file.
+            if f.sync_all().is_err() {
+                return;
+            } else {
+                path_buf.pop();
+            }
+
+            path_buf.pop();
+        }
+
+        // A/.skipped/skipped -> None
+        path_buf.push(".skipped");
+        if dir_builder.create(path_buf.as_path()).is_err() {
+            return;
+        } else {
+            path_buf.push("skipped");
+            let f = fs::File::create(&path_buf);
+            if f.is_err() {
+                return;
+            }
+            let f = f.unwrap();
+            // Flush the file.
+            if f.sync_all().is_err() {
+                return;
+            } else {
+                path_buf.pop();
+            }
+
+            path_buf.pop();
+        }
+
+        // A/+D/E -> A - D - E
+        path_buf.push("+D");
+        if dir_builder.create(path_buf.as_path()).is_err() {
+            return;
+        } else {
+            path_buf.push("E");
+            let f = fs::File::create(&path_buf);
+            if f.is_err() {
+                return;
+            }
+            let f = f.unwrap();
+            // Flush the file.
+            if f.sync_all().is_err() {
+                return;
+            } else {
+                path_buf.pop();
+            }
+
+            path_buf.pop();
+        }
+
+        // A/.skipped -> None
+        path_buf.push(".skipped");
+        let f = fs::File::create(&path_buf);
+        if f.is_err() {
+            return;
+        }
+        let f = f.unwrap();
+        // Flush the file.
+        if f.sync_all().is_err() {
+            return;
+        } else {
+            path_buf.pop();
+        }
+
+        // A/F -> A - F
+        path_buf.push("F");
+        let f = fs::File::create(&path_buf);
+        if f.is_err() {
+            return;
+        }
+        let f = f.unwrap();
+        // Flush the file.
+        if f.sync_all().is_err() {
+            return;
+        } else {
+            path_buf.pop();
+        }
+
+        // A/G/H -> A - G - H
+        path_buf.push("G");
+        if dir_builder.create(path_buf.as_path()).is_err() {
+            return;
+        } else {
+            path_buf.push("H");
+            let f = fs::File::create(&path_buf);
+            if f.is_err() {
+                return;
+            }
+            let f = f.unwrap();
+            // Flush the file.
+            if f.sync_all().is_err() {
+                return;
+            } else {
+                path_buf.pop();
+            }
+
+            path_buf.pop();
+        }
+
+        flatten(&path_buf, "", &FlattenOptions::default(), &mut Report::new(&path_buf)).unwrap();
+
+        // A/_skipped/skipped -> None
+        path_buf.push("_skipped");
+        path_buf.push("skipped");
+        assert!(path_buf.exists());
+        path_buf.pop();
+        path_buf.pop();
+        // A/-B/C -> A - B - C
+        path_buf.push("-B");
+        path_buf.push("a - b - c");
+        assert!(path_buf.exists());
+        path_buf.pop();
+        path_buf.pop();
+        // A/.skipped/skipped -> None
+        path_buf.push(".skipped");
+        path_buf.push("skipped");
+        assert!(path_buf.exists());
+        path_buf.pop();
+        path_buf.pop();
+        // A/+D/E -> A - D - E
+        path_buf.push("+D");
+        path_buf.push("a - d - e");
+        assert!(path_buf.exists());
+        path_buf.pop();
+        path_buf.pop();
+        // A/.skipped -> None
+        path_buf.push(".skipped");
+        assert!(path_buf.exists());
+        path_buf.pop();
+        // A/F -> A - F
+        path_buf.push("a - f");
+        assert!(path_buf.exists());
+        path_buf.pop();
+        // A/G/H -> A - G - H
+        path_buf.push("G");
+        path_buf.push("a - g - h");
+        assert!(path_buf.exists());
+
+        path_buf.pop();
+
+        // -I/J -> I - J
+        path_buf.push("-I");
+        if dir_builder.create(path_buf.as_path()).is_err() {
+            return;
+        } else {
+            path_buf.push("J");
+            let f = fs::File::create(&path_buf);
+            if f.is_err() {
+                return;
+            }
+            let f = f.unwrap();
+            // Flush the file.
+            if f.sync_all().is_err() {
+                return;
+            } else {
+                path_buf.pop();
+            }
+        }
+
+        flatten(&path_buf, "", &FlattenOptions::default(), &mut Report::new(&path_buf)).unwrap();
+
+        path_buf.push("i - j");
+        assert!(path_buf.exists());
+
+
+    }
+
+    #[test]
+    fn new_filename_works() {
+        let options = FlattenOptions::default();
+        assert_eq!("a - b - c.txt", new_filename("a - b", "C.txt", &options));
+    }
+
+    #[test]
+    fn explain_renamed_file() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let mut path_buf = tmp_dir.path().to_path_buf();
+        path_buf.push("A");
+        let root = path_buf.clone();
+        path_buf.push("+B");
+        if fs::DirBuilder::new().recursive(true).create(&path_buf).is_err() {
+            return;
+        }
+        path_buf.push("C");
+        if fs::File::create(&path_buf).is_err() {
+            return;
+        }
+
+        let lines = explain(&root, &path_buf, &FlattenOptions::default()).unwrap();
+        assert!(lines.iter().any(|line| line.contains("leading '+'")));
+        assert_eq!(Some(&"result: renamed to \"a - b - c\"".to_string()),
+                   lines.last());
+    }
+
+    #[test]
+    fn explain_skipped_directory() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let mut path_buf = tmp_dir.path().to_path_buf();
+        path_buf.push("A");
+        let root = path_buf.clone();
+        path_buf.push("_skipped");
+        if fs::DirBuilder::new().recursive(true).create(&path_buf).is_err() {
+            return;
+        }
+        path_buf.push("file");
+        if fs::File::create(&path_buf).is_err() {
+            return;
+        }
+
+        let lines = explain(&root, &path_buf, &FlattenOptions::default()).unwrap();
+        assert!(lines.iter().any(|line| line.contains("starts with '_'")));
+        assert_eq!(Some(&"result: skipped".to_string()), lines.last());
+
+        // The directory itself is renamed as a whole.
+        path_buf.pop();
+        let lines = explain(&root, &path_buf, &FlattenOptions::default()).unwrap();
+        assert_eq!(Some(&"result: renamed to \"a - _skipped\"".to_string()),
+                   lines.last());
+    }
+
+    #[test]
+    fn explain_junk() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir_all(&root).is_err() ||
+           fs::File::create(root.join("empty")).is_err() ||
+           fs::write(root.join(".DS_Store"), "junk").is_err() {
+            return;
+        }
+
+        let options = FlattenOptions { delete_empty: true,
+                                       junk: vec![".DS_Store".to_string()],
+                                       ..FlattenOptions::default() };
+        let lines = explain(&root, &root.join("empty"), &options).unwrap();
+        assert!(lines.iter().any(|line| line.contains("--delete-empty")));
+        assert_eq!(Some(&"result: deleted".to_string()), lines.last());
+        let lines = explain(&root, &root.join(".DS_Store"), &options).unwrap();
+        assert_eq!(Some(&"result: deleted".to_string()), lines.last());
+
+        let lines = explain(&root, &root.join("empty"), &FlattenOptions::default()).unwrap();
+        assert_eq!(Some(&"result: renamed to \"a - empty\"".to_string()), lines.last());
+    }
+
+    #[test]
+    fn plan_skips_unwritable_mounts() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir_all(root.join("b")).is_err() ||
+           fs::File::create(root.join("c")).is_err() ||
+           fs::File::create(root.join("b").join("c")).is_err() {
+            return;
+        }
+
+        let options = FlattenOptions { unwritable: vec![root.join("b")],
+                                       ..FlattenOptions::default() };
+        let mut report = Report::new(&root);
+        let renames = plan(&root, &options, &mut report).unwrap();
+        assert_eq!(vec![(root.join("c"), "a - c".to_string())], renames);
+        assert_eq!(vec![(root.join("b"), SkipReason::ReadOnly)], report.skips);
+        let lines = explain(&root, &root.join("b").join("c"), &options).unwrap();
+        assert_eq!(Some(&"result: skipped".to_string()), lines.last());
+
+        let mut totals = Estimate::default();
+        estimate(&root, 0, &options, &mut totals);
+        assert_eq!(Estimate { directories: 1, files: 1, renames: 1, prefix_growth: 4 }, totals);
+        let graph = visualize(&root, &options);
+        assert!(!graph.nodes.contains(&("a - b - c".to_string(), false)));
+    }
+
+    #[test]
+    fn plan_skips_artifacts() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir_all(&root).is_err() ||
+           fs::File::create(root.join("b")).is_err() ||
+           fs::File::create(root.join("report.json")).is_err() {
+            return;
+        }
+
+        let options = FlattenOptions { artifacts: vec![root.join("report.json")],
+                                       ..FlattenOptions::default() };
+        let mut report = Report::new(&root);
+        let renames = plan(&root, &options, &mut report).unwrap();
+        assert_eq!(vec![(root.join("b"), "a - b".to_string())], renames);
+        assert_eq!(vec![(root.join("report.json"), SkipReason::Artifact)], report.skips);
+    }
+
+    #[test]
+    fn explain_outside_root() {
+        let root = path::Path::new("/tmp/a");
+        let target = path::Path::new("/tmp/b/c");
+        assert!(explain(root, target, &FlattenOptions::default()).is_err());
+    }
+
+    #[test]
+    fn try_path_works() {
+        let options = FlattenOptions::default();
+        let sample = path::Path::new("Music/+The Beatles/Abbey Road/01 Come Together.flac");
+        assert_eq!(Ok("music - the beatles - abbey road - 01 come together.flac".to_string()),
+                   try_path(sample, &options));
+        assert!(try_path(path::Path::new("Music/_Singles/song.mp3"), &options).is_err());
+        assert!(try_path(path::Path::new("Music/.song.mp3"), &options).is_err());
+        assert!(try_path(path::Path::new("song.mp3"), &options).is_err());
+
+        let options = FlattenOptions {
+            target_profile: Some(TargetProfile::Fat),
+            ..FlattenOptions::default()
+        };
+        assert_eq!(Ok("music - what_.mp3".to_string()),
+                   try_path(path::Path::new("Music/what?.mp3"), &options));
+    }
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(time::Duration::from_secs(30), parse_duration("30").unwrap());
+        assert_eq!(time::Duration::from_secs(30), parse_duration("30s").unwrap());
+        assert_eq!(time::Duration::from_secs(15 * 60), parse_duration("15m").unwrap());
+        assert_eq!(time::Duration::from_secs(2 * 60 * 60), parse_duration("2h").unwrap());
+        assert_eq!(time::Duration::from_secs(24 * 60 * 60), parse_duration("1d").unwrap());
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("999999999999999999d").is_err());
+    }
+
+    #[test]
+    fn is_settled_recent_directory() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+
+        assert!(is_settled(tmp_dir.path(), time::Duration::from_secs(0)));
+        assert!(!is_settled(tmp_dir.path(), time::Duration::from_secs(60 * 60)));
+    }
+
+    #[test]
+    fn flatten_skips_unsettled_directory() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let mut path_buf = tmp_dir.path().to_path_buf();
+        path_buf.push("A");
+        path_buf.push("B");
+        if fs::DirBuilder::new().recursive(true).create(&path_buf).is_err() {
+            return;
+        }
+        path_buf.push("C");
+        if fs::File::create(&path_buf).is_err() {
+            return;
+        }
+        path_buf.pop();
+        path_buf.pop();
+
+        let options = FlattenOptions {
+            settle: Some(time::Duration::from_secs(60 * 60)),
+            ..FlattenOptions::default()
+        };
+        flatten(&path_buf, "", &options, &mut Report::new(&path_buf)).unwrap();
+
+        path_buf.push("B");
+        path_buf.push("C");
+        assert!(path_buf.exists());
+    }
+
+    #[test]
+    fn is_partial_download_extensions() {
+        assert!(is_partial_download(path::Path::new("/tmp/movie.mkv.part")));
+        assert!(is_partial_download(path::Path::new("/tmp/setup.exe.CRDOWNLOAD")));
+        assert!(!is_partial_download(path::Path::new("/tmp/movie.mkv")));
+        assert!(!is_partial_download(path::Path::new("/tmp/part")));
+    }
+
+    #[test]
+    fn flatten_defers_partial_downloads() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let mut path_buf = tmp_dir.path().to_path_buf();
+        path_buf.push("A");
+        if fs::DirBuilder::new().create(&path_buf).is_err() {
+            return;
+        }
+        path_buf.push("B.part");
+        if fs::File::create(&path_buf).is_err() {
+            return;
+        }
+        path_buf.pop();
+
+        let options = FlattenOptions {
+            defer_growing: Some(time::Duration::from_millis(1)),
+            ..FlattenOptions::default()
+        };
+        let mut report = Report::new(&path_buf);
+        report.quiet = true;
+        flatten(&path_buf, "", &options, &mut report).unwrap();
+
+        path_buf.push("B.part");
+        assert!(path_buf.exists());
+        assert_eq!(vec![format!("deferred (still being written): {:?}", path_buf)],
+                   report.warnings);
+    }
+
+    #[test]
+    fn growing_files_unchanged() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let mut path_buf = tmp_dir.path().to_path_buf();
+        path_buf.push("file");
+        if fs::File::create(&path_buf).is_err() {
+            return;
+        }
+
+        assert!(growing_files(tmp_dir.path(), time::Duration::from_millis(1)).is_empty());
+    }
+
+    #[test]
+    fn is_valid_filename_works() {
+        assert!(is_valid_filename("a - b.txt"));
+        assert!(!is_valid_filename(""));
+        assert!(!is_valid_filename(".."));
+        assert!(!is_valid_filename("a/b"));
+        assert!(!is_valid_filename("/a"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scheme_filename_works() {
+        use std::ffi::OsStr;
+        use std::io::Write;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let mut scheme = tmp_dir.path().to_path_buf();
+        scheme.push("flatten-scheme-test");
+        {
+            let f = fs::File::create(&scheme);
+            if f.is_err() {
+                return;
+            }
+            let mut f = f.unwrap();
+            let script = format!("#!/bin/sh\ncat > {:?}\necho '{{\"name\": \"new\"}}'\n",
+                                 tmp_dir.path().join("request"));
+            if f.write_all(script.as_bytes()).is_err() {
+                return;
+            }
+        }
+        if fs::set_permissions(&scheme, fs::Permissions::from_mode(0o755)).is_err() {
+            return;
+        }
+
+        let path = path::Path::new("/tmp/a/b");
+        let options = FlattenOptions::default();
+        assert_eq!(Some("new".to_string()),
+                   scheme_filename(&scheme, path, "a", &options).unwrap());
+
+        // A path that isn't UTF-8 is still sent, with its bad bytes replaced.
+        let path = path::Path::new(OsStr::from_bytes(b"/tmp/\xff/b"));
+        assert_eq!(Some("new".to_string()),
+                   scheme_filename(&scheme, path, "a", &options).unwrap());
+        let request = fs::read(tmp_dir.path().join("request")).unwrap();
+        let request: serde_json::Value = serde_json::from_slice(&request).unwrap();
+        assert_eq!(json!("/tmp/\u{fffd}/b"), request["path"]);
+    }
+
+    #[test]
+    fn report_records_subtrees() {
+        let root = path::Path::new("/tmp/a");
+        let mut report = Report::new(root);
+        report.record(&root.join("file"), Outcome::Renamed);
+        report.record(&root.join("b").join("file"), Outcome::Renamed);
+        report.record(&root.join("b").join("c").join(".file"),
+                      Outcome::Skipped(SkipReason::DotFile));
+
+        report.record(&root.join("b").join("locked"),
+                      Outcome::Skipped(SkipReason::PermissionDenied));
+
+        assert_eq!(Counts { renamed: 2, skipped: 2, failed: 1, deferred: 0 }, report.totals);
+        assert_eq!(Some(&Counts { renamed: 1, skipped: 0, failed: 0, deferred: 0 }),
+                   report.subtrees.get("."));
+        assert_eq!(Some(&Counts { renamed: 1, skipped: 2, failed: 1, deferred: 0 }),
+                   report.subtrees.get("b"));
+        assert_eq!(vec![(root.join("b").join("c").join(".file"), SkipReason::DotFile),
+                        (root.join("b").join("locked"), SkipReason::PermissionDenied)],
+                   report.skips);
+    }
+
+    #[test]
+    fn report_render_text() {
+        let root = path::Path::new("/tmp/a");
+        let mut report = Report::new(root);
+        report.record(&root.join("b").join("file"), Outcome::Renamed);
+        report.warnings.push("something odd".to_string());
+
+        let text = report.render_text();
+        assert!(text.contains("renamed   1\n"));
+        assert!(text.contains("subtree  renamed  skipped  failed  deferred\n"));
+        assert!(text.contains("b              1        0       0         0\n"));
+        assert!(text.contains("  something odd\n"));
+    }
+
+    #[test]
+    fn report_render_json() {
+        let root = path::Path::new("/tmp/a");
+        let mut report = Report::new(root);
+        report.record_rename(&root.join("b").join("file"),
+                             &root.join("b").join("a - b - file"));
+        report.record(&root.join(".file"), Outcome::Skipped(SkipReason::DotFile));
+
+        let json = report.render_json();
+        assert_eq!("/tmp/a", json["root"]);
+        assert_eq!(1, json["renamed"]);
+        assert_eq!("b/file", json["renames"][0]["from"]);
+        assert_eq!("b/a - b - file", json["renames"][0]["to"]);
+        assert_eq!(".file", json["skips"][0]["path"]);
+        assert_eq!("dot_file", json["skips"][0]["reason"]);
+        assert_eq!(0, json["failures"].as_array().unwrap().len());
+
+        let locked = root.join("b").join("locked");
+        let e = io::Error::new(io::ErrorKind::PermissionDenied, "permission denied");
+        report.record_failure(&locked, &e, false);
+        let json = report.render_json();
+        assert_eq!("b/locked", json["failures"][0]["path"]);
+        assert_eq!("permission denied", json["failures"][0]["error"]);
+        assert_eq!(true, json["stopped"]);
+    }
+
+    #[test]
+    fn report_manifest() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        let manifest_path = tmp_dir.path().join("manifest.csv");
+        if fs::create_dir_all(&root).is_err() || fs::File::create(root.join("b,c")).is_err() {
+            return;
+        }
+        let manifest = match fs::File::create(&manifest_path) {
+            Ok(manifest) => manifest,
+            Err(_) => return,
+        };
+
+        let mut report = Report::new(&root);
+        report.start_manifest(Box::new(manifest)).unwrap();
+        flatten(&root, "", &FlattenOptions::default(), &mut report).unwrap();
+        report.record_failure(&root.join("d"), &io::Error::other("busy"), true);
+        drop(report);
+        let contents = fs::read_to_string(&manifest_path).unwrap();
+        let rows: Vec<&str> = contents.lines().collect();
+        assert_eq!(3, rows.len());
+        assert_eq!(MANIFEST_HEADER, rows[0]);
+        let renamed = format!("{},{},", csv_field(&root.join("b,c").to_string_lossy()),
+                              csv_field(&root.join("a - b,c").to_string_lossy()));
+        assert!(rows[1].starts_with(&renamed));
+        assert!(rows[1].ends_with("Z,renamed,1"));
+        assert!(rows[2].starts_with(&format!("{},,", root.join("d").display())));
+        assert!(rows[2].ends_with(",failed,1"));
+    }
+
+    #[test]
+    fn manifest_fields() {
+        assert_eq!("a b", csv_field("a b"));
+        assert_eq!("\"a,b\"", csv_field("a,b"));
+        assert_eq!("\"a \"\"b\"\"\"", csv_field("a \"b\""));
+        assert_eq!("1970-01-01T00:00:00Z", utc_timestamp(time::UNIX_EPOCH));
+        assert_eq!("2000-02-29T23:59:59Z",
+                   utc_timestamp(time::UNIX_EPOCH + time::Duration::from_secs(951_868_799)));
+        assert_eq!("2026-10-16T12:00:00Z",
+                   utc_timestamp(time::UNIX_EPOCH + time::Duration::from_secs(1_792_152_000)));
+    }
+
+    #[test]
+    fn report_events() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        let events_path = tmp_dir.path().join("events");
+        if fs::create_dir_all(&root).is_err() ||
+           fs::File::create(root.join("b")).is_err() ||
+           fs::File::create(root.join(".c")).is_err() {
+            return;
+        }
+        let events = match fs::File::create(&events_path) {
+            Ok(events) => events,
+            Err(_) => return,
+        };
+
+        let mut report = Report::new(&root);
+        report.events = Some(Box::new(events));
+        flatten(&root, "", &FlattenOptions::default(), &mut report).unwrap();
+        report.finish();
+        drop(report);
+        let contents = fs::read_to_string(&events_path).unwrap();
+        let events: Vec<serde_json::Value> = contents.lines()
+                                                     .map(|line| {
+                                                         serde_json::from_str(line).unwrap()
+                                                     })
+                                                     .collect();
+        assert_eq!(4, events.len());
+        assert_eq!(json!({"event": "scan", "path": "", "schema_version": 1}), events[0]);
+        assert!(events.contains(&json!({"event": "rename", "from": "b", "to": "a - b",
+                                        "schema_version": 1})));
+        assert!(events.contains(&json!({"event": "skip", "path": ".c",
+                                        "reason": "dot_file", "schema_version": 1})));
+        assert_eq!("done", events[3]["event"]);
+        assert_eq!(1, events[3]["renamed"]);
+    }
+
+    #[test]
+    fn report_render_prometheus() {
+        let root = path::Path::new("/tmp/a");
+        let mut report = Report::new(root);
+        report.record_rename(&root.join("file"), &root.join("a - file"));
+        report.record(&root.join(".file"), Outcome::Skipped(SkipReason::DotFile));
+        report.record(&root.join(".other"), Outcome::Skipped(SkipReason::DotFile));
+
+        let metrics = report.render_prometheus();
+        assert!(metrics.contains("# TYPE flatten_filenames_last_run_renamed gauge\n\
+                                  flatten_filenames_last_run_renamed 1\n"));
+        assert!(metrics.contains("\nflatten_filenames_last_run_skipped{reason=\"dot_file\"} 2\n"));
+        assert!(metrics.contains("\nflatten_filenames_last_run_deferred 0\n"));
+        assert!(metrics.contains("\nflatten_filenames_last_run_timestamp_seconds "));
+    }
+
+    #[test]
+    fn report_render_html() {
+        let root = path::Path::new("/tmp/a");
+        let mut report = Report::new(root);
+        report.record_rename(&root.join("b").join("<file>"),
+                             &root.join("b").join("a - b - <file>"));
+        report.record(&root.join(".file"), Outcome::Skipped(SkipReason::DotFile));
+
+        let html = report.render_html();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<summary>b (1)</summary>"));
+        assert!(html.contains("<tr><td>&lt;file&gt;</td><td>renamed</td>\
+                               <td>a - b - &lt;file&gt;</td></tr>"));
+        assert!(html.contains("<summary>. (1)</summary>"));
+        assert!(html.contains("<tr><td>.file</td><td>skipped</td><td>dot_file</td></tr>"));
+    }
+
+    #[test]
+    fn estimate_works() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let mut path_buf = tmp_dir.path().to_path_buf();
+        path_buf.push("A");
+        path_buf.push("+B");
+        if fs::DirBuilder::new().recursive(true).create(&path_buf).is_err() {
+            return;
+        }
+        for filename in &["c", ".d"] {
+            path_buf.push(filename);
+            if fs::File::create(&path_buf).is_err() {
+                return;
+            }
+            path_buf.pop();
+        }
+        path_buf.pop();
+
+        let mut totals = Estimate::default();
+        estimate(&path_buf, 0, &FlattenOptions::default(), &mut totals);
+        // "a - b - c" adds "a - b - " to "c".
+        assert_eq!(Estimate { directories: 2, files: 2, renames: 1, prefix_growth: 8 },
+                   totals);
+
+        path_buf.push("+B");
+        path_buf.push("c");
+        assert!(path_buf.exists());
+
+        // Junk is deleted rather than renamed, so it adds nothing.
+        path_buf.set_file_name("Thumbs.db");
+        if fs::File::create(&path_buf).is_err() {
+            return;
+        }
+        path_buf.pop();
+        path_buf.pop();
+        let options = FlattenOptions { junk: vec!["Thumbs.db".to_string()],
+                                       ..FlattenOptions::default() };
+        let mut totals = Estimate::default();
+        estimate(&path_buf, 0, &options, &mut totals);
+        assert_eq!(Estimate { directories: 2, files: 3, renames: 1, prefix_growth: 8 },
+                   totals);
+    }
+
+    #[test]
+    fn analyze_works() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir_all(root.join("b").join("c")).is_err() ||
+           fs::File::create(root.join("b").join("c").join("d")).is_err() ||
+           fs::File::create(root.join("B - c - d")).is_err() ||
+           fs::File::create(root.join(".e")).is_err() {
+            return;
+        }
+
+        let mut report = Report::new(&root);
+        let renames = plan(&root, &FlattenOptions::default(), &mut report).unwrap();
+        assert_eq!(vec![(root.join("B - c - d"), "a - b - c - d".to_string()),
+                        (root.join("b").join("c").join("d"), "a - b - c - d".to_string())],
+                   renames);
+        assert_eq!(1, report.totals.skipped);
+        assert!(root.join("b").join("c").join("d").exists());
+
+        let analysis = Analysis::new(&root, &renames);
+        assert_eq!(2, analysis.renames);
+        assert_eq!(Some(&vec![root.join("B - c - d"), root.join("b").join("c").join("d")]),
+                   analysis.collisions.get("a - b - c - d"));
+        assert_eq!((3, root.join("b").join("c").join("d")), analysis.deepest[0]);
+        assert_eq!(Some(&1), analysis.depths.get(&1));
+        assert_eq!(13.0, analysis.mean_length);
+        assert!(analysis.render_text(&root).contains("    b/c/d\n"));
+    }
+
+    #[test]
+    fn virtual_listing() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir_all(root.join("b")).is_err() ||
+           fs::create_dir_all(root.join("_d")).is_err() ||
+           fs::File::create(root.join("e")).is_err() ||
+           fs::File::create(root.join("b").join("g")).is_err() ||
+           fs::write(root.join("b").join("Thumbs.db"), "junk").is_err() ||
+           fs::File::create(root.join("_d").join("f")).is_err() ||
+           fs::write(root.join("_d").join("Thumbs.db"), "junk").is_err() {
+            return;
+        }
+
+        let options = FlattenOptions { junk: vec!["Thumbs.db".to_string()],
+                                       ..FlattenOptions::default() };
+        let renames = plan(&root, &options, &mut Report::new(&root)).unwrap();
+        let listing = VirtualListing::new(&root, &options, &renames);
+        // Junk is only deleted from directories a run descends into.
+        assert_eq!("a - _d/\na - _d/Thumbs.db\na - _d/f\na - e\nb/\nb/a - b - g\n",
+                   listing.render_text());
+        assert_eq!(json!({"path": "a - _d/f", "from": "_d/f", "type": "file"}),
+                   listing.render_json()["entries"][2]);
+        assert!(root.join("b").join("g").exists());
+    }
+
+    #[test]
+    fn similar_names_works() {
+        let names = ["a - the beatles - 01.mp3", "a - the-beatles - 01.mp3",
+                     "a - the beatle - 01.mp3", "a - the beatles - 01.mp3",
+                     "a - queen - 01.mp3", "a - queen - 02.mp3", "b"];
+        assert_eq!(vec![vec!["a - queen - 01.mp3".to_string(), "a - queen - 02.mp3".to_string()],
+                        vec!["a - the beatle - 01.mp3".to_string(),
+                             "a - the beatles - 01.mp3".to_string(),
+                             "a - the-beatles - 01.mp3".to_string()]],
+                   similar_names(&names));
+    }
+
+    #[test]
+    fn visualize_works() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir_all(root.join("b")).is_err() ||
+           fs::File::create(root.join("b").join("c")).is_err() ||
+           fs::File::create(root.join(".d")).is_err() {
+            return;
+        }
+
+        let graph = visualize(&root, &FlattenOptions::default());
+        assert_eq!(vec![("a".to_string(), true),
+                        (".d".to_string(), false),
+                        ("b".to_string(), true),
+                        ("c".to_string(), false),
+                        ("a - b - c".to_string(), false)],
+                   graph.nodes);
+        assert_eq!(vec![(0, 1, false), (0, 2, false), (2, 3, false), (3, 4, true)],
+                   graph.edges);
+        assert!(root.join("b").join("c").exists());
+
+        let dot = graph.render(GraphFormat::Dot);
+        assert!(dot.contains("  n4 [label=\"a - b - c\", shape=note];\n"));
+        assert!(dot.contains("  n3 -> n4 [style=dashed];\n"));
+        let mermaid = graph.render(GraphFormat::Mermaid);
+        assert!(mermaid.starts_with("graph LR\n"));
+        assert!(mermaid.contains("  n2[\"b\"]\n"));
+        assert!(mermaid.contains("  n3 -.-> n4\n"));
+
+        // Junk is shown as deleted instead of renamed.
+        if fs::File::create(root.join("Thumbs.db")).is_err() {
+            return;
+        }
+        let options = FlattenOptions { junk: vec!["Thumbs.db".to_string()],
+                                       ..FlattenOptions::default() };
+        let graph = visualize(&root, &options);
+        let junk = graph.nodes.iter().position(|node| node.0 == "Thumbs.db").unwrap();
+        let deleted = graph.nodes.iter().position(|node| node.0 == "(deleted)").unwrap();
+        assert!(graph.edges.contains(&(junk, deleted, true)));
+        assert!(!graph.nodes.iter().any(|node| node.0 == "a - thumbs.db"));
+    }
+
+    #[test]
+    fn anchored_prefix_works() {
+        let root = path::Path::new("/music/+The Beatles/Abbey Road");
+        let anchored = |anchor| {
+            anchored_prefix(root, &FlattenOptions { anchor, ..FlattenOptions::default() })
+        };
+        assert_eq!(Ok("abbey road".to_string()), anchored(Anchor::RootIncluded));
+        assert_eq!(Ok("".to_string()), anchored(Anchor::RootExcluded));
+        assert_eq!(Ok("music - the beatles - abbey road".to_string()),
+                   anchored(Anchor::From(path::PathBuf::from("/music"))));
+        assert_eq!(Ok("music - the beatles - abbey road".to_string()),
+                   anchored(Anchor::From(path::PathBuf::from("/"))));
+        assert!(anchored(Anchor::From(path::PathBuf::from("/video"))).is_err());
+        assert_eq!("c.txt", new_filename("", "C.txt", &FlattenOptions::default()));
+    }
+
+    #[test]
+    fn flatten_skips_artifacts() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir(&root).is_err() ||
+           fs::File::create(root.join("stats.json")).is_err() ||
+           fs::File::create(root.join("b")).is_err() {
+            return;
+        }
+
+        let options = FlattenOptions {
+            artifacts: vec![root.join("stats.json")],
+            ..FlattenOptions::default()
+        };
+        let mut report = Report::new(&root);
+        flatten(&root, "", &options, &mut report).unwrap();
+        assert!(root.join("stats.json").exists());
+        assert!(root.join("a - b").exists());
+        assert_eq!(vec![(root.join("stats.json"), SkipReason::Artifact)], report.skips);
+    }
+
+    #[test]
+    fn flattener_works() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir_all(root.join("b")).is_err() ||
+           fs::File::create(root.join("b").join("c")).is_err() {
+            return;
+        }
+
+        let flattener = Flattener::new(FlattenOptions::default());
+        let (renames, _) = flattener.plan(&root).unwrap();
+        assert_eq!(vec![(root.join("b").join("c"), "a - b - c".to_string())], renames);
+        assert_eq!(1, flattener.estimate(&root).renames);
+
+        let report = flattener.flatten(&root).unwrap();
+        assert_eq!(1, report.totals.renamed);
+        assert!(root.join("b").join("a - b - c").exists());
+    }
+
+    #[test]
+    fn is_hidden_policies() {
+        let mut options = FlattenOptions::default();
+        assert!(is_hidden(path::Path::new("/a/.config"), &options));
+        assert!(is_hidden(path::Path::new("/a/.env.production"), &options));
+        assert!(!is_hidden(path::Path::new("/a/b"), &options));
+
+        options.dot_files = DotFiles::HiddenUnlessExtension;
+        assert!(is_hidden(path::Path::new("/a/.config"), &options));
+        assert!(!is_hidden(path::Path::new("/a/.env.production"), &options));
+
+        options.dot_allowlist.push(".config".to_string());
+        assert!(!is_hidden(path::Path::new("/a/.config"), &options));
+    }
+
+    #[test]
+    fn prefix_segment_numbering() {
+        let mut options = FlattenOptions::default();
+        assert_eq!("01 Intro", prefix_segment("01 Intro", &options));
+
+        options.numbering = Numbering::Strip;
+        assert_eq!("Intro", prefix_segment("01 Intro", &options));
+        assert_eq!("+Intro", prefix_segment("+01. Intro", &options));
+        assert_eq!("2019", prefix_segment("2019", &options));
+        assert_eq!("3rd", prefix_segment("3rd", &options));
+
+        options.numbering = Numbering::Pad(3);
+        assert_eq!("001 Intro", prefix_segment("1 Intro", &options));
+        assert_eq!("1234 Intro", prefix_segment("1234 Intro", &options));
+        assert_eq!("Intro", prefix_segment("Intro", &options));
+    }
+
+    #[test]
+    fn prefix_segment_numerals() {
+        let mut options = FlattenOptions::default();
+        assert_eq!("Part II", prefix_segment("Part II", &options));
+
+        options.normalize_numerals = true;
+        assert_eq!("Part 2", prefix_segment("Part II", &options));
+        assert_eq!("part 4", prefix_segment("part iv", &options));
+        assert_eq!("Volume 1", prefix_segment("Volume I", &options));
+        assert_eq!("Rocky 14", prefix_segment("Rocky XIV", &options));
+        assert_eq!("03 Season", prefix_segment("3rd Season", &options));
+        assert_eq!("+The 21 Album", prefix_segment("+The 21st Album", &options));
+        assert_eq!("I Robot", prefix_segment("I Robot", &options));
+        assert_eq!("Mix Tape", prefix_segment("Mix Tape", &options));
+        assert_eq!("Part IIII", prefix_segment("Part IIII", &options));
+        assert_eq!("3D", prefix_segment("3D", &options));
+        assert_eq!("CD 2", prefix_segment("CD 2", &options));
+
+        options.numbering = Numbering::Strip;
+        assert_eq!("Season", prefix_segment("3rd Season", &options));
+    }
+
+    #[test]
+    fn folding() {
+        let mut folding = Folding::default();
+        let name = "Beyonc\u{e9} \u{2018}D\u{e9}j\u{e0} Vu\u{2019} \u{2013} Don\u{2019}t";
+        assert_eq!(name, folding.apply(name));
+
+        folding.diacritics = true;
+        assert_eq!("Beyonce \u{2018}Deja Vu\u{2019} \u{2013} Don\u{2019}t", folding.apply(name));
+        assert_eq!("Manana", folding.apply("Man\u{303}ana"));
+        folding.quotes = true;
+        assert_eq!("Beyonce 'Deja Vu' \u{2013} Don't", folding.apply(name));
+        folding.apostrophes = true;
+        assert_eq!("Beyonce Deja Vu \u{2013} Dont", folding.apply(name));
+
+        let options = FlattenOptions { folding, ..FlattenOptions::default() };
+        assert_eq!(Ok("cafe - dont stop.mp3".to_string()),
+                   try_path(path::Path::new("Caf\u{e9}/Don\u{2019}t Stop.mp3"), &options));
+    }
+
+    #[test]
+    fn preflight_collisions() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        let sub = root.join("b");
+        if fs::create_dir_all(&sub).is_err() ||
+           fs::File::create(sub.join("C.txt")).is_err() ||
+           fs::File::create(sub.join("c.txt")).is_err() ||
+           fs::File::create(sub.join("d")).is_err() ||
+           fs::File::create(sub.join("a - b - d")).is_err() {
+            return;
+        }
+
+        let collisions = preflight(&root, &FlattenOptions::default()).unwrap();
+        assert_eq!(2, collisions.len());
+        assert_eq!(sub.join("a - b - c.txt"), collisions[0].target);
+        assert_eq!(vec![sub.join("C.txt"), sub.join("c.txt")], collisions[0].sources);
+        assert!(!collisions[0].exists);
+        // Flattening `a - b - d` gives it a new name, but maybe too late for `d`.
+        assert_eq!(sub.join("a - b - d"), collisions[1].target);
+        assert!(collisions[1].exists);
+
+        let renames = vec![(sub.join("C.txt"), "c.txt".to_string())];
+        assert!(find_collisions(&renames).is_empty());
+
+        let options = FlattenOptions { max_collisions: Some(2), ..FlattenOptions::default() };
+        assert_eq!(2, preflight(&root, &options).unwrap().len());
+        let options = FlattenOptions { max_collisions: Some(1), ..FlattenOptions::default() };
+        match preflight(&root, &options) {
+            Err(FlattenError::TooManyCollisions(1)) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn collision_policies() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        let taken = ["a - b.txt", "a - b (2).txt"];
+        // Directories are never renamed, so this one keeps its name.
+        if fs::create_dir_all(root.join("a - c")).is_err() ||
+           taken.iter().any(|name| fs::File::create(root.join(name)).is_err()) ||
+           fs::File::create(root.join("B.txt")).is_err() ||
+           fs::File::create(root.join("c")).is_err() {
+            return;
+        }
+        let b = root.join("B.txt");
+        let c = root.join("c");
+
+        assert_eq!(None, free_filename(&b, "a - b.txt", &CollisionPolicy::Fail));
+        assert_eq!(None, free_filename(&b, "a - b.txt", &CollisionPolicy::Skip));
+        assert_eq!(Some("a - b.txt".to_string()),
+                   free_filename(&b, "a - b.txt", &CollisionPolicy::Overwrite));
+        assert_eq!(Some("a - b (3).txt".to_string()),
+                   free_filename(&b, "a - b.txt", &CollisionPolicy::Number));
+        assert_eq!(Some("a - c (2)".to_string()),
+                   free_filename(&c, "a - c", &CollisionPolicy::Number));
+        assert_eq!(Some("b.txt".to_string()),
+                   free_filename(&b, "b.txt", &CollisionPolicy::Fail));
+
+        let options = FlattenOptions { on_collision: CollisionPolicy::Skip,
+                                       ..FlattenOptions::default() };
+        let mut report = Report::new(&root);
+        report.quiet = true;
+        flatten(&root, "", &options, &mut report).unwrap();
+        assert!(c.exists());
+        assert!(report.skips.contains(&(c, SkipReason::Collision)));
+    }
+
+    /// Keeps whichever of two colliding files is larger.
+    struct KeepLarger;
+
+    impl ConflictResolver for KeepLarger {
+        fn resolve(&self, entry: &path::Path, filename: &str, taken: &path::Path)
+                   -> Option<String> {
+            let size = |path: &path::Path| fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+            if size(entry) > size(taken) {
+                Some(filename.to_string())
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn conflict_resolver() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir_all(&root).is_err() ||
+           fs::write(root.join("b"), "larger").is_err() ||
+           fs::write(root.join("c"), "").is_err() ||
+           fs::write(root.join("a - b"), "").is_err() ||
+           fs::write(root.join("a - c"), "larger").is_err() {
+            return;
+        }
+
+        let options = FlattenOptions { resolver: Some(Box::new(KeepLarger)),
+                                       ..FlattenOptions::default() };
+        assert_eq!(Some("a - b".to_string()),
+                   free_filename(&root.join("b"), "a - b", options.resolver()));
+        assert_eq!(None, free_filename(&root.join("c"), "a - c", options.resolver()));
+        assert_eq!(Some("a - d".to_string()),
+                   free_filename(&root.join("d"), "a - d", options.resolver()));
+        let options = FlattenOptions { on_collision: CollisionPolicy::Number,
+                                       ..FlattenOptions::default() };
+        assert_eq!(Some("a - c (2)".to_string()),
+                   free_filename(&root.join("c"), "a - c", options.resolver()));
+    }
+
+    #[test]
+    fn merge_similar_directories() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        let journal_path = tmp_dir.path().join("journal");
+        if fs::create_dir_all(root.join("AC-DC").join("Live")).is_err() ||
+           fs::create_dir_all(root.join("acdc").join("live")).is_err() ||
+           fs::create_dir_all(root.join("ABBA")).is_err() ||
+           fs::write(root.join("AC-DC").join("x"), "").is_err() ||
+           fs::write(root.join("acdc").join("y"), "").is_err() ||
+           fs::write(root.join("acdc").join("live").join("z"), "").is_err() {
+            return;
+        }
+        let journal = match fs::File::create(&journal_path) {
+            Ok(journal) => journal,
+            Err(_) => return,
+        };
+
+        let options = FlattenOptions { merge_directories: true, ..FlattenOptions::default() };
+        let mut report = Report::new(&root);
+        report.journal = Some(Box::new(journal));
+        flatten(&root, "", &options, &mut report).unwrap();
+        drop(report);
+        assert!(!root.join("acdc").exists());
+        assert!(root.join("ABBA").exists());
+        assert!(root.join("AC-DC").join("a - ac-dc - x").exists());
+        assert!(root.join("AC-DC").join("a - acdc - y").exists());
+        assert!(root.join("AC-DC").join("Live").join("a - acdc - live - z").exists());
+
+        undo(&journal_path, &UndoOptions::default()).unwrap();
+        assert!(root.join("acdc").join("live").join("z").exists());
+        assert!(root.join("acdc").join("y").exists());
+
+        // A stopped run leaves what it has yet to move where it is.
+        let mut report = Report::new(&root);
+        report.stopped = true;
+        merge_into(&root.join("acdc"), &root.join("AC-DC"), &options, &mut report);
+        assert!(root.join("acdc").join("y").exists());
+        report.unprocessed.sort();
+        assert_eq!(vec![root.join("acdc").join("live"), root.join("acdc").join("y")],
+                   report.unprocessed);
+    }
+
+    #[test]
+    fn parallel_renames() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        // Every directory holds names that only come free, or are only
+        // taken, once other entries of it have been renamed, and names
+        // that collide once lowercased so they are numbered.
+        let mut names = vec!["x".to_string(), "a - x".to_string(), "a - a - x".to_string()];
+        for number in 0..12 {
+            let directory = format!("b{}", number);
+            for name in &["x".to_string(), "X".to_string(), format!("a - {} - x", directory)] {
+                names.push(format!("{}/{}", directory, name));
+            }
+        }
+        // The same tree renamed one directory at a time and side by side.
+        let mut results = Vec::new();
+        for jobs in &[1, 4] {
+            let root = tmp_dir.path().join(format!("jobs{}", jobs)).join("a");
+            for name in &names {
+                let path = root.join(name);
+                if fs::create_dir_all(path.parent().unwrap()).is_err() ||
+                   fs::write(&path, name).is_err() {
+                    return;
+                }
+            }
+
+            let options = FlattenOptions { jobs: *jobs, on_collision: CollisionPolicy::Number,
+                                           ..FlattenOptions::default() };
+            let mut report = Report::new(&root);
+            flatten(&root, "", &options, &mut report).unwrap();
+            assert_eq!(names.len(), report.totals.renamed);
+            assert!(report.failures.is_empty());
+            let mut renamed: Vec<(String, String)> = report.renames
+                .iter()
+                .map(|(_, to)| (portable_path(&root, to), fs::read_to_string(to).unwrap()))
+                .collect();
+            renamed.sort();
+            results.push(renamed);
+        }
+        assert_eq!(results[0], results[1]);
+        assert!(results[1].contains(&("b3/a - b3 - x (2)".to_string(), "b3/X".to_string())) ||
+                results[1].contains(&("b3/a - b3 - x (2)".to_string(), "b3/x".to_string())));
+    }
+
+    #[test]
+    fn read_ahead_walks_like_a_run() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir_all(root.join("b").join("c")).is_err() ||
+           fs::create_dir_all(root.join("_d")).is_err() ||
+           fs::File::create(root.join("b").join("c").join("e")).is_err() ||
+           fs::File::create(root.join("_d").join("f")).is_err() {
+            return;
+        }
+
+        let options = FlattenOptions { jobs: 4, ..FlattenOptions::default() };
+        let mut read: Vec<path::PathBuf> = read_ahead(&root, &options).into_keys().collect();
+        read.sort();
+        assert_eq!(vec![root.clone(), root.join("b"), root.join("b").join("c")], read);
+
+        let options = FlattenOptions { order: Order::BreadthFirst, ..options };
+        flatten(&root, "", &options, &mut Report::new(&root)).unwrap();
+        assert!(root.join("b").join("c").join("a - b - c - e").exists());
+        assert!(root.join("a - _d").join("f").exists());
+    }
+
+    #[test]
+    fn keep_going() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        // A file can't be renamed over a directory, even when overwriting.
+        for name in &["b", "c"] {
+            if fs::create_dir_all(root.join(format!("a - {}", name)).join("d")).is_err() ||
+               fs::File::create(root.join(name)).is_err() {
+                return;
+            }
+        }
+        let options = FlattenOptions { on_collision: CollisionPolicy::Overwrite,
+                                       ..FlattenOptions::default() };
+
+        let mut report = Report::new(&root);
+        report.quiet = true;
+        flatten(&root, "", &options, &mut report).unwrap();
+        assert_eq!(1, report.failures.len());
+        assert!(report.stopped);
+        let failures = report.render_failures();
+        assert!(failures.starts_with("1 renames failed:\n"));
+        assert!(failures.contains("by subtree:\n  .: 1 failed, 0 renamed\n"));
+
+        let options = FlattenOptions { keep_going: true, ..options };
+        let mut report = Report::new(&root);
+        report.quiet = true;
+        flatten(&root, "", &options, &mut report).unwrap();
+        assert_eq!(2, report.failures.len());
+        assert!(!report.stopped);
+        assert_eq!(2, report.totals.skipped);
+
+        let mut report = Report::new(&root);
+        report.quiet = true;
+        report.max_errors = Some(0);
+        flatten(&root, "", &options, &mut report).unwrap();
+        assert_eq!(1, report.failures.len());
+        assert!(report.stopped);
+        assert_eq!(1, report.unprocessed.len());
+        assert!(report.render_failures().contains("stopped after more than 0 failures"));
+    }
+
+    #[test]
+    fn quota_exceeded() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir_all(root.join("b")).is_err() ||
+           fs::File::create(root.join("b").join("c")).is_err() ||
+           fs::File::create(root.join("d")).is_err() {
+            return;
+        }
+
+        let mut report = Report::new(&root);
+        report.quiet = true;
+        let quota = io::Error::from(io::ErrorKind::QuotaExceeded);
+        report.record_failure(&root.join("e"), &quota, true);
+        assert!(report.stopped);
+        assert!(report.quota_exceeded());
+        // Breadth first, the root itself is left unprocessed.
+        let options = FlattenOptions { order: Order::BreadthFirst, ..FlattenOptions::default() };
+        flatten(&root, "", &options, &mut report).unwrap();
+        assert_eq!(vec![root.clone()], report.unprocessed);
+        report.unprocessed.clear();
+        flatten(&root, "", &FlattenOptions::default(), &mut report).unwrap();
+        report.unprocessed.sort();
+        assert_eq!(vec![root.join("b"), root.join("d")], report.unprocessed);
+        assert!(root.join("d").exists());
+        let failures = report.render_failures();
+        assert!(failures.contains("stopped as a disk quota was exceeded"));
+        assert!(failures.contains("entries left unprocessed:\n"));
+        assert_eq!("quota_exceeded", report.render_json()["skips"][0]["reason"]);
+    }
+
+    #[test]
+    fn immutable_entries() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        let file = root.join("b");
+        if fs::create_dir_all(&root).is_err() || fs::File::create(&file).is_err() {
+            return;
+        }
+        // Setting the flag takes privileges and a filesystem supporting it.
+        let flags = IMMUTABLE_FLAGS & !(IMMUTABLE_FLAGS - 1);
+        if flags == 0 || change_immutable_flags(&file, flags, true).is_err() {
+            return;
+        }
+        assert_eq!(flags, immutable_flags(&file));
+
+        let mut report = Report::new(&root);
+        flatten(&root, "", &FlattenOptions::default(), &mut report).unwrap();
+        assert_eq!(vec![(file.clone(), SkipReason::Immutable)], report.skips);
+
+        let options = FlattenOptions { clear_immutable: true, ..FlattenOptions::default() };
+        let mut report = Report::new(&root);
+        flatten(&root, "", &options, &mut report).unwrap();
+        let renamed = root.join("a - b");
+        assert_eq!(flags, immutable_flags(&renamed));
+        change_immutable_flags(&renamed, flags, false).unwrap();
+        assert_eq!(0, immutable_flags(&renamed));
+    }
+
+    #[test]
+    fn unwritable_mounts_skipped() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir_all(root.join("b")).is_err() ||
+           fs::File::create(root.join("b").join("c")).is_err() ||
+           fs::File::create(root.join("d")).is_err() {
+            return;
+        }
+
+        assert!(unwritable_mounts(&root, &FlattenOptions::default(), true).is_empty());
+        // The probe leaves nothing behind.
+        assert_eq!(2, fs::read_dir(&root).unwrap().count());
+
+        let options = FlattenOptions { unwritable: vec![root.join("b")],
+                                       ..FlattenOptions::default() };
+        let mut report = Report::new(&root);
+        flatten(&root, "", &options, &mut report).unwrap();
+        assert!(root.join("b").join("c").exists());
+        assert!(root.join("a - d").exists());
+        assert_eq!(vec![(root.join("b"), SkipReason::ReadOnly)], report.skips);
+    }
+
+    #[test]
+    fn separator() {
+        let options = FlattenOptions { separator: Some("_".to_string()),
+                                       ..FlattenOptions::default() };
+        assert_eq!(Ok("music_abba_waterloo_01 waterloo.flac".to_string()),
+                   try_path(path::Path::new("Music/ABBA/Waterloo/01 Waterloo.flac"), &options));
+        let options = FlattenOptions { separator: Some(".".to_string()),
+                                       ..FlattenOptions::default() };
+        assert_eq!("a.b.c", new_prefix("a.b", "C", &options));
+    }
+
+    #[test]
+    fn case() {
+        let name = "+the Beatles - abbey ROAD (remastered) don't-stop 3rd.FLAC";
+        assert_eq!("+the beatles - abbey road (remastered) don't-stop 3rd.flac",
+                   Case::Lower.apply(name));
+        assert_eq!("+THE BEATLES - ABBEY ROAD (REMASTERED) DON'T-STOP 3RD.FLAC",
+                   Case::Upper.apply(name));
+        assert_eq!("+The Beatles - Abbey Road (Remastered) Don't-Stop 3rd.flac",
+                   Case::Title.apply(name));
+        assert_eq!(name, Case::Preserve.apply(name));
+
+        let options = FlattenOptions { case: Case::Preserve, ..FlattenOptions::default() };
+        assert_eq!(Ok("Music - The Beatles - 01 Come Together.flac".to_string()),
+                   try_path(path::Path::new("Music/+The Beatles/01 Come Together.flac"),
+                            &options));
+    }
+
+    #[test]
+    fn case_locale() {
+        assert_eq!("\u{3bf}\u{3b4}\u{3bf}\u{3c2} \u{3c3}\u{3b1}",
+                   Case::Lower.apply("\u{39f}\u{394}\u{39f}\u{3a3} \u{3a3}\u{391}"));
+        assert_eq!("\u{39f}\u{3b4}\u{3bf}\u{3c2}",
+                   Case::Title.apply("\u{39f}\u{394}\u{39f}\u{3a3}"));
+
+        let turkic = CaseLocale::Turkic;
+        assert_eq!("ispanak i\u{307}zmir", Case::Lower.apply("Ispanak \u{130}zmir"));
+        assert_eq!("\u{131}spanak izmir",
+                   Case::Lower.apply_in("ISPANAK \u{130}ZM\u{130}R", turkic));
+        assert_eq!("\u{130}ZM\u{130}R", Case::Upper.apply_in("izmir", turkic));
+        assert_eq!("\u{130}zmir Ispanak",
+                   Case::Title.apply_in("\u{130}ZM\u{130}R ISPANAK", turkic));
+
+        let options = FlattenOptions { case_locale: turkic, ..FlattenOptions::default() };
+        assert_eq!("di\u{15f} - \u{131}\u{15f}\u{131}k",
+                   new_prefix("D\u{130}\u{15e}", "I\u{15e}IK", &options));
+    }
+
+    #[test]
+    fn opaque_segments() {
+        let options = FlattenOptions { opaque: Some(Opaque::new("seed")),
+                                       ..FlattenOptions::default() };
+        let token = prefix_segment("Holiday", &options);
+        assert_eq!(OPAQUE_TOKEN_LEN, token.len());
+        assert!(!token.contains("holiday"));
+        assert_eq!(token, prefix_segment("holiday", &options));
+        assert_eq!(format!("+{}", token), prefix_segment("+Holiday", &options));
+        assert_ne!(token, prefix_segment("Work", &options));
+
+        let other = FlattenOptions { opaque: Some(Opaque::new("other")),
+                                     ..FlattenOptions::default() };
+        assert_ne!(token, prefix_segment("Holiday", &other));
+
+        let mapping = options.opaque.as_ref().unwrap().mapping();
+        assert_eq!(2, mapping.len());
+        assert_eq!(Some(&"holiday".to_string()), mapping.get(&token));
+    }
+
+    #[test]
+    fn encrypted_opaque_map() {
+        let opaque = Opaque::new("seed");
+        opaque.token("Holiday");
+        opaque.token("Work");
+        let encrypted = opaque.render_encrypted("secret").unwrap();
+        assert!(!String::from_utf8_lossy(&encrypted).contains("holiday"));
+
+        let identity = age::scrypt::Identity::new("secret".to_string().into());
+        let decrypted = age::decrypt(&identity, &encrypted).unwrap();
+        let decrypted: BTreeMap<String, String> = serde_json::from_slice(&decrypted).unwrap();
+        assert_eq!(opaque.mapping(), decrypted);
+
+        let identity = age::scrypt::Identity::new("guess".to_string().into());
+        assert!(age::decrypt(&identity, &encrypted).is_err());
+    }
+
+    #[test]
+    fn opaque_segments_every_case() {
+        let token = Opaque::new("seed").token("holiday");
+        for &case in &[Case::Lower, Case::Upper, Case::Title, Case::Preserve] {
+            for &case_locale in &[CaseLocale::Unicode, CaseLocale::Turkic] {
+                let options = FlattenOptions { opaque: Some(Opaque::new("seed")),
+                                               case,
+                                               case_locale,
+                                               ..FlattenOptions::default() };
+                for name in &["Holiday", "HOLIDAY", "holiday"] {
+                    let segment = prefix_segment(name, &options);
+                    assert_eq!(token, segment);
+                    let prefix = new_prefix("", &segment, &options);
+                    assert_eq!(token, prefix.to_lowercase());
+                }
+                let mapping = options.opaque.as_ref().unwrap().mapping();
+                assert_eq!(vec![&token], mapping.keys().collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[test]
+    fn normalization() {
+        let composed = "Caf\u{e9}/Ros\u{e9}.txt";
+        let decomposed = "Cafe\u{301}/Rose\u{301}.txt";
+        let mut options = FlattenOptions::default();
+        assert_eq!(Ok("cafe\u{301} - ros\u{e9}.txt".to_string()),
+                   try_path(path::Path::new("Cafe\u{301}/Ros\u{e9}.txt"), &options));
+
+        options.normalization = Normalization::Nfc;
+        assert_eq!(Ok("caf\u{e9} - ros\u{e9}.txt".to_string()),
+                   try_path(path::Path::new(decomposed), &options));
+        options.normalization = Normalization::Nfd;
+        assert_eq!(Ok("cafe\u{301} - rose\u{301}.txt".to_string()),
+                   try_path(path::Path::new(composed), &options));
+    }
+
+    #[test]
+    fn journal_and_undo() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        let journal_path = tmp_dir.path().join("journal");
+        if fs::create_dir_all(root.join("b")).is_err() ||
+           fs::File::create(root.join("b").join("c")).is_err() ||
+           fs::File::create(root.join("d")).is_err() {
+            return;
+        }
+        let journal = match fs::File::create(&journal_path) {
+            Ok(journal) => journal,
+            Err(_) => return,
+        };
+
+        let mut report = Report::new(&root);
+        report.journal = Some(Box::new(journal));
+        flatten(&root, "", &FlattenOptions::default(), &mut report).unwrap();
+        drop(report);
+        assert!(root.join("b").join("a - b - c").exists());
+        assert_eq!(2, read_journal(&journal_path).unwrap().len());
+
+        // Something new has taken one of the old names.
+        if fs::File::create(root.join("d")).is_err() {
+            return;
+        }
+        let (undone, problems) = undo(&journal_path, &UndoOptions::default()).unwrap();
+        assert_eq!(1, undone);
+        assert_eq!(1, problems.len());
+        assert!(root.join("b").join("c").exists());
+        assert!(root.join("a - d").exists());
+    }
+
+    #[test]
+    fn undo_selected_entries() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        let journal_path = tmp_dir.path().join("journal");
+        if fs::create_dir_all(root.join("b")).is_err() ||
+           fs::create_dir_all(root.join("c")).is_err() ||
+           fs::File::create(root.join("b").join("d")).is_err() ||
+           fs::File::create(root.join("b").join("e")).is_err() ||
+           fs::File::create(root.join("c").join("f")).is_err() {
+            return;
+        }
+        let journal = match fs::File::create(&journal_path) {
+            Ok(journal) => journal,
+            Err(_) => return,
+        };
+        let mut report = Report::new(&root);
+        report.journal = Some(Box::new(journal));
+        flatten(&root, "", &FlattenOptions::default(), &mut report).unwrap();
+        drop(report);
+
+        let entry = JournalEntry::Rename(root.join("b").join("d"),
+                                         root.join("b").join("a - b - d"));
+        assert!(UndoOptions::default().selects(&entry));
+        let options = UndoOptions { include: vec!["*/c/*".to_string()],
+                                    ..UndoOptions::default() };
+        assert!(!options.selects(&entry));
+        let options = UndoOptions { exclude: vec!["*/a - b - d".to_string()],
+                                    ..UndoOptions::default() };
+        assert!(!options.selects(&entry));
+
+        // Only b's subtree, and not everything in it, goes back.
+        let options = UndoOptions { exclude: vec!["*/e".to_string()],
+                                    paths: vec![root.join("b")],
+                                    ..UndoOptions::default() };
+        assert_eq!((1, Vec::new()), undo(&journal_path, &options).unwrap());
+        assert!(root.join("b").join("d").exists());
+        assert!(root.join("b").join("a - b - e").exists());
+        assert!(root.join("c").join("a - c - f").exists());
+    }
+
+    #[test]
+    fn undo_conflicts() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        let journal_path = tmp_dir.path().join("journal");
+        let directory = root.join("b");
+        if fs::create_dir_all(&directory).is_err() ||
+           ["c.txt", "d", "e", "f"].iter().any(|name| {
+               fs::File::create(directory.join(name)).is_err()
+           }) {
+            return;
+        }
+        let journal = match fs::File::create(&journal_path) {
+            Ok(journal) => journal,
+            Err(_) => return,
+        };
+        let mut report = Report::new(&root);
+        report.journal = Some(Box::new(journal));
+        flatten(&root, "", &FlattenOptions::default(), &mut report).unwrap();
+        drop(report);
+
+        // An old name taken, a renamed file gone, and one modified since.
+        let later = time::SystemTime::now() + time::Duration::from_secs(60 * 60);
+        if fs::File::create(directory.join("c.txt")).is_err() ||
+           fs::remove_file(directory.join("a - b - d")).is_err() ||
+           fs::File::options().write(true)
+                              .open(directory.join("a - b - e"))
+                              .and_then(|file| file.set_modified(later))
+                              .is_err() {
+            return;
+        }
+        let mut options = UndoOptions { dry_run: true, ..UndoOptions::default() };
+        let (undone, problems) = undo(&journal_path, &options).unwrap();
+        assert_eq!(1, undone);
+        assert_eq!(3, problems.len());
+        assert!(problems.iter().any(|problem| problem.contains("already exists")));
+        assert!(problems.iter().any(|problem| problem.contains("is gone")));
+        assert!(problems.iter().any(|problem| problem.contains("modified after the run")));
+        // Nothing was touched.
+        assert!(directory.join("a - b - f").exists());
+
+        options.on_collision = CollisionPolicy::Number;
+        assert_eq!(2, undo(&journal_path, &options).unwrap().0);
+        options.dry_run = false;
+        assert_eq!(2, undo(&journal_path, &options).unwrap().0);
+        assert!(directory.join("c (2).txt").exists());
+        assert!(directory.join("f").exists());
+        assert!(directory.join("a - b - e").exists());
+    }
+
+    #[test]
+    fn delete_junk_and_undo() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        let journal_path = tmp_dir.path().join("journal");
+        if fs::create_dir_all(root.join("b")).is_err() ||
+           fs::File::create(root.join("b").join("empty")).is_err() ||
+           fs::write(root.join("b").join(".DS_Store"), "junk").is_err() ||
+           fs::write(root.join("b").join("notes.tmp"), "junk").is_err() ||
+           fs::write(root.join("b").join("c"), "keep").is_err() {
+            return;
+        }
+        let journal = match fs::File::create(&journal_path) {
+            Ok(journal) => journal,
+            Err(_) => return,
+        };
+
+        let mut options = FlattenOptions {
+            delete_empty: true,
+            junk: DEFAULT_JUNK.iter().map(|name| name.to_string()).collect(),
+            ..FlattenOptions::default()
+        };
+        options.junk.push("*.tmp".to_string());
+        let mut report = Report::new(&root);
+        report.journal = Some(Box::new(journal));
+        flatten(&root, "", &options, &mut report).unwrap();
+        drop(report);
+        assert_eq!(1, fs::read_dir(root.join("b")).unwrap().count());
+        assert!(root.join("b").join("a - b - c").exists());
+        assert_eq!(1, read_journal(&journal_path).unwrap().len());
+        assert_eq!(4, read_journal_entries(&journal_path).unwrap().len());
+
+        let (undone, problems) = undo(&journal_path, &UndoOptions::default()).unwrap();
+        assert_eq!(2, undone);
+        assert_eq!(2, problems.len());
+        assert!(root.join("b").join("c").exists());
+        assert!(root.join("b").join("empty").exists());
+        assert!(!root.join("b").join(".DS_Store").exists());
+    }
+
+    #[test]
+    fn apply_manifest() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        let manifest_path = tmp_dir.path().join("manifest.csv");
+        if fs::create_dir_all(root.join("b")).is_err() ||
+           fs::File::create(root.join("b").join("c, \"d\"")).is_err() ||
+           fs::File::create(root.join("e")).is_err() {
+            return;
+        }
+        let manifest = match fs::File::create(&manifest_path) {
+            Ok(manifest) => manifest,
+            Err(_) => return,
+        };
+
+        let mut report = Report::new(&root);
+        report.start_manifest(Box::new(manifest)).unwrap();
+        report.record_deletion(&root.join("f"), 0);
+        flatten(&root, "", &FlattenOptions::default(), &mut report).unwrap();
+        drop(report);
+        assert_eq!(2, read_journal(&manifest_path).unwrap().len());
+
+        // Replay it on a copy of the tree elsewhere.
+        let mirror = tmp_dir.path().join("mirror");
+        if fs::create_dir_all(mirror.join("b")).is_err() ||
+           fs::File::create(mirror.join("b").join("c, \"d\"")).is_err() {
+            return;
+        }
+        let applied = apply(&manifest_path, None, None, Some((&root, &mirror))).unwrap();
+        assert_eq!(1, applied.renamed);
+        assert_eq!(1, applied.drifted.len());
+        assert!(mirror.join("b").join("a - b - c, \"d\"").exists());
+
+        if fs::write(&manifest_path, format!("{}\na,b,now,moved,1\n", MANIFEST_HEADER)).is_err() {
+            return;
+        }
+        let e = read_journal(&manifest_path).unwrap_err();
+        assert_eq!(format!("{}: not a journal entry: a,b,now,moved,1", manifest_path.display()),
+                   e.to_string());
+    }
+
+    #[test]
+    fn csv_parsing() {
+        assert_eq!(vec![vec!["a".to_string(), "b".to_string()],
+                        vec!["c,d".to_string(), "e\"\nf".to_string()]],
+                   parse_csv("a,b\r\n\"c,d\",\"e\"\"\nf\"\n"));
+        assert_eq!(vec![vec!["".to_string(), "".to_string()]], parse_csv(","));
+    }
+
+    #[test]
+    fn find_duplicates() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir_all(root.join("b")).is_err() ||
+           fs::write(root.join("b").join("c"), "same").is_err() ||
+           fs::write(root.join("d"), "same").is_err() ||
+           fs::write(root.join("e"), "diff").is_err() ||
+           fs::File::create(root.join("f")).is_err() ||
+           fs::File::create(root.join("g")).is_err() {
+            return;
+        }
+
+        let options = FlattenOptions { find_duplicates: true, ..FlattenOptions::default() };
+        let mut report = Report::new(&root);
+        flatten(&root, "", &options, &mut report).unwrap();
+        assert_eq!(vec![vec![root.join("a - d"), root.join("b").join("a - b - c")]],
+                   report.duplicates);
+        assert!(report.render_text()
+                      .contains("\nlikely duplicates (1):\n  a - d  |  b/a - b - c\n"));
+        assert_eq!(json!([["a - d", "b/a - b - c"]]), report.render_json()["duplicates"]);
+    }
+
+    #[test]
+    fn sh_script() {
+        let from = path::Path::new("-a/it's");
+        let to = path::Path::new("-a/b c");
+        assert_eq!(b"mv -n -- '-a/it'\\''s' '-a/b c'\n".to_vec(),
+                   Script::Sh.command(from, Some(to), false));
+        assert_eq!(b"mv -f -- '-a/it'\\''s' '-a/b c'\n".to_vec(),
+                   Script::Sh.command(from, Some(to), true));
+        assert_eq!(b"rm -f -- '-a/it'\\''s'\n".to_vec(), Script::Sh.command(from, None, false));
+    }
+
+    #[test]
+    fn powershell_script() {
+        let from = path::Path::new("C:/a/it's");
+        let to = path::Path::new("C:/a/b [1]");
+        assert_eq!(b"Rename-Item -LiteralPath 'C:/a/it''s' -NewName 'b [1]'\n".to_vec(),
+                   Script::PowerShell.command(from, Some(to), false));
+        assert_eq!(b"Remove-Item -LiteralPath 'C:/a/it''s' -Force\n".to_vec(),
+                   Script::PowerShell.command(from, None, false));
+        let overwritten = Script::PowerShell.command(from, Some(to), true);
+        assert!(overwritten.starts_with(b"if (Test-Path -LiteralPath 'C:/a/b [1]') "));
+        let moved = Script::PowerShell.command(from, Some(path::Path::new("C:/c/d*")), false);
+        assert_eq!(b"Move-Item -LiteralPath 'C:/a/it''s' -Destination 'C:/c/d`*'\n".to_vec(),
+                   moved);
+        assert_eq!("'a\u{2019}\u{2019}b'", ps_quote("a\u{2019}b"));
+    }
+
+    #[test]
+    fn git_renames() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir_all(root.join("b")).is_err() ||
+           fs::write(root.join("b").join("c"), "tracked").is_err() ||
+           fs::write(root.join("d"), "untracked").is_err() {
+            return;
+        }
+        let git = |args: &[&str]| {
+            process::Command::new("git").arg("-C").arg(&root)
+                                        .args(["-c", "user.name=a", "-c", "user.email=a@b"])
+                                        .args(args)
+                                        .output()
+                                        .ok()
+                                        .filter(|output| output.status.success())
+        };
+        if git(&["init", "-q"]).is_none() || git(&["add", "b/c"]).is_none() ||
+           git(&["commit", "-q", "-m", "c"]).is_none() {
+            return;
+        }
+        assert!(in_git_work_tree(&root));
+
+        let options = FlattenOptions { git: true, ..FlattenOptions::default() };
+        let mut report = Report::new(&root);
+        flatten(&root, "", &options, &mut report).unwrap();
+        assert_eq!(2, report.totals.renamed);
+        let status = git(&["status", "--porcelain"]).unwrap();
+        assert_eq!("R  b/c -> \"b/a - b - c\"\n?? \"a - d\"\n",
+                   String::from_utf8_lossy(&status.stdout));
+    }
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_matches("Thumbs.db", "Thumbs.db"));
+        assert!(!glob_matches("Thumbs.db", "thumbs.db"));
+        assert!(glob_matches("*.tmp", "notes.tmp"));
+        assert!(glob_matches("*.tmp", ".tmp"));
+        assert!(!glob_matches("*.tmp", "notes.tmp.txt"));
+        assert!(glob_matches("~$*", "~$report.docx"));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(glob_matches("?.txt", "a.txt"));
+        assert!(!glob_matches("?.txt", "ab.txt"));
+        assert!(glob_matches("*", ""));
+    }
+
+    #[test]
+    fn flatten_errors() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let missing = tmp_dir.path().join("missing");
+        let journal_path = tmp_dir.path().join("journal");
+        if fs::write(&journal_path, "{\"from\": \"a\"}\n").is_err() {
+            return;
+        }
+
+        let mut report = Report::new(&missing);
+        match flatten(&missing, "", &FlattenOptions::default(), &mut report) {
+            Err(FlattenError::Io(path, _)) => assert_eq!(missing, path),
+            other => panic!("{:?}", other),
+        }
+        assert!(Flattener::new(FlattenOptions::default()).plan(&missing).is_err());
+        let e = read_journal(&journal_path).unwrap_err();
+        assert_eq!(format!("{}: not a journal entry: {{\"from\": \"a\"}}",
+                           journal_path.display()),
+                   e.to_string());
+    }
+
+    #[test]
+    fn schema_versions() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let journal_path = tmp_dir.path().join("journal");
+        let manifest_path = tmp_dir.path().join("manifest.csv");
+
+        let current = format!("{}\n{}\n", journal_entry(path::Path::new("a"),
+                                                         path::Path::new("b")),
+                              deletion_entry(path::Path::new("c"), 0));
+        // Written before versions were recorded.
+        let unversioned = "{\"from\": \"d\", \"to\": \"e\"}\n";
+        if fs::write(&journal_path, current + unversioned).is_err() {
+            return;
+        }
+        assert_eq!(3, read_journal_entries(&journal_path).unwrap().len());
+        if fs::write(&journal_path, "{\"schema_version\": 2, \"from\": \"a\", \"to\": \"b\"}\n")
+               .is_err() {
+            return;
+        }
+        let e = read_journal(&journal_path).unwrap_err();
+        assert_eq!(format!("{}: schema_version 2 is not supported (this version of \
+                            flatten-filenames reads 1)", journal_path.display()),
+                   e.to_string());
+
+        let unversioned = format!("{}\na,b,now,renamed\n", UNVERSIONED_MANIFEST_HEADER);
+        if fs::write(&manifest_path, unversioned).is_err() {
+            return;
+        }
+        assert_eq!(1, read_journal(&manifest_path).unwrap().len());
+        if fs::write(&manifest_path, format!("{}\na,b,now,renamed,2\n", MANIFEST_HEADER))
+               .is_err() {
+            return;
+        }
+        match read_journal(&manifest_path) {
+            Err(FlattenError::UnsupportedSchema(_, version, 1)) => assert_eq!("2", version),
+            other => panic!("{:?}", other),
+        }
+
+        let report = Report::new(tmp_dir.path());
+        assert_eq!(json!(REPORT_SCHEMA), report.render_json()["schema_version"]);
+    }
+
+    #[test]
+    fn fsync_journal() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        let journal_path = tmp_dir.path().join("journal");
+        if fs::create_dir_all(root.join("b")).is_err() ||
+           fs::File::create(root.join("b").join("c")).is_err() ||
+           fs::File::create(root.join("d")).is_err() {
+            return;
+        }
+        let journal = match fs::File::create(&journal_path) {
+            Ok(journal) => journal,
+            Err(_) => return,
+        };
+
+        let mut report = Report::new(&root);
+        report.journal = Some(Box::new(SyncedFile(journal)));
+        let options = FlattenOptions { fsync: true, ..FlattenOptions::default() };
+        flatten(&root, "", &options, &mut report).unwrap();
+        assert!(report.warnings.is_empty());
+        // Each directory's renames are journaled once it has been synced.
+        assert_eq!(2, read_journal(&journal_path).unwrap().len());
+        assert!(root.join("b").join("a - b - c").exists());
+        assert!(root.join("a - d").exists());
+    }
+
+    #[test]
+    fn plan_and_apply() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        let plan_path = tmp_dir.path().join("plan");
+        if fs::create_dir_all(root.join("b")).is_err() ||
+           fs::File::create(root.join("b").join("c")).is_err() ||
+           fs::File::create(root.join("d")).is_err() {
+            return;
+        }
+
+        let mut report = Report::new(&root);
+        let renames = plan(&root, &FlattenOptions::default(), &mut report).unwrap();
+        let mut contents = Vec::new();
+        write_plan(&renames, &mut contents).unwrap();
+        // Hand-edit the plan before applying it.
+        let contents = String::from_utf8(contents).unwrap().replace("a - d", "d - edited");
+        if fs::write(&plan_path, contents).is_err() {
+            return;
+        }
+        assert!(root.join("b").join("c").exists());
+
+        let before = plan_hash(&read_journal(&plan_path).unwrap());
+        assert_eq!(before, plan_hash(&read_journal(&plan_path).unwrap()));
+        if fs::write(root.join("d"), "changed").is_err() {
+            return;
+        }
+        assert_ne!(before, plan_hash(&read_journal(&plan_path).unwrap()));
+
+        let mut journal = Vec::new();
+        let applied = apply(&plan_path, Some(&mut journal), None, None).unwrap();
+        assert_eq!(2, applied.renamed);
+        assert!(applied.drifted.is_empty() && applied.failed.is_empty());
+        assert!(root.join("b").join("a - b - c").exists());
+        assert!(root.join("d - edited").exists());
+        assert_eq!(2, String::from_utf8(journal).unwrap().lines().count());
+
+        // Applying it again finds nothing left to rename.
+        let applied = apply(&plan_path, None, None, None).unwrap();
+        assert_eq!(0, applied.renamed);
+        assert_eq!(2, applied.drifted.len());
+        assert!(!applied.stopped);
+
+        // Too much drift abandons the rest of the plan.
+        let applied = apply(&plan_path, None, Some(0), None).unwrap();
+        assert_eq!(1, applied.drifted.len());
+        assert!(applied.stopped);
+    }
+
+    #[test]
+    fn flatten_dry_run() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir_all(root.join("b")).is_err() ||
+           fs::File::create(root.join("b").join("c")).is_err() {
+            return;
+        }
+
+        let options = FlattenOptions { dry_run: true, ..FlattenOptions::default() };
+        let mut report = Report::new(&root);
+        flatten(&root, "", &options, &mut report).unwrap();
+        assert!(root.join("b").join("c").exists());
+        assert!(!root.join("b").join("a - b - c").exists());
+        assert_eq!(vec![(root.join("b").join("c"), root.join("b").join("a - b - c"))],
+                   report.renames);
+    }
+
+    #[test]
+    fn flatten_breadth_first() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let mut path_buf = tmp_dir.path().to_path_buf();
+        path_buf.push("A");
+        path_buf.push("B");
+        if fs::DirBuilder::new().recursive(true).create(&path_buf).is_err() {
+            return;
+        }
+        path_buf.push("C");
+        if fs::File::create(&path_buf).is_err() {
+            return;
+        }
+        path_buf.pop();
+        path_buf.pop();
+        path_buf.push("D");
+        if fs::File::create(&path_buf).is_err() {
+            return;
+        }
+        path_buf.pop();
+
+        let options = FlattenOptions { order: Order::BreadthFirst, ..FlattenOptions::default() };
+        flatten(&path_buf, "", &options, &mut Report::new(&path_buf)).unwrap();
+
+        path_buf.push("a - d");
+        assert!(path_buf.exists());
+        path_buf.pop();
+        path_buf.push("B");
+        path_buf.push("a - b - c");
+        assert!(path_buf.exists());
+    }
+
+    #[test]
+    fn deep_trees() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        let deepest = (0..400).fold(root.clone(), |path, _| path.join("b"));
+        if fs::create_dir_all(&deepest).is_err() || fs::File::create(deepest.join("c")).is_err() {
+            return;
+        }
+
+        // Far less stack than a call per level would need.
+        let walked = std::thread::Builder::new().stack_size(64 * 1024).spawn(move || {
+            let options = FlattenOptions { dry_run: true, ..FlattenOptions::default() };
+            let mut report = Report::new(&root);
+            report.quiet = true;
+            let planned = plan(&root, &options, &mut report).unwrap().len();
+            flatten(&root, "", &options, &mut report).unwrap();
+            (planned, report.totals.renamed)
+        });
+        assert_eq!((1, 1), walked.unwrap().join().unwrap());
+    }
+
+    #[test]
+    fn flatten_within_max_open() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let mut path_buf = tmp_dir.path().to_path_buf();
+        path_buf.push("A");
+        path_buf.push("B");
+        path_buf.push("C");
+        if fs::DirBuilder::new().recursive(true).create(&path_buf).is_err() {
+            return;
+        }
+        path_buf.push("D");
+        if fs::File::create(&path_buf).is_err() {
+            return;
+        }
+        path_buf.pop();
+        path_buf.pop();
+        path_buf.pop();
+
+        let options = FlattenOptions { max_open: Some(1), ..FlattenOptions::default() };
+        flatten(&path_buf, "", &options, &mut Report::new(&path_buf)).unwrap();
+
+        path_buf.push("B");
+        path_buf.push("C");
+        path_buf.push("a - b - c - d");
+        assert!(path_buf.exists());
+    }
+
+    #[test]
+    fn options_validation() {
+        assert!(FlattenOptions::default().validate().is_empty());
+
+        let options = FlattenOptions { jobs: 8, max_open: Some(4),
+                                       ..FlattenOptions::default() };
+        let diagnostics = options.validate();
+        assert_eq!(1, diagnostics.len());
+        assert!(diagnostics[0].fatal);
+        assert_eq!("error: --jobs 8 reads up to 8 directories at once, more than --max-open 4 \
+                    allows\n  fix: lower --jobs to 4 or raise --max-open",
+                   diagnostics[0].to_string());
+
+        let options = FlattenOptions { dot_allowlist: vec!["env".to_string()],
+                                       junk: vec!["cache/*.tmp".to_string()],
+                                       numbering: Numbering::Pad(1),
+                                       fsync: true,
+                                       dry_run: true,
+                                       ..FlattenOptions::default() };
+        let diagnostics = options.validate();
+        assert_eq!(4, diagnostics.len());
+        assert!(diagnostics.iter().all(|diagnostic| !diagnostic.fatal));
+        assert_eq!("give the name with its leading '.', like \".env\"", diagnostics[0].fix);
+        assert_eq!("give just the file name, like \"*.tmp\"", diagnostics[1].fix);
+    }
+
+    #[test]
+    fn build_details_works() {
+        let details = build_details();
+        let lines: Vec<&str> = details.lines().collect();
+        assert_eq!(format!("flatten-filenames {}", env!("CARGO_PKG_VERSION")), lines[0]);
+        assert!(lines[1].starts_with("target: ") && lines[1].len() > "target: ".len());
+        assert!(lines[2].starts_with("profile: "));
+        assert!(lines[3].starts_with("features: "));
+        assert!(lines[4].starts_with("formats: journal 1, plan 1, "));
+        assert!(lines[4].ends_with(", scheme protocol 1"));
+
+        // The versions listed are the ones written into each format.
+        let root = path::Path::new("/tmp/a");
+        let listing = VirtualListing { root: root.to_path_buf(), entries: Vec::new() };
+        let written = [
+            ("journal", journal_entry(root, root)["schema_version"].clone()),
+            ("plan", journal_entry(root, root)["schema_version"].clone()),
+            ("manifest", json!(MANIFEST_SCHEMA)),
+            ("report", Report::new(root).render_json()["schema_version"].clone()),
+            ("events", json!(EVENTS_SCHEMA)),
+            ("listing", listing.render_json()["schema_version"].clone()),
+            ("scheme protocol", json!(SCHEME_PROTOCOL)),
+        ];
+        for ((name, version), (written_name, written_version)) in
+            FORMAT_VERSIONS.iter().zip(written.iter()) {
+            assert_eq!((name, &json!(version)), (written_name, written_version));
+        }
+    }
+
+    #[test]
+    fn incident_reports() {
+        let arguments = vec!["flatten-filenames".to_string(), "--jobs".to_string(),
+                             "2".to_string(), "photos".to_string()];
+        let report = incident_report("123-45", &arguments, "boom at src/lib.rs:1:1", "0: main");
+        assert!(report.starts_with("flatten-filenames crashed\nrun: 123-45\n"));
+        assert!(report.contains("\narguments: flatten-filenames --jobs 2 photos\n"));
+        assert!(report.contains("\nlast operation: "));
+        assert!(report.contains("\npanic: boom at src/lib.rs:1:1\n"));
+        assert!(report.ends_with("\nbacktrace:\n0: main\n"));
+    }
+
+    #[test]
+    fn progress_line_works() {
+        let totals = Counts { renamed: 3, skipped: 2, failed: 1, deferred: 0 };
+        assert_eq!("7 processed, 3 renamed, 2 skipped [0:00:05]",
+                   progress_line(7, None, &totals, time::Duration::from_secs(5)));
+        assert_eq!("0 processed, 0 renamed, 0 skipped [2:03:04]",
+                   progress_line(0, None, &Counts::default(), time::Duration::from_secs(7384)));
+        assert_eq!("12,345 / 87,000 processed, 3 renamed, 2 skipped [0:00:10] ETA 0:01:00",
+                   progress_line(12345, Some(87000), &totals, time::Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn digit_grouping() {
+        assert_eq!("0", group_digits(0));
+        assert_eq!("999", group_digits(999));
+        assert_eq!("1,000", group_digits(1000));
+        assert_eq!("12,345,678", group_digits(12345678));
+    }
+
+    #[test]
+    fn count_entries_works() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let root = tmp_dir.path().join("a");
+        if fs::create_dir_all(root.join("b").join("c")).is_err() ||
+           fs::create_dir_all(root.join("_d")).is_err() ||
+           fs::File::create(root.join("e")).is_err() ||
+           fs::File::create(root.join("b").join("f")).is_err() ||
+           fs::File::create(root.join("b").join("c").join("g")).is_err() ||
+           fs::File::create(root.join("_d").join("h")).is_err() {
+            return;
+        }
+        // "_d" itself is looked at but not descended into.
+        assert_eq!(4, count_entries(&root, &FlattenOptions::default()));
+        let mut report = Report::new(&root);
+        flatten(&root, "", &FlattenOptions::default(), &mut report).unwrap();
+        assert_eq!(4, report.processed);
+    }
+
+    #[test]
+    fn max_depth() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        for order in &[Order::DepthFirst, Order::BreadthFirst] {
+            let root = tmp_dir.path().join(format!("{:?}", order)).join("a");
+            if fs::create_dir_all(root.join("b").join("c")).is_err() ||
+               fs::File::create(root.join("d")).is_err() ||
+               fs::File::create(root.join("b").join("e")).is_err() ||
+               fs::File::create(root.join("b").join("c").join("f")).is_err() {
+                return;
+            }
+
+            let options = FlattenOptions { order: *order, max_depth: Some(2),
+                                           ..FlattenOptions::default() };
+            assert_eq!(2, count_entries(&root, &options));
+            let renames = plan(&root, &options, &mut Report::new(&root)).unwrap();
+            assert_eq!(2, renames.len());
+            let lines = explain(&root, &root.join("b").join("c").join("f"), &options).unwrap();
+            assert_eq!(Some(&"result: skipped".to_string()), lines.last());
+
+            flatten(&root, "", &options, &mut Report::new(&root)).unwrap();
+            assert!(root.join("a - d").exists());
+            assert!(root.join("b").join("a - b - e").exists());
+            assert!(root.join("b").join("c").join("f").exists());
+        }
+    }
+
+    #[test]
+    fn progress_snapshot_works() {
+        let totals = Counts { renamed: 6, skipped: 3, failed: 0, deferred: 1 };
+        let snapshot = progress_snapshot(&totals, 2, time::Duration::from_secs(2), true);
+        assert_eq!(json!(10), snapshot["scanned"]);
+        assert_eq!(json!(6), snapshot["renamed"]);
+        assert_eq!(json!(2), snapshot["warnings"]);
+        assert_eq!(json!(5.0), snapshot["rate"]);
+        assert_eq!(json!(true), snapshot["done"]);
+    }
+
+    #[test]
+    fn control_commands() {
+        let control = Control::default();
+        let totals = Counts::default();
+
+        assert_eq!(json!("running"), control.command("status\n")["state"]);
+        assert_eq!(json!("paused"), control.command("pause")["state"]);
+        assert_eq!(json!("running"), control.command("resume")["state"]);
+        assert!(control.checkpoint(&totals, 0));
+        assert!(control.command("jump").get("error").is_some());
+        assert_eq!(json!("cancelled"), control.command("cancel")["state"]);
+        assert!(!control.checkpoint(&totals, 0));
+        assert!(control.is_cancelled());
+    }
+
+    #[test]
+    fn control_resume_unblocks_checkpoint() {
+        use std::sync::Arc;
+
+        let control = Arc::new(Control::default());
+        control.command("pause");
+        let paused = control.clone();
+        let waiter = std::thread::spawn(move || paused.checkpoint(&Counts::default(), 0));
+        control.command("resume");
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn report_status_line() {
+        let root = path::Path::new("/tmp/a");
+        let mut report = Report::new(root);
+        report.record(&root.join("file"), Outcome::Renamed);
+        assert_eq!("1 renamed, 0 skipped, 0 deferred, 0 warnings", report.status_line());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pause_signals() {
+        use std::sync::atomic::Ordering;
+
+        install_pause_signals();
+        unsafe {
+            libc::raise(libc::SIGUSR1);
+        }
+        let paused = SIGNAL_PAUSED.load(Ordering::SeqCst);
+        unsafe {
+            libc::raise(libc::SIGUSR2);
+        }
+        assert!(paused);
+        assert!(!SIGNAL_PAUSED.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn truncate_utf16_keeps_extension() {
+        assert_eq!("abc.txt", truncate_utf16("abc.txt", 10));
+        assert_eq!("abcd.txt", truncate_utf16("abcdefgh.txt", 8));
+        assert_eq!("abcdefgh", truncate_utf16("abcdefgh.longextension", 8));
+        assert_eq!("abcdefgh", truncate_utf16("abcdefghijk", 8));
+        // "é" is a single UTF-16 code unit even though it is two bytes.
+        assert_eq!("éé.txt", truncate_utf16("éééé.txt", 6));
+    }
+
+    #[test]
+    fn iso9660_profile() {
+        let profile = TargetProfile::Iso9660;
+        assert_eq!(("a - b_ c_.txt".to_string(), Vec::<String>::new()),
+                   profile.apply("a - b: c?.txt", 2));
+        assert_eq!("a_.txt", profile.apply("a\u{1F600}.txt", 1).0);
+
+        let long_name = "x".repeat(70) + ".flac";
+        let (name, problems) = profile.apply(&long_name, 1);
+        assert_eq!(JOLIET_MAX_NAME, name.chars().count());
+        assert!(name.ends_with(".flac"));
+        assert_eq!(1, problems.len());
+
+        assert_eq!(1, profile.apply("a.txt", ISO9660_MAX_DEPTH + 1).1.len());
+    }
+
+    #[test]
+    fn fat_profile() {
+        let profile = TargetProfile::Fat;
+        assert_eq!(("a - b_ c_ d_.txt".to_string(), Vec::<String>::new()),
+                   profile.apply("a - b: c? d|.txt", 2));
+        assert_eq!("a - b", profile.apply("a - b. .", 1).0);
+        assert_eq!("con_.txt", profile.apply("con.txt", 1).0);
+        assert_eq!("a - con", profile.apply("a - con", 1).0);
+
+        let (name, problems) = profile.apply("...", 1);
+        assert_eq!("_", name);
+        assert_eq!(1, problems.len());
+
+        let long_name = "x".repeat(300) + ".flac";
+        let (name, problems) = profile.apply(&long_name, 1);
+        assert_eq!(FAT_MAX_NAME, name.chars().count());
+        assert_eq!(1, problems.len());
+    }
+
+    #[test]
+    fn profile_for_filesystem_works() {
+        assert_eq!(Some(TargetProfile::Fat), profile_for_filesystem("vfat"));
+        assert_eq!(Some(TargetProfile::Fat), profile_for_filesystem("cifs"));
+        assert_eq!(Some(TargetProfile::Iso9660), profile_for_filesystem("iso9660"));
+        assert_eq!(None, profile_for_filesystem("ext4"));
+    }
+
+    #[test]
+    fn is_case_sensitive_probe() {
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let mut path_buf = tmp_dir.path().to_path_buf();
+        path_buf.push("Probe");
+        if fs::File::create(&path_buf).is_err() {
+            return;
+        }
+        path_buf.pop();
+        path_buf.push("pROBE");
+        let expected = !path_buf.exists();
+
+        assert_eq!(expected, is_case_sensitive(tmp_dir.path()));
+    }
+
+    #[test]
+    fn odd_paths_do_not_panic() {
+        for odd in &["", "/", ".", "..", "a/", "\u{0}", "\u{fffd}", "\u{10000}.txt"] {
+            let odd = path::Path::new(odd);
+            leading_char(odd);
+            let _ = filename_str(odd);
+            let _ = directory_prefix(odd, "a", &FlattenOptions::default());
+            portable_path(path::Path::new("/"), odd);
+            for profile in &[TargetProfile::Iso9660, TargetProfile::Fat] {
+                profile.apply(&odd.to_string_lossy(), 0);
+            }
+        }
+        assert_eq!('\0', leading_char(path::Path::new("/")));
+        assert!(filename_str(path::Path::new("/")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn flatten_skips_non_utf8_filenames() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp_dir = tempdir::TempDir::new("test");
+        if tmp_dir.is_err() {
+            return;
+        }
+        let tmp_dir = tmp_dir.unwrap();
+        let dir = tmp_dir.path().join("a");
+        let odd = dir.join(OsStr::from_bytes(b"\xff"));
+        if fs::create_dir(&dir).is_err() || fs::File::create(&odd).is_err() {
+            return;
+        }
+
+        let mut report = Report::new(tmp_dir.path());
+        flatten(&dir, "", &FlattenOptions::default(), &mut report).unwrap();
+        assert!(odd.exists());
+        assert_eq!(vec![(odd.clone(), SkipReason::NonUtf8)], report.skips);
+        assert_eq!(1, report.warnings.len());
+
+        let options = FlattenOptions { non_utf8: NonUtf8::Lossy, ..FlattenOptions::default() };
+        let odd_dir = dir.join(OsStr::from_bytes(b"b\xfe"));
+        if fs::create_dir(&odd_dir).is_err() || fs::File::create(odd_dir.join("c")).is_err() {
+            return;
+        }
+        let mut report = Report::new(tmp_dir.path());
+        flatten(&dir, "", &options, &mut report).unwrap();
+        assert!(!odd.exists());
+        assert!(dir.join("a - \u{fffd}").exists());
+        assert!(odd_dir.join("a - b\u{fffd} - c").exists());
+        assert!(report.warnings.is_empty());
+    }
+}
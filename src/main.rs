@@ -1,549 +1,1111 @@
+extern crate clap;
+extern crate flatten_filenames;
+
 use std::env;
-use std::error::Error;
 use std::fs;
+use std::io::IsTerminal;
 use std::io::Write;  // Need `write_fmt()` method for `writeln!()`.
 use std::path;
 use std::process;
+use std::sync::Arc;
+use std::time;
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+
+use flatten_filenames::*;
+
+/// Exit status of a run that found nothing to rename.
+const EXIT_NOTHING_TO_DO: i32 = 2;
+
+/// Exit status of a run that finished but failed to rename some files.
+const EXIT_FILE_ERRORS: i32 = 3;
+
+/// Exit status of a run that renamed nothing because of collisions.
+const EXIT_COLLISIONS: i32 = 4;
+
+/// Environment variable holding the passphrase for `--encrypt-map`.
+const PASSPHRASE_VAR: &str = "FLATTEN_FILENAMES_PASSPHRASE";
 
 /// Prints a message to `std::io::stderr`.
 fn println_stderr(message: String) {
-    let r = writeln!(&mut std::io::stderr(), "{}", message);
-    r.expect("failed to write to stderr");
+    let _ = writeln!(&mut std::io::stderr(), "{}", message);
 }
 
-/// Extract the leading character of a path.
-pub fn leading_char(path: &path::PathBuf) -> char {
-    let filename = path.file_name().expect("path lacks filename");
-    let filename_str = filename.to_str().expect("filename as str");
-    filename_str.chars().next().unwrap()
+/// Arguments shared by running without a subcommand, `flatten`, and
+/// `plan`.
+fn flatten_args() -> Vec<Arg> {
+    vec![
+        Arg::new("directory")
+            .value_name("DIRECTORY")
+            .help("Directory to flatten")
+            .required_unless_present_any(["list-schemes", "try"]),
+        Arg::new("explain")
+            .long("explain")
+            .value_name("PATH")
+            .help("Print why PATH would be skipped or what it would be renamed to"),
+        Arg::new("try")
+            .long("try")
+            .value_name("PATH")
+            .help("Print the name a file at the hypothetical PATH would be given"),
+        Arg::new("estimate")
+            .long("estimate")
+            .action(ArgAction::SetTrue)
+            .help("Only estimate how many renames a run would make"),
+        Arg::new("analyze")
+            .long("analyze")
+            .action(ArgAction::SetTrue)
+            .help("Report colliding, similar, deep, and long new names"),
+        Arg::new("export-listing")
+            .long("export-listing")
+            .value_name("PATH")
+            .help("Write every entry of the tree as it would be after renaming to PATH, \
+                   without renaming anything"),
+        Arg::new("listing-format")
+            .long("listing-format")
+            .value_name("FORMAT")
+            .value_parser(["text", "json"])
+            .requires("export-listing")
+            .help("Format of the --export-listing file [default: text]"),
+        Arg::new("visualize")
+            .long("visualize")
+            .value_name("FORMAT")
+            .value_parser(["dot", "mermaid"])
+            .help("Print the tree and its new names as a graph"),
+        Arg::new("quiet")
+            .short('q')
+            .long("quiet")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("verbose")
+            .help("Only print errors to stderr, not warnings"),
+        Arg::new("verbose")
+            .short('v')
+            .long("verbose")
+            .action(ArgAction::Count)
+            .help("Print every rename to stderr as it happens; given twice, also every \
+                   skipped entry and whether each directory is descended into"),
+        Arg::new("as-user")
+            .long("as-user")
+            .value_name("USER")
+            .help("When run as root, switch to USER (and their groups) before touching any \
+                   files"),
+        Arg::new("dry-run")
+            .long("dry-run")
+            .action(ArgAction::SetTrue)
+            .help("Print every rename as `old -> new` instead of making it"),
+        Arg::new("emit-script")
+            .long("emit-script")
+            .value_name("SHELL")
+            .value_parser(["sh", "powershell"])
+            .conflicts_with_all(["report-format", "merge-dirs"])
+            .help("Print every rename as a command of a SHELL script instead of making it"),
+        Arg::new("report-format")
+            .long("report-format")
+            .value_name("FORMAT")
+            .value_parser(["text", "json", "jsonl", "html"])
+            .help("Print a report of the run"),
+        Arg::new("journal")
+            .long("journal")
+            .value_name("PATH")
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("")
+            .help("Record every rename in PATH (default: DIRECTORY/.flatten_undo.json) \
+                   for `undo`"),
+        Arg::new("manifest")
+            .long("manifest")
+            .value_name("PATH")
+            .help("Record every renamed or failed file in PATH as CSV for auditing"),
+        Arg::new("fsync")
+            .long("fsync")
+            .action(ArgAction::SetTrue)
+            .help("Sync each directory to disk after renaming in it, before journaling \
+                   the renames"),
+        Arg::new("on-collision")
+            .long("on-collision")
+            .value_name("POLICY")
+            .value_parser(["fail", "skip", "overwrite", "number"])
+            .help("What to do when a new name is already taken [default: fail]"),
+        Arg::new("merge-dirs")
+            .long("merge-dirs")
+            .action(ArgAction::SetTrue)
+            .help("Merge sibling directories whose names only differ by case or punctuation \
+                   after renaming"),
+        Arg::new("git")
+            .long("git")
+            .action(ArgAction::SetTrue)
+            .help("Rename files tracked by git with `git mv`"),
+        Arg::new("jobs")
+            .long("jobs")
+            .short('j')
+            .value_name("N")
+            .value_parser(parse_positive)
+            .conflicts_with("git")
+            .help("Read directories with N threads and rename up to N directories at once, \
+                   each one a single entry at a time"),
+        Arg::new("clear-immutable")
+            .long("clear-immutable")
+            .action(ArgAction::SetTrue)
+            .help("Rename immutable and append-only files by clearing the flag and setting it \
+                   again afterwards (needs privileges)"),
+        Arg::new("find-duplicates")
+            .long("find-duplicates")
+            .action(ArgAction::SetTrue)
+            .help("Report files with the same size and contents"),
+        Arg::new("max-collisions")
+            .long("max-collisions")
+            .value_name("N")
+            .value_parser(clap::value_parser!(usize))
+            .help("Give up planning once more than N collisions are found"),
+        Arg::new("keep-going")
+            .long("keep-going")
+            .action(ArgAction::SetTrue)
+            .help("Carry on after a rename fails instead of stopping the run"),
+        Arg::new("max-errors")
+            .long("max-errors")
+            .value_name("N")
+            .value_parser(clap::value_parser!(usize))
+            .requires("keep-going")
+            .help("With --keep-going, stop the run anyway once more than N renames fail"),
+        Arg::new("no-preflight")
+            .long("no-preflight")
+            .action(ArgAction::SetTrue)
+            .help("Skip checking for renames that would clobber something, and for \
+                   read-only mounts, before renaming"),
+        Arg::new("no-prescan")
+            .long("no-prescan")
+            .action(ArgAction::SetTrue)
+            .help("Start renaming right away instead of first counting entries so the progress \
+                   bar can show a total and an ETA"),
+        Arg::new("stats-json")
+            .long("stats-json")
+            .value_name("PATH")
+            .help("Append JSON progress snapshots to PATH"),
+        Arg::new("metrics-textfile")
+            .long("metrics-textfile")
+            .value_name("PATH")
+            .help("Write Prometheus metrics about the run to PATH when it finishes"),
+        Arg::new("control-socket")
+            .long("control-socket")
+            .value_name("PATH")
+            .help("Accept status, pause, resume, and cancel on a Unix socket at PATH"),
+        Arg::new("settle")
+            .long("settle")
+            .value_name("DURATION")
+            .value_parser(parse_duration)
+            .help("Skip directories modified less than DURATION ago"),
+        Arg::new("defer-growing")
+            .long("defer-growing")
+            .action(ArgAction::SetTrue)
+            .help("Leave files that are still being written alone"),
+        Arg::new("order")
+            .long("order")
+            .value_name("ORDER")
+            .value_parser(["depth-first", "breadth-first"])
+            .help("Order directories are processed in"),
+        Arg::new("max-depth")
+            .long("max-depth")
+            .value_name("N")
+            .value_parser(parse_positive)
+            .help("Only flatten the top N levels, leaving deeper directories as they are"),
+        Arg::new("max-open")
+            .long("max-open")
+            .value_name("N")
+            .value_parser(parse_positive)
+            .help("Hold at most N directory handles open"),
+        Arg::new("anchor")
+            .long("anchor")
+            .value_names(["ANCHOR", "DIR"])
+            .num_args(1..=2)
+            .help("Which directory starts the prefix: root-included, root-excluded, \
+                   or absolute-from DIR"),
+        Arg::new("dot-files")
+            .long("dot-files")
+            .value_name("POLICY")
+            .value_parser(["hidden", "hidden-unless-extension"])
+            .help("Which files starting with '.' are hidden and left alone"),
+        Arg::new("rename-dot-file")
+            .long("rename-dot-file")
+            .value_name("NAME")
+            .action(ArgAction::Append)
+            .help("Rename the file NAME even though it starts with '.'"),
+        Arg::new("delete-empty")
+            .long("delete-empty")
+            .action(ArgAction::SetTrue)
+            .help("Delete zero-byte files instead of renaming them"),
+        Arg::new("delete-junk")
+            .long("delete-junk")
+            .action(ArgAction::SetTrue)
+            .help("Delete Thumbs.db and .DS_Store files instead of renaming them"),
+        Arg::new("junk")
+            .long("junk")
+            .value_name("GLOB")
+            .action(ArgAction::Append)
+            .help("Delete files whose names match GLOB (with * and ?) instead of renaming them"),
+        Arg::new("strip-numbers")
+            .long("strip-numbers")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("pad-numbers")
+            .help("Drop numbers leading directory names, like the 01 of `01 Intro`"),
+        Arg::new("pad-numbers")
+            .long("pad-numbers")
+            .value_name("WIDTH")
+            .value_parser(clap::value_parser!(usize))
+            .help("Pad numbers leading directory names with zeros to WIDTH digits"),
+        Arg::new("normalize-numerals")
+            .long("normalize-numerals")
+            .action(ArgAction::SetTrue)
+            .help("Turn Roman numerals and ordinals in directory names into numbers, \
+                   like `part 2` for `Part II`"),
+        Arg::new("separator")
+            .long("separator")
+            .value_name("SEP")
+            .value_parser(parse_separator)
+            .help("Join directory names and the filename with SEP [default: \" - \"]"),
+        Arg::new("case")
+            .long("case")
+            .value_name("CASE")
+            .value_parser(["lower", "upper", "title", "preserve"])
+            .help("Case new names are put in [default: lower]"),
+        Arg::new("case-locale")
+            .long("case-locale")
+            .value_name("LOCALE")
+            .value_parser(["tr", "az"])
+            .help("Change case following the rules of LOCALE instead of Unicode's defaults"),
+        Arg::new("normalize")
+            .long("normalize")
+            .value_name("FORM")
+            .value_parser(["nfc", "nfd", "none"])
+            .help("Put directory names and filenames in Unicode normalization FORM \
+                   [default: none]"),
+        Arg::new("non-utf8")
+            .long("non-utf8")
+            .value_name("POLICY")
+            .value_parser(["skip", "lossy"])
+            .help("What to do with names which aren't UTF-8 [default: skip]"),
+        Arg::new("fold-diacritics")
+            .long("fold-diacritics")
+            .action(ArgAction::SetTrue)
+            .help("Strip accents and other marks, so `é` becomes `e`"),
+        Arg::new("fold-quotes")
+            .long("fold-quotes")
+            .action(ArgAction::SetTrue)
+            .help("Turn curly quotes into straight ones"),
+        Arg::new("drop-apostrophes")
+            .long("drop-apostrophes")
+            .action(ArgAction::SetTrue)
+            .help("Drop apostrophes, straight or curly"),
+        Arg::new("opaque")
+            .long("opaque")
+            .value_name("SEED")
+            .help("Replace directory names in prefixes with opaque tokens derived from SEED"),
+        Arg::new("opaque-map")
+            .long("opaque-map")
+            .value_name("PATH")
+            .requires("opaque")
+            .help("Write which directory name each opaque token stands for to PATH"),
+        Arg::new("encrypt-map")
+            .long("encrypt-map")
+            .action(ArgAction::SetTrue)
+            .requires("opaque-map")
+            .help("Encrypt the --opaque-map file with the passphrase in \
+                   $FLATTEN_FILENAMES_PASSPHRASE, in the age format"),
+        Arg::new("scheme")
+            .long("scheme")
+            .value_name("NAME")
+            .help("Let the flatten-scheme-NAME executable pick new filenames"),
+        Arg::new("list-schemes")
+            .long("list-schemes")
+            .action(ArgAction::SetTrue)
+            .help("List the naming schemes found on PATH"),
+        Arg::new("target-profile")
+            .long("target-profile")
+            .value_name("PROFILE")
+            .value_parser(["iso9660", "fat", "exfat"])
+            .help("Keep new names within a filesystem's restrictions"),
+        Arg::new("assume-fs")
+            .long("assume-fs")
+            .value_name("TYPE")
+            .help("Treat the directory as living on a filesystem of TYPE"),
+    ]
 }
 
-/// Check if a `entry` is a directory that doesn't have any special
-/// leading characters.
-///
-/// The characters that signal not to traverse into a directory are
-/// '.' and '_'.
-pub fn should_traverse(entry: &fs::DirEntry) -> bool {
-    let metadata = entry.metadata();
-    if metadata.is_err() {
-        println_stderr(format!("path missing metadata: {:?}", entry.path()));
-        return false;
-    }
-
-    if metadata.unwrap().is_dir() {
-        let path = entry.path();
-        let leading_char = leading_char(&path);
-        if leading_char != '.' && leading_char != '_' {
-            true
-        } else {
-            false
-        }
-    } else {
-        false
+/// Parse the value of `--max-open` or `--jobs`.
+fn parse_positive(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(value) if value > 0 => Ok(value),
+        _ => Err("expects a positive number".to_string()),
     }
 }
 
-/// Rename a file with a prefix.
-///
-/// If the file starts with '.' then skip the renaming.
-pub fn rename(path: &path::PathBuf, prefix: &str) {
-    if leading_char(path) == '.' {
-        return;
-    }
-
-    let os_filename = path.file_name().expect("path lacks a filename");
-    let filename = os_filename.to_str().expect("filename not UTF-8");
-    let new_filename = prefix.to_string() + " - " + filename;
-    let mut new_path = path.clone();
-    new_path.pop();
-    new_path.push(new_filename.to_lowercase());
-    let r = fs::rename(path.as_path(), new_path.as_path());
-    if r.is_err() {
-        panic!(r);
+/// Parse a `--separator` value, which must fit within a filename.
+fn parse_separator(value: &str) -> Result<String, String> {
+    if value.is_empty() || value.contains(['/', '\\']) {
+        Err("expects a non-empty string without `/` or `\\`".to_string())
+    } else {
+        Ok(value.to_string())
     }
 }
 
-/// Create the filename prefix.
-///
-/// If a new part starts with '-' or '+' then strip it off.
-pub fn new_prefix(old_prefix: &str, tail: &str) -> String {
-    let mut postfix = tail;
-    if tail[0..1] == "+".to_string() || tail[0..1] == "-".to_string() {
-            postfix = &tail[1..];
-    }
-    if old_prefix.is_empty() {
-        postfix.to_string().to_lowercase()
-    } else {
-        (old_prefix.to_string() + " - " + postfix).to_lowercase()
-    }
+/// The command line interface.
+fn cli() -> Command {
+    Command::new("flatten-filenames")
+        .version(env!("CARGO_PKG_VERSION"))
+        .disable_version_flag(true)
+        .about("Flatten filenames by embedding directory names in a file's name")
+        .arg(Arg::new("version")
+                 .short('V')
+                 .long("version")
+                 .action(ArgAction::SetTrue)
+                 .help("Print version; with --verbose, also the target, features, and format \
+                        versions of this build"))
+        .args(flatten_args())
+        .mut_arg("directory", |arg| {
+            arg.required_unless_present_any(["list-schemes", "try", "version"])
+        })
+        .args_conflicts_with_subcommands(true)
+        .subcommand(Command::new("flatten")
+                        .about("Rename files (the default without a subcommand)")
+                        .args(flatten_args()))
+        .subcommand(Command::new("plan")
+                        .about("Print every rename as `old -> new` without making it")
+                        .args(flatten_args())
+                        .arg(Arg::new("output")
+                                 .long("output")
+                                 .short('o')
+                                 .value_name("PLAN")
+                                 .help("Write the renames to PLAN for `apply` instead")))
+        .subcommand(Command::new("apply")
+                        .about("Make the renames in a plan written by `plan --output`, or in \
+                                a CSV manifest written by --manifest")
+                        .arg(Arg::new("plan")
+                                 .value_name("PLAN")
+                                 .required(true)
+                                 .help("Plan or manifest to apply"))
+                        .arg(Arg::new("journal")
+                                 .long("journal")
+                                 .value_name("PATH")
+                                 .help("Record every rename in PATH for `undo`"))
+                        .arg(Arg::new("max-drift")
+                                 .long("max-drift")
+                                 .value_name("N")
+                                 .value_parser(clap::value_parser!(usize))
+                                 .help("Tolerate up to N renames that no longer match the \
+                                        tree, abandoning the rest of the plan past that"))
+                        .arg(Arg::new("expect-hash")
+                                 .long("expect-hash")
+                                 .value_name("HASH")
+                                 .help("Refuse to apply the plan unless its hash, which \
+                                        covers the files it renames, is still HASH"))
+                        .arg(Arg::new("rebase")
+                                 .long("rebase")
+                                 .value_names(["OLD", "NEW"])
+                                 .num_args(2)
+                                 .conflicts_with("expect-hash")
+                                 .help("Apply the renames under OLD to the copy of it at NEW \
+                                        instead")))
+        .subcommand(Command::new("undo")
+                        .about("Undo the renames recorded in a journal by --journal")
+                        .arg(Arg::new("journal")
+                                 .value_name("JOURNAL")
+                                 .required(true)
+                                 .help("Journal of the run to undo"))
+                        .arg(Arg::new("paths")
+                                 .value_name("PATH")
+                                 .num_args(0..)
+                                 .help("Only undo what the run did to these paths or inside \
+                                        these directories"))
+                        .arg(Arg::new("include")
+                                 .long("include")
+                                 .value_name("GLOB")
+                                 .action(ArgAction::Append)
+                                 .help("Only undo what the run did to paths matching GLOB \
+                                        (with * and ?)"))
+                        .arg(Arg::new("exclude")
+                                 .long("exclude")
+                                 .value_name("GLOB")
+                                 .action(ArgAction::Append)
+                                 .help("Leave alone what the run did to paths matching GLOB"))
+                        .arg(Arg::new("dry-run")
+                                 .long("dry-run")
+                                 .action(ArgAction::SetTrue)
+                                 .help("Print every rename as `new -> old` and every problem \
+                                        without undoing anything"))
+                        .arg(Arg::new("on-collision")
+                                 .long("on-collision")
+                                 .value_name("POLICY")
+                                 .value_parser(["fail", "skip", "overwrite", "number"])
+                                 .help("What to do when an old name has since been taken \
+                                        [default: fail]")))
 }
 
-/// "Flattens" `directory by prepending `prefix` plus the directories
-/// name.
-///
-/// Certain considerations are taken into account based on the leading
-/// character of the directory's name.
-pub fn flatten(directory: &path::PathBuf, prev_prefix: &str) {
-    let filename = directory.file_name().expect("directory lacks a tail");
-    let path_tail = filename.to_str().expect("can't decode path tail");
-    let prefix = new_prefix(prev_prefix, path_tail);
-    let prefix_str = prefix.as_str();
-    for entry in directory.read_dir().unwrap() {
-        let entry = entry.unwrap();
-        let entry_path = entry.path();
-        if should_traverse(&entry) {
-            flatten(&entry_path, prefix_str);
+fn main() {
+    let arguments = env::args_os().map(|argument| argument.to_string_lossy().into_owned())
+                                  .collect();
+    install_panic_hook(new_run_id(), arguments);
+    // Usage errors exit with 1 as status 2 means there was nothing to do.
+    let matches = match cli().try_get_matches() {
+        Ok(matches) => matches,
+        Err(e) => {
+            let _ = e.print();
+            process::exit(if e.use_stderr() { 1 } else { 0 });
+        }
+    };
+    if matches.get_flag("version") {
+        if matches.get_count("verbose") > 0 {
+            println!("{}", build_details());
         } else {
-            rename(&entry_path, prefix_str);
+            println!("flatten-filenames {}", env!("CARGO_PKG_VERSION"));
         }
+        return;
+    }
+    match matches.subcommand() {
+        Some(("apply", matches)) => run_apply(matches),
+        Some(("undo", matches)) => run_undo(matches),
+        Some(("plan", matches)) => run(matches, true),
+        Some((_, matches)) => run(matches, false),
+        None => run(&matches, false),
     }
 }
 
-fn main() {
-    // Parse arguments.
-    let mut args = env::args();
-    // Program name (argument 0).
-    args.next().expect("no program name specified!?!");
+/// Print `report` in the format asked for by `--report-format`, if any,
+/// returning whether it was printed.
+fn print_report(matches: &ArgMatches, report: &Report) -> bool {
+    match matches.get_one::<String>("report-format").map(String::as_str) {
+        Some("json") => println!("{}", report.render_json()),
+        // Streamed as the run went.
+        Some("jsonl") => return false,
+        Some("html") => print!("{}", report.render_html()),
+        Some(_) => print!("{}", report.render_text()),
+        None => return false,
+    }
+    true
+}
 
-    // Directory to process (argument 1).
-    let directory = match args.next() {
-        Some(dir) => dir,
-        None => {
-            println_stderr("Expected an argument".to_string());
+/// Write the tokens handed out by `options.opaque` to the path given by
+/// `--opaque-map`, if any.
+fn write_opaque_map(matches: &ArgMatches, options: &FlattenOptions) {
+    let path = matches.get_one::<String>("opaque-map");
+    if let (Some(path), Some(opaque)) = (path, &options.opaque) {
+        let written = if matches.get_flag("encrypt-map") {
+            opaque.render_encrypted(&map_passphrase())
+                  .and_then(|encrypted| fs::write(path, encrypted))
+        } else {
+            fs::write(path, opaque.render_json() + "\n")
+        };
+        if let Err(e) = written {
+            println_stderr(format!("{}: {}", path, e));
             process::exit(1);
         }
-    };
-
-    // Already consumed all the arguments that I care about.
-    if args.next().is_some() {
-        println_stderr(format!("expected only 1 argument, not {}", args.len() + 1));
-        process::exit(1);
     }
+}
 
-    let path = match path::Path::new(&directory).canonicalize() {
-        Ok(o) => o,  // Using o.as_path() won't work as `o` leaves the scope.
-        Err(e) => {
-            println_stderr(e.description().to_string());
+/// The passphrase for `--encrypt-map`, exiting if there isn't one.
+fn map_passphrase() -> String {
+    match env::var(PASSPHRASE_VAR) {
+        Ok(passphrase) if !passphrase.is_empty() => passphrase,
+        _ => {
+            println_stderr(format!("--encrypt-map needs a passphrase in ${}", PASSPHRASE_VAR));
             process::exit(1);
         }
-    };
-
-    if !path.is_dir() {
-        println_stderr("argument is not a directory".to_string());
-        process::exit(1);
     }
-
-    flatten(&path, "");
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-
-    use std::fs;
-    use std::path;
-
-    extern crate tempdir;
+/// Create the report for a run of `path`, as quiet or verbose as asked.
+fn new_report(matches: &ArgMatches, path: &path::Path) -> Report {
+    let mut report = Report::new(path);
+    report.quiet = matches.get_flag("quiet");
+    report.verbose = matches.get_count("verbose");
+    report.max_errors = matches.get_one::<usize>("max-errors").copied();
+    report
+}
 
-    #[test]
-    fn leading_char_for_filename() {
-        let mut path = path::PathBuf::new();
-        path.push("/tmp");
-        path.push("file.txt");
-        assert_eq!(leading_char(&path), 'f');
+/// Report `e` and exit with an error status.
+fn fail<T>(e: FlattenError) -> T {
+    println_stderr(e.to_string());
+    match e {
+        FlattenError::TooManyCollisions(_) => process::exit(EXIT_COLLISIONS),
+        _ => process::exit(1),
     }
+}
 
-    #[test]
-    fn should_traverse_not_dir() {
-        // Create a temporary directory.
-        let tmp_dir = tempdir::TempDir::new("test");
-        if tmp_dir.is_err() {
-            return;
-        }
-        let tmp_dir = tmp_dir.unwrap();
-
-        // Create a file.
-        let tmp_dir_path = tmp_dir.path();
-        let mut path_buf = tmp_dir_path.to_path_buf();
-        path_buf.push("file.txt");
-        let f = fs::File::create(&path_buf);
-        if f.is_err() {
-            return;
+/// Act on the arguments of the `apply` subcommand.
+fn run_apply(matches: &ArgMatches) {
+    let plan = matches.get_one::<String>("plan").map(String::as_str).unwrap_or_default();
+    if let Some(expected) = matches.get_one::<String>("expect-hash") {
+        match read_journal(path::Path::new(plan)) {
+            Ok(renames) => {
+                let actual = plan_hash(&renames);
+                if &actual != expected {
+                    println_stderr(format!("the plan or the files it renames have changed \
+                                            (hash {}), so nothing was renamed", actual));
+                    process::exit(1);
+                }
+            }
+            Err(e) => fail(e),
         }
-        let f = f.unwrap();
-        // Flush the file.
-        if f.sync_all().is_err() {
-            return;
+    }
+    let mut journal = None;
+    if let Some(journal_path) = matches.get_one::<String>("journal") {
+        match fs::File::create(journal_path) {
+            Ok(file) => journal = Some(file),
+            Err(e) => {
+                println_stderr(format!("{}: {}", journal_path, e));
+                process::exit(1);
+            }
         }
-
-        // Get the temporary directory's content.
-        let read_dir = path_buf.read_dir();
-        if read_dir.is_err() {
-            return;
+    }
+    let max_drift = matches.get_one::<usize>("max-drift").copied();
+    let journal = journal.as_mut().map(|file| file as &mut dyn Write);
+    let rebase: Option<Vec<&path::Path>> = matches.get_many::<String>("rebase")
+        .map(|directories| directories.map(path::Path::new).collect());
+    let rebase = rebase.as_ref().map(|directories| (directories[0], directories[1]));
+    match apply(path::Path::new(plan), journal, max_drift, rebase) {
+        Ok(applied) => {
+            for drift in &applied.drifted {
+                println_stderr(format!("drift: {}", drift));
+            }
+            for failure in &applied.failed {
+                println_stderr(failure.clone());
+            }
+            println_stderr(format!("{} renames made, {} drifted, {} failed", applied.renamed,
+                                   applied.drifted.len(), applied.failed.len()));
+            if applied.stopped {
+                println_stderr(format!("more than {} renames drifted, so the rest of the plan \
+                                        was abandoned", max_drift.unwrap_or_default()));
+            }
+            // Without a bound, any drift counts as a problem.
+            let drift_failed = max_drift.is_none() && !applied.drifted.is_empty();
+            if applied.stopped || drift_failed || !applied.failed.is_empty() {
+                process::exit(EXIT_FILE_ERRORS);
+            } else if applied.renamed == 0 {
+                process::exit(EXIT_NOTHING_TO_DO);
+            }
         }
-        let entry_item = read_dir.unwrap().last();
-        let entry_option = entry_item.unwrap();
-        let entry = entry_option.unwrap();
+        Err(e) => fail(e),
+    }
+}
 
-        assert!(!should_traverse(&entry));
+/// The `--on-collision` policy in `matches`.
+fn collision_policy(matches: &ArgMatches) -> CollisionPolicy {
+    match matches.get_one::<String>("on-collision").map(String::as_str) {
+        Some("skip") => CollisionPolicy::Skip,
+        Some("overwrite") => CollisionPolicy::Overwrite,
+        Some("number") => CollisionPolicy::Number,
+        _ => CollisionPolicy::Fail,
     }
+}
 
-    #[test]
-    fn should_traverse_not_leading_dot_or_underscore() {
-        let tmp_dir = tempdir::TempDir::new("test");
-        if tmp_dir.is_err() {
-            return;
+/// Act on the arguments of the `undo` subcommand.
+fn run_undo(matches: &ArgMatches) {
+    let journal = matches.get_one::<String>("journal").map(String::as_str).unwrap_or_default();
+    let mut options = UndoOptions {
+        include: matches.get_many::<String>("include")
+                        .map(|patterns| patterns.cloned().collect())
+                        .unwrap_or_default(),
+        exclude: matches.get_many::<String>("exclude")
+                        .map(|patterns| patterns.cloned().collect())
+                        .unwrap_or_default(),
+        dry_run: matches.get_flag("dry-run"),
+        on_collision: collision_policy(matches),
+        ..UndoOptions::default()
+    };
+    // Journals hold absolute paths.
+    for path in matches.get_many::<String>("paths").into_iter().flatten() {
+        match absolute_path(path::Path::new(path)) {
+            Ok(absolute) => options.paths.push(absolute),
+            Err(e) => {
+                println_stderr(format!("{}: {}", path, e));
+                process::exit(1);
+            }
         }
-        let tmp_dir = tmp_dir.unwrap();
-
-        let dir_builder = fs::DirBuilder::new();
-        let tmp_dir_path = tmp_dir.path();
-        let mut path_buf = tmp_dir_path.to_path_buf();
-        path_buf.push(".directory");
-        if dir_builder.create(path_buf.as_path()).is_err() {
-            return;
-        } else {
-            path_buf.pop();
+    }
+    match undo(path::Path::new(journal), &options) {
+        Ok((undone, problems)) => {
+            for problem in &problems {
+                println_stderr(problem.clone());
+            }
+            let verb = if options.dry_run { "would be undone" } else { "undone" };
+            println_stderr(format!("{} changes {}, {} left alone", undone, verb, problems.len()));
+            if !problems.is_empty() {
+                process::exit(EXIT_FILE_ERRORS);
+            }
         }
+        Err(e) => fail(e),
+    }
+}
 
-        path_buf.push("_directory");
-        if dir_builder.create(path_buf.as_path()).is_err() {
-            return;
-        } else {
-            path_buf.pop();
+/// Absolute paths of the files a run on `directory` with these arguments
+/// writes itself; see `FlattenOptions::artifacts`.
+fn run_artifacts(matches: &ArgMatches, directory: &path::Path, options: &FlattenOptions)
+                 -> Vec<path::PathBuf> {
+    let mut written: Vec<path::PathBuf> = ["stats-json", "control-socket", "opaque-map",
+                                           "export-listing"]
+        .iter()
+        .filter_map(|name| matches.get_one::<String>(name))
+        .map(path::PathBuf::from)
+        .collect();
+    if let Some(metrics) = matches.get_one::<String>("metrics-textfile") {
+        written.push(path::PathBuf::from(metrics));
+        written.push(path::PathBuf::from(format!("{}.partial", metrics)));
+    }
+    if !options.dry_run {
+        if let Some(journal) = matches.get_one::<String>("journal") {
+            written.push(if journal.is_empty() {
+                directory.join(DEFAULT_JOURNAL)
+            } else {
+                path::PathBuf::from(journal)
+            });
         }
+        written.extend(matches.get_one::<String>("manifest").map(path::PathBuf::from));
+    }
+    // Only `plan` has --output.
+    if let Ok(Some(output)) = matches.try_get_one::<String>("output") {
+        written.push(path::PathBuf::from(output));
+    }
+    written.iter().filter_map(|path| absolute_path(path).ok()).collect()
+}
 
-        // Get the temporary directory's content.
-        let read_dir = path_buf.read_dir();
-        if read_dir.is_err() {
-            return;
+/// Act on the arguments of a (possibly implied) `flatten` or `plan`
+/// subcommand.
+fn run(matches: &ArgMatches, plan_only: bool) {
+    if let Some(user) = matches.get_one::<String>("as-user") {
+        if let Err(e) = run_as_user(user) {
+            println_stderr(format!("--as-user {}: {}", user, e));
+            process::exit(1);
         }
-
-        let mut count = 0;
-        for entry in read_dir.unwrap() {
-            assert!(!should_traverse(&entry.unwrap()));
-            count += 1;
+    }
+    if matches.get_flag("list-schemes") {
+        for (name, scheme) in discover_schemes() {
+            println!("{}\t{}", name, scheme.display());
         }
-        assert_eq!(2, count);
+        return;
     }
 
-    #[test]
-    fn should_traverse_directory() {
-        let tmp_dir = tempdir::TempDir::new("test");
-        if tmp_dir.is_err() {
-            return;
+    let mut options = FlattenOptions {
+        settle: matches.get_one::<time::Duration>("settle").cloned(),
+        max_open: matches.get_one::<usize>("max-open").cloned(),
+        target_profile: matches.get_one::<String>("target-profile")
+                               .and_then(|name| TargetProfile::from_name(name)),
+        dry_run: plan_only || matches.get_flag("dry-run") ||
+                 matches.get_one::<String>("emit-script").is_some(),
+        fsync: matches.get_flag("fsync"),
+        keep_going: matches.get_flag("keep-going"),
+        merge_directories: matches.get_flag("merge-dirs"),
+        find_duplicates: matches.get_flag("find-duplicates"),
+        git: matches.get_flag("git"),
+        clear_immutable: matches.get_flag("clear-immutable"),
+        jobs: matches.get_one::<usize>("jobs").copied().unwrap_or(1),
+        max_depth: matches.get_one::<usize>("max-depth").copied(),
+        max_collisions: matches.get_one::<usize>("max-collisions").copied(),
+        dot_allowlist: matches.get_many::<String>("rename-dot-file")
+                              .map(|names| names.cloned().collect())
+                              .unwrap_or_default(),
+        delete_empty: matches.get_flag("delete-empty"),
+        junk: matches.get_many::<String>("junk")
+                     .map(|patterns| patterns.cloned().collect())
+                     .unwrap_or_default(),
+        ..FlattenOptions::default()
+    };
+    if matches.get_flag("delete-junk") {
+        options.junk.extend(DEFAULT_JUNK.iter().map(|name| name.to_string()));
+    }
+    if matches.get_flag("defer-growing") {
+        options.defer_growing = Some(time::Duration::from_secs(1));
+    }
+    if let Some(name) = matches.get_one::<String>("scheme") {
+        let schemes = discover_schemes();
+        match schemes.into_iter().find(|scheme| &scheme.0 == name) {
+            Some((_, scheme)) => options.scheme = Some(scheme),
+            None => {
+                println_stderr(format!("no {}{} executable found on PATH", SCHEME_PREFIX, name));
+                process::exit(1);
+            }
         }
-        let tmp_dir = tmp_dir.unwrap();
-
-        let dir_builder = fs::DirBuilder::new();
-        let tmp_dir_path = tmp_dir.path();
-        let mut path_buf = tmp_dir_path.to_path_buf();
-        path_buf.push("directory");
-        if dir_builder.create(path_buf.as_path()).is_err() {
-            return;
-        } else {
-            path_buf.pop();
+    }
+    if let Some(policy) = matches.get_one::<String>("dot-files") {
+        if policy == "hidden-unless-extension" {
+            options.dot_files = DotFiles::HiddenUnlessExtension;
         }
-
-        // Get the temporary directory's content.
-        let read_dir = path_buf.read_dir();
-        if read_dir.is_err() {
-            return;
+    }
+    if matches.get_flag("strip-numbers") {
+        options.numbering = Numbering::Strip;
+    } else if let Some(&width) = matches.get_one::<usize>("pad-numbers") {
+        options.numbering = Numbering::Pad(width);
+    }
+    options.on_collision = collision_policy(matches);
+    options.separator = matches.get_one::<String>("separator").cloned();
+    options.script = match matches.get_one::<String>("emit-script").map(String::as_str) {
+        Some("powershell") => Some(Script::PowerShell),
+        Some(_) => Some(Script::Sh),
+        None => None,
+    };
+    options.case = match matches.get_one::<String>("case").map(String::as_str) {
+        Some("upper") => Case::Upper,
+        Some("title") => Case::Title,
+        Some("preserve") => Case::Preserve,
+        _ => Case::Lower,
+    };
+    if matches.get_one::<String>("case-locale").is_some() {
+        options.case_locale = CaseLocale::Turkic;
+    }
+    options.normalization = match matches.get_one::<String>("normalize").map(String::as_str) {
+        Some("nfc") => Normalization::Nfc,
+        Some("nfd") => Normalization::Nfd,
+        _ => Normalization::None,
+    };
+    if matches.get_one::<String>("non-utf8").map(String::as_str) == Some("lossy") {
+        options.non_utf8 = NonUtf8::Lossy;
+    }
+    options.normalize_numerals = matches.get_flag("normalize-numerals");
+    options.opaque = matches.get_one::<String>("opaque").map(|seed| Opaque::new(seed));
+    if matches.get_flag("encrypt-map") {
+        // Find out about a missing passphrase before renaming anything.
+        map_passphrase();
+    }
+    options.folding = Folding {
+        diacritics: matches.get_flag("fold-diacritics"),
+        quotes: matches.get_flag("fold-quotes"),
+        apostrophes: matches.get_flag("drop-apostrophes"),
+    };
+    if let Some(order) = matches.get_one::<String>("order") {
+        if order == "breadth-first" {
+            options.order = Order::BreadthFirst;
         }
-
-        let mut count = 0;
-        for entry in read_dir.unwrap() {
-            assert!(should_traverse(&entry.unwrap()));
-            count += 1;
+    }
+    let mut anchor_from = None;
+    if let Some(anchor) = matches.get_many::<String>("anchor") {
+        let anchor: Vec<&String> = anchor.collect();
+        match (anchor[0].as_str(), anchor.get(1)) {
+            ("root-included", None) => options.anchor = Anchor::RootIncluded,
+            ("root-excluded", None) => options.anchor = Anchor::RootExcluded,
+            ("absolute-from", Some(ancestor)) => anchor_from = Some(ancestor.to_string()),
+            ("absolute-from", None) => {
+                println_stderr("--anchor absolute-from expects a directory".to_string());
+                process::exit(1);
+            }
+            _ => {
+                let anchor: Vec<&str> = anchor.iter().map(|value| value.as_str()).collect();
+                println_stderr(format!("--anchor: unknown anchor {:?}", anchor.join(" ")));
+                process::exit(1);
+            }
         }
-        assert_eq!(1, count);
     }
-
-    #[test]
-    fn new_prefix_empty_old_prefix() {
-        assert_eq!("tail", new_prefix("", "tail"));
+    let assume_fs = matches.get_one::<String>("assume-fs");
+    let mut diagnostics = options.validate();
+    for name in ["journal", "manifest"] {
+        if options.dry_run && matches.get_one::<String>(name).is_some() {
+            diagnostics.push(Diagnostic::warning(
+                format!("--{} isn't written without renaming anything", name),
+                format!("leave out --{} for a dry run or plan", name)));
+        }
     }
-
-    #[test]
-    fn new_prefix_leading_dash_or_plus() {
-        assert_eq!("a - b", new_prefix("a", "-b"));
-        assert_eq!("a - b", new_prefix("a", "+b"));
+    for diagnostic in &diagnostics {
+        if diagnostic.fatal || !matches.get_flag("quiet") {
+            println_stderr(diagnostic.to_string());
+        }
     }
-
-    #[test]
-    fn new_prefix_works() {
-        assert_eq!("a - b", new_prefix("a", "B"));
-        assert_eq!("a - b - c", new_prefix("a - b", "C"));
+    if diagnostics.iter().any(|diagnostic| diagnostic.fatal) {
+        process::exit(1);
     }
 
-    #[test]
-    fn rename_skips_dot_files() {
-        let tmp_dir = tempdir::TempDir::new("test");
-        if tmp_dir.is_err() {
-            return;
-        }
-        let tmp_dir = tmp_dir.unwrap();
-
-        // Create a file.
-        let tmp_dir_path = tmp_dir.path();
-        let mut path_buf = tmp_dir_path.to_path_buf();
-        path_buf.push(".file");
-        let f = fs::File::create(&path_buf);
-        if f.is_err() {
-            return;
+    if let Some(sample) = matches.get_one::<String>("try") {
+        if options.target_profile.is_none() {
+            if let Some(kind) = assume_fs {
+                options.target_profile = profile_for_filesystem(kind);
+            }
         }
-        let f = f.unwrap();
-        // Flush the file.
-        if f.sync_all().is_err() {
-            return;
+        match try_path(path::Path::new(sample), &options) {
+            Ok(new_name) => println!("{}", new_name),
+            Err(message) => println!("left alone: {}", message),
         }
-
-        rename(&path_buf, "prefix");
-        assert!(path_buf.exists());
+        return;
     }
 
-    #[test]
-    fn rename_works() {
-        let tmp_dir = tempdir::TempDir::new("test");
-        if tmp_dir.is_err() {
-            return;
+    // Directory to process.
+    let directory = match matches.get_one::<String>("directory") {
+        Some(dir) => dir,
+        None => {
+            println_stderr("Expected an argument".to_string());
+            process::exit(1);
         }
-        let tmp_dir = tmp_dir.unwrap();
+    };
 
-        // Create a file.
-        let tmp_dir_path = tmp_dir.path();
-        let mut path_buf = tmp_dir_path.to_path_buf();
-        path_buf.push("d");
-        let f = fs::File::create(&path_buf);
-        if f.is_err() {
-            return;
-        }
-        let f = f.unwrap();
-        // Flush the file.
-        if f.sync_all().is_err() {
-            return;
+    let path = match path::Path::new(directory).canonicalize() {
+        Ok(o) => o,  // Using o.as_path() won't work as `o` leaves the scope.
+        Err(e) => {
+            println_stderr(e.to_string());
+            process::exit(1);
         }
+    };
 
-        rename(&path_buf, "a - b - c");
-        path_buf.pop();
-        path_buf.push("a - b - c - d");
-        assert!(path_buf.exists());
+    if !path.is_dir() {
+        println_stderr("argument is not a directory".to_string());
+        process::exit(1);
     }
 
-    #[test]
-    fn flatten_works() {
-        let tmp_dir = tempdir::TempDir::new("test");
-        if tmp_dir.is_err() {
-            return;
-        }
-        let tmp_dir = tmp_dir.unwrap();
-        let tmp_dir_path = tmp_dir.path();
-        let mut path_buf = tmp_dir_path.to_path_buf();
-        let dir_builder = fs::DirBuilder::new();
+    // Known before planning, so the run's own files never show up as renames.
+    options.artifacts = run_artifacts(matches, &path, &options);
 
-        path_buf.push("A");
-        if dir_builder.create(path_buf.as_path()).is_err() {
-            return;
-        }
+    if options.git && !in_git_work_tree(&path) {
+        println_stderr(format!("--git: {} is not in a git work tree", path.display()));
+        process::exit(1);
+    }
 
-        // A/_skipped/skipped -> None
-        path_buf.push("_skipped");
-        if dir_builder.create(path_buf.as_path()).is_err() {
-            return;
-        } else {
-            path_buf.push("skipped");
-            let f = fs::File::create(&path_buf);
-            if f.is_err() {
-                return;
+    if let Some(ancestor) = anchor_from {
+        match path::Path::new(&ancestor).canonicalize() {
+            Ok(ancestor) if path.starts_with(&ancestor) => {
+                options.anchor = Anchor::From(ancestor);
             }
-            let f = f.unwrap();
-            // Flush the file.
-            if f.sync_all().is_err() {
-                return;
-            } else {
-                path_buf.pop();
+            Ok(_) => {
+                println_stderr(format!("--anchor: {} does not contain {}",
+                                       ancestor, path.display()));
+                process::exit(1);
+            }
+            Err(e) => {
+                println_stderr(format!("{}: {}", ancestor, e));
+                process::exit(1);
             }
-
-            path_buf.pop();
         }
+    }
 
-        // A/-B/C -> A - B - C
-        path_buf.push("-B");
-        if dir_builder.create(path_buf.as_path()).is_err() {
-            return;
-        } else {
-            path_buf.push("C");
-            let f = fs::File::create(&path_buf);
-            if f.is_err() {
-                return;
-            }
-            let f = f.unwrap();
-            // Flush the file.
-            if f.sync_all().is_err() {
-                return;
-            } else {
-                path_buf.pop();
+    if options.target_profile.is_none() {
+        match assume_fs {
+            Some(kind) => options.target_profile = profile_for_filesystem(kind),
+            None => {
+                if let Some(info) = detect_filesystem(&path) {
+                    options.target_profile = profile_for_filesystem(&info.kind);
+                    if let Some(profile) = options.target_profile {
+                        let case = if info.case_sensitive { "" } else { "case-insensitive " };
+                        println_stderr(format!("detected {}{} filesystem; using the {} \
+                                                naming profile",
+                                               case, info.kind, profile.name()));
+                    }
+                }
             }
+        }
+    }
 
-            path_buf.pop();
+    if !matches.get_flag("no-preflight") {
+        // Runs that only look go by how mounts are flagged instead of
+        // writing to them to find out.
+        let given = |name| matches.get_one::<String>(name).is_some();
+        let looking = options.dry_run || matches.get_flag("estimate") ||
+                      matches.get_flag("analyze") || given("explain") || given("visualize") ||
+                      given("export-listing");
+        let unwritable = unwritable_mounts(&path, &options, !looking);
+        for (mount, e) in &unwritable {
+            println_stderr(format!("{}: mount can't be written to ({})", mount.display(), e));
         }
+        let root_unwritable = unwritable.iter().any(|(mount, _)| mount == &path);
+        if root_unwritable || (!looking && !unwritable.is_empty() && !options.keep_going) {
+            println_stderr(format!("{} read-only mounts found, so nothing was renamed",
+                                   unwritable.len()));
+            process::exit(1);
+        }
+        // Known before planning, so nothing under them is planned, explained,
+        // or counted as colliding.
+        options.unwritable = unwritable.into_iter().map(|(mount, _)| mount).collect();
+    }
 
-        // A/.skipped/skipped -> None
-        path_buf.push(".skipped");
-        if dir_builder.create(path_buf.as_path()).is_err() {
-            return;
-        } else {
-            path_buf.push("skipped");
-            let f = fs::File::create(&path_buf);
-            if f.is_err() {
-                return;
+    if let Some(explain_path) = matches.get_one::<String>("explain") {
+        let target = match absolute_path(path::Path::new(explain_path)) {
+            Ok(target) => target,
+            Err(e) => {
+                println_stderr(format!("{}: {}", explain_path, e));
+                process::exit(1);
             }
-            let f = f.unwrap();
-            // Flush the file.
-            if f.sync_all().is_err() {
-                return;
-            } else {
-                path_buf.pop();
+        };
+        match explain(&path, &target, &options) {
+            Ok(lines) => {
+                for line in lines {
+                    println!("{}", line);
+                }
+            }
+            Err(message) => {
+                println_stderr(message);
+                process::exit(1);
             }
+        }
+        return;
+    }
 
-            path_buf.pop();
+    if matches.get_flag("estimate") {
+        let mut totals = Estimate::default();
+        estimate(&path, 0, &options, &mut totals);
+        print!("{}", totals.render_text());
+        return;
+    }
+
+    if let Some(format) = matches.get_one::<String>("visualize") {
+        if let Some(format) = GraphFormat::from_name(format) {
+            print!("{}", visualize(&path, &options).render(format));
         }
+        return;
+    }
 
-        // A/+D/E -> A - D - E
-        path_buf.push("+D");
-        if dir_builder.create(path_buf.as_path()).is_err() {
-            return;
-        } else {
-            path_buf.push("E");
-            let f = fs::File::create(&path_buf);
-            if f.is_err() {
-                return;
-            }
-            let f = f.unwrap();
-            // Flush the file.
-            if f.sync_all().is_err() {
-                return;
-            } else {
-                path_buf.pop();
-            }
+    if matches.get_flag("analyze") {
+        let mut report = Report::new(&path);
+        let renames = plan(&path, &options, &mut report).unwrap_or_else(fail);
+        print!("{}", Analysis::new(&path, &renames).render_text(&path));
+        return;
+    }
 
-            path_buf.pop();
+    if let Some(export) = matches.get_one::<String>("export-listing") {
+        let mut report = Report::new(&path);
+        let renames = plan(&path, &options, &mut report).unwrap_or_else(fail);
+        let listing = VirtualListing::new(&path, &options, &renames);
+        let rendered = match matches.get_one::<String>("listing-format").map(String::as_str) {
+            Some("json") => format!("{:#}\n", listing.render_json()),
+            _ => listing.render_text(),
+        };
+        if let Err(e) = fs::write(export, rendered) {
+            println_stderr(format!("{}: {}", export, e));
+            process::exit(1);
         }
+        return;
+    }
 
-        // A/.skipped -> None
-        path_buf.push(".skipped");
-        let f = fs::File::create(&path_buf);
-        if f.is_err() {
-            return;
+    // Only `plan` has --output.
+    let output = if plan_only { matches.get_one::<String>("output") } else { None };
+    if let Some(output) = output {
+        let mut report = new_report(matches, &path);
+        let renames = plan(&path, &options, &mut report).unwrap_or_else(fail);
+        for collision in find_collisions(&renames) {
+            println_stderr(format!("warning: {}", collision.describe()));
         }
-        let f = f.unwrap();
-        // Flush the file.
-        if f.sync_all().is_err() {
-            return;
-        } else {
-            path_buf.pop();
+        let written = fs::File::create(output)
+            .and_then(|mut file| write_plan(&renames, &mut file));
+        if let Err(e) = written {
+            println_stderr(format!("{}: {}", output, e));
+            process::exit(1);
         }
+        let planned: Vec<(path::PathBuf, path::PathBuf)> = renames
+            .iter()
+            .map(|(from, name)| (from.clone(), from.with_file_name(name)))
+            .collect();
+        report.plan_hash = Some(plan_hash(&planned));
+        if !print_report(matches, &report) {
+            println!("{}", plan_hash(&planned));
+        }
+        write_opaque_map(matches, &options);
+        if renames.is_empty() {
+            report.log(0, format!("nothing to do ({})", report.status_line()));
+            process::exit(EXIT_NOTHING_TO_DO);
+        }
+        return;
+    }
 
-        // A/F -> A - F
-        path_buf.push("F");
-        let f = fs::File::create(&path_buf);
-        if f.is_err() {
-            return;
+    // Other policies deal with collisions as they come up.
+    if options.on_collision == CollisionPolicy::Fail && !matches.get_flag("no-preflight") {
+        let collisions = preflight(&path, &options).unwrap_or_else(fail);
+        for collision in &collisions {
+            println_stderr(collision.describe());
         }
-        let f = f.unwrap();
-        // Flush the file.
-        if f.sync_all().is_err() {
-            return;
-        } else {
-            path_buf.pop();
+        if !collisions.is_empty() && !options.dry_run {
+            println_stderr(format!("{} collisions found, so nothing was renamed",
+                                   collisions.len()));
+            process::exit(EXIT_COLLISIONS);
         }
+    }
 
-        // A/G/H -> A - G - H
-        path_buf.push("G");
-        if dir_builder.create(path_buf.as_path()).is_err() {
-            return;
-        } else {
-            path_buf.push("H");
-            let f = fs::File::create(&path_buf);
-            if f.is_err() {
-                return;
+    let mut report = new_report(matches, &path);
+    if matches.get_one::<String>("report-format").map(String::as_str) == Some("jsonl") {
+        report.events = Some(Box::new(std::io::stdout()));
+    }
+    if let Some(stats_json) = matches.get_one::<String>("stats-json") {
+        match fs::OpenOptions::new().create(true).append(true).open(stats_json) {
+            Ok(file) => {
+                report.progress = Some(ProgressStream::new(Box::new(file)));
             }
-            let f = f.unwrap();
-            // Flush the file.
-            if f.sync_all().is_err() {
-                return;
-            } else {
-                path_buf.pop();
+            Err(e) => {
+                println_stderr(format!("{}: {}", stats_json, e));
+                process::exit(1);
             }
-
-            path_buf.pop();
-        }
-
-        flatten(&path_buf, "");
-
-        // A/_skipped/skipped -> None
-        path_buf.push("_skipped");
-        path_buf.push("skipped");
-        assert!(path_buf.exists());
-        path_buf.pop();
-        path_buf.pop();
-        // A/-B/C -> A - B - C
-        path_buf.push("-B");
-        path_buf.push("a - b - c");
-        assert!(path_buf.exists());
-        path_buf.pop();
-        path_buf.pop();
-        // A/.skipped/skipped -> None
-        path_buf.push(".skipped");
-        path_buf.push("skipped");
-        assert!(path_buf.exists());
-        path_buf.pop();
-        path_buf.pop();
-        // A/+D/E -> A - D - E
-        path_buf.push("+D");
-        path_buf.push("a - d - e");
-        assert!(path_buf.exists());
-        path_buf.pop();
-        path_buf.pop();
-        // A/.skipped -> None
-        path_buf.push(".skipped");
-        assert!(path_buf.exists());
-        path_buf.pop();
-        // A/F -> A - F
-        path_buf.push("a - f");
-        assert!(path_buf.exists());
-        path_buf.pop();
-        // A/G/H -> A - G - H
-        path_buf.push("G");
-        path_buf.push("a - g - h");
-        assert!(path_buf.exists());
-
-        path_buf.pop();
-
-        // -I/J -> I - J
-        path_buf.push("-I");
-        if dir_builder.create(path_buf.as_path()).is_err() {
-            return;
+        }
+    }
+    if let Some(journal) = matches.get_one::<String>("journal") {
+        let journal = if journal.is_empty() {
+            path.join(DEFAULT_JOURNAL)
         } else {
-            path_buf.push("J");
-            let f = fs::File::create(&path_buf);
-            if f.is_err() {
-                return;
+            path::PathBuf::from(journal)
+        };
+        if !options.dry_run {
+            match fs::File::create(&journal) {
+                Ok(file) => {
+                    report.journal = Some(if options.fsync {
+                        Box::new(SyncedFile(file))
+                    } else {
+                        Box::new(file)
+                    });
+                }
+                Err(e) => {
+                    println_stderr(format!("{}: {}", journal.display(), e));
+                    process::exit(1);
+                }
             }
-            let f = f.unwrap();
-            // Flush the file.
-            if f.sync_all().is_err() {
-                return;
-            } else {
-                path_buf.pop();
+        }
+    }
+    if let Some(manifest) = matches.get_one::<String>("manifest") {
+        if !options.dry_run {
+            let started = fs::File::create(manifest)
+                .and_then(|file| report.start_manifest(Box::new(file)));
+            if let Err(e) = started {
+                println_stderr(format!("{}: {}", manifest, e));
+                process::exit(1);
             }
         }
-
-        flatten(&path_buf, "");
-
-        path_buf.push("i - j");
-        assert!(path_buf.exists());
-
-
+    }
+    let control_socket = matches.get_one::<String>("control-socket");
+    if let Some(control_socket) = control_socket {
+        let control = Arc::new(Control::default());
+        if let Err(e) = serve_control(path::Path::new(control_socket), control.clone()) {
+            println_stderr(format!("{}: {}", control_socket, e));
+            process::exit(1);
+        }
+        report.control = Some(control);
+    }
+    if !report.quiet && std::io::stderr().is_terminal() {
+        let total = if matches.get_flag("no-prescan") {
+            None
+        } else {
+            Some(count_entries(&path, &options))
+        };
+        report.progress_bar = Some(ProgressBar::new(total));
+    }
+    install_pause_signals();
+    if let Some(script) = options.script {
+        print!("{}", script.header());
+    }
+    let flattened = flatten(&path, "", &options, &mut report);
+    report.finish();
+    if let Some(control_socket) = control_socket {
+        // Nothing is left to control, so stale sockets are only clutter.
+        let _ = fs::remove_file(control_socket);
+    }
+    flattened.unwrap_or_else(fail);
+    if let Some(metrics) = matches.get_one::<String>("metrics-textfile") {
+        // node_exporter must never see a half-written file.
+        let partial = format!("{}.partial", metrics);
+        let written = fs::write(&partial, report.render_prometheus())
+            .and_then(|()| fs::rename(&partial, metrics));
+        if let Err(e) = written {
+            println_stderr(format!("{}: {}", metrics, e));
+        }
+    }
+    if report.is_cancelled() {
+        println_stderr("run cancelled".to_string());
+        process::exit(1);
+    }
+    if !print_report(matches, &report) && !report.duplicates.is_empty() {
+        report.log(0, report.render_duplicates().trim_end().to_string());
+    }
+    write_opaque_map(matches, &options);
+    if !report.failures.is_empty() {
+        println_stderr(report.render_failures().trim_end().to_string());
+        process::exit(EXIT_FILE_ERRORS);
+    }
+    if report.totals.renamed == 0 && report.deletions.is_empty() {
+        report.log(0, format!("nothing to do ({})", report.status_line()));
+        process::exit(EXIT_NOTHING_TO_DO);
     }
 }
@@ -0,0 +1,187 @@
+use std::fs;
+use std::path;
+use std::process;
+
+#[cfg(unix)]
+extern crate libc;
+extern crate tempdir;
+
+/// Run the `flatten-filenames` binary with `args`.
+fn flatten_filenames(args: &[&str], directory: &path::Path) -> process::Output {
+    process::Command::new(env!("CARGO_BIN_EXE_flatten-filenames"))
+        .args(args)
+        .arg(directory)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn exit_nothing_to_do() {
+    let tmp_dir = tempdir::TempDir::new("test");
+    if tmp_dir.is_err() {
+        return;
+    }
+    let tmp_dir = tmp_dir.unwrap();
+    let root = tmp_dir.path().join("a");
+    if fs::create_dir(&root).is_err() {
+        return;
+    }
+
+    let output = flatten_filenames(&[], &root);
+    assert_eq!(Some(2), output.status.code());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("nothing to do"));
+}
+
+#[test]
+fn exit_file_errors() {
+    let tmp_dir = tempdir::TempDir::new("test");
+    if tmp_dir.is_err() {
+        return;
+    }
+    let tmp_dir = tmp_dir.unwrap();
+    let root = tmp_dir.path().join("a");
+    // Each name fits, but together they are longer than filesystems allow.
+    let directory = root.join("b".repeat(150));
+    let file = directory.join("c".repeat(150));
+    if fs::create_dir_all(&directory).is_err() || fs::File::create(&file).is_err() {
+        return;
+    }
+
+    let output = flatten_filenames(&[], &root);
+    assert_eq!(Some(3), output.status.code());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("1 renames failed"));
+    assert!(file.exists());
+}
+
+#[test]
+fn exit_collisions() {
+    let tmp_dir = tempdir::TempDir::new("test");
+    if tmp_dir.is_err() {
+        return;
+    }
+    let tmp_dir = tmp_dir.unwrap();
+    let root = tmp_dir.path().join("a");
+    let file = root.join("b").join("c.txt");
+    let taken = root.join("b").join("a - b - c.txt");
+    if fs::create_dir_all(root.join("b")).is_err() || fs::File::create(&file).is_err() ||
+       fs::File::create(&taken).is_err() {
+        return;
+    }
+
+    let output = flatten_filenames(&["--on-collision", "fail"], &root);
+    assert_eq!(Some(4), output.status.code());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("1 collisions found"));
+    assert!(file.exists());
+}
+
+/// Create a tree under `root` with a file to rename, a directory not
+/// descended into, and a file whose new name is taken.
+fn verbosity_tree(root: &path::Path) -> bool {
+    fs::create_dir_all(root.join("b")).is_ok() && fs::create_dir_all(root.join("_d")).is_ok() &&
+    fs::create_dir_all(root.join("e")).is_ok() &&
+    fs::File::create(root.join("b").join("c.txt")).is_ok() &&
+    fs::File::create(root.join("e").join("f.txt")).is_ok() &&
+    fs::File::create(root.join("e").join("a - e - f.txt")).is_ok()
+}
+
+#[test]
+fn verbosity_levels() {
+    let tmp_dir = tempdir::TempDir::new("test");
+    if tmp_dir.is_err() {
+        return;
+    }
+    let tmp_dir = tmp_dir.unwrap();
+    let mut outputs = Vec::new();
+    for (index, &verbosity) in ["-q", "", "-v", "-vv"].iter().enumerate() {
+        let root = tmp_dir.path().join(index.to_string()).join("a");
+        if !verbosity_tree(&root) {
+            return;
+        }
+        // Messages name the paths as the binary resolved them.
+        let root = root.canonicalize().unwrap();
+        let mut args = vec!["--on-collision", "skip"];
+        if !verbosity.is_empty() {
+            args.push(verbosity);
+        }
+        let output = flatten_filenames(&args, &root);
+        assert!(output.status.success());
+        let rename = format!("{} -> {}", root.join("b").join("c.txt").display(),
+                             root.join("b").join("a - b - c.txt").display());
+        let warning = format!("{:?}: \"a - e - f.txt\" is already taken",
+                              root.join("e").join("f.txt"));
+        let descended = format!("descending into {:?}", root.join("b"));
+        let not_descended = format!("not descending into {:?}", root.join("_d"));
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        let lines = [warning, rename, descended, not_descended];
+        outputs.push(lines.iter().map(|line| stderr.contains(line)).collect::<Vec<_>>());
+    }
+
+    // -q silences warnings, -v adds every rename, and -vv also adds
+    // whether each directory is descended into.
+    assert_eq!(vec![vec![false, false, false, false],
+                    vec![true, false, false, false],
+                    vec![true, true, false, false],
+                    vec![true, true, true, true]],
+               outputs);
+}
+
+#[cfg(unix)]
+#[test]
+fn as_user_needs_root() {
+    use std::os::unix::process::CommandExt;
+
+    let tmp_dir = tempdir::TempDir::new("test");
+    if tmp_dir.is_err() {
+        return;
+    }
+    let tmp_dir = tmp_dir.unwrap();
+    let root = tmp_dir.path().join("a");
+    if fs::create_dir(&root).is_err() {
+        return;
+    }
+
+    let mut command = process::Command::new(env!("CARGO_BIN_EXE_flatten-filenames"));
+    if unsafe { libc::geteuid() } == 0 {
+        // Run a copy of the binary as nobody, as the build may not be
+        // reachable by anyone but root.
+        let binary = tmp_dir.path().join("flatten-filenames");
+        if fs::copy(env!("CARGO_BIN_EXE_flatten-filenames"), &binary).is_err() {
+            return;
+        }
+        command = process::Command::new(&binary);
+        command.uid(65534).gid(65534);
+    }
+    let output = command.args(["--as-user", "root"]).arg(&root).output();
+    if output.is_err() {
+        return;
+    }
+    let output = output.unwrap();
+    assert_eq!(Some(1), output.status.code());
+    assert!(String::from_utf8_lossy(&output.stderr)
+                .contains("--as-user root: only root can run as another user"));
+}
+
+#[cfg(unix)]
+#[test]
+fn as_user_unknown_user() {
+    let tmp_dir = tempdir::TempDir::new("test");
+    if tmp_dir.is_err() {
+        return;
+    }
+    let tmp_dir = tmp_dir.unwrap();
+    let root = tmp_dir.path().join("a");
+    let file = root.join("b").join("c.txt");
+    if fs::create_dir_all(root.join("b")).is_err() || fs::File::create(&file).is_err() {
+        return;
+    }
+
+    let output = flatten_filenames(&["--as-user", "flatten-filenames-nobody"], &root);
+    assert_eq!(Some(1), output.status.code());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if unsafe { libc::geteuid() } == 0 {
+        assert!(stderr.contains("no user named \"flatten-filenames-nobody\""));
+    } else {
+        assert!(stderr.contains("only root can run as another user"));
+    }
+    assert!(file.exists());
+}